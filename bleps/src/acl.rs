@@ -1,11 +1,20 @@
-use crate::{Data, HciConnection};
+use crate::{h4::RawPacket, read_exact, Data, Error, HciConnection};
 
+/// Capacity for the payload of an ACL packet, longer packets are cut
+pub const ACL_DATA_LEN: usize = 256;
+
+/// Capacity of an encoded ACL packet: packet type, header and payload
+pub const ACL_PACKET_LEN: usize = ACL_DATA_LEN + 5;
+
+/// An ACL packet, or an L2CAP PDU reassembled from several of them with `N` = [`L2CAP_PDU_LEN`]
+///
+/// [`L2CAP_PDU_LEN`]: crate::l2cap::L2CAP_PDU_LEN
 #[derive(Debug, Clone, Copy)]
-pub struct AclPacket {
+pub struct AclPacket<const N: usize = ACL_DATA_LEN> {
     pub handle: u16,
     pub boundary_flag: BoundaryFlag,
     pub bc_flag: ControllerBroadcastFlag,
-    pub data: Data,
+    pub data: Data<N>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -35,48 +44,41 @@ pub enum HostBroadcastFlag {
 }
 
 impl AclPacket {
-    pub fn read(connector: &dyn HciConnection) -> Self {
-        let raw_handle_buffer = [connector.read().unwrap(), connector.read().unwrap()];
-        let (pb, bc, handle) = Self::decode_raw_handle(raw_handle_buffer);
+    pub fn read(connector: &dyn HciConnection) -> Result<Self, Error> {
+        let mut header = [0u8; 4];
+        read_exact(connector, &mut header)?;
+
+        let len = u16::from_le_bytes([header[2], header[3]]);
+        let data = Data::read_truncating(connector, len as usize)?;
+        Self::from_parts(header, data)
+    }
+
+    /// Decodes a packet collected by [`crate::h4::H4Reader`].
+    pub fn from_raw(packet: &RawPacket) -> Result<Self, Error> {
+        Self::from_parts(packet.header, packet.data)
+    }
+
+    fn from_parts(header: [u8; 4], data: Data<ACL_DATA_LEN>) -> Result<Self, Error> {
+        let (pb, bc, handle) = Self::decode_raw_handle([header[0], header[1]]);
         log::debug!(
             "raw handle {:08b} {:08b} - boundary {:?}",
-            raw_handle_buffer[0],
-            raw_handle_buffer[1],
+            header[0],
+            header[1],
             pb
         );
 
-        let len = u16::from_le_bytes([connector.read().unwrap(), connector.read().unwrap()]);
+        let len = u16::from_le_bytes([header[2], header[3]]);
         log::debug!("read len {}", len);
-        let data = Data::read(connector, len as usize);
-
-        Self {
-            handle,
-            boundary_flag: pb,
-            bc_flag: bc,
-            data,
+        if data.len() < len as usize {
+            return Err(Error::Truncated);
         }
-    }
-
-    #[cfg(feature = "async")]
-    pub async fn async_read<T>(connector: &mut T) -> Self
-    where
-        T: embedded_io_async::Read,
-    {
-        let mut raw_handle_buffer = [0u8; 2];
-        let _raw_handle_len = connector.read(&mut raw_handle_buffer).await.unwrap();
-        let (pb, bc, handle) = Self::decode_raw_handle(raw_handle_buffer);
 
-        let mut len_buffer = [0u8; 2];
-        let _len_len = connector.read(&mut len_buffer).await.unwrap();
-        let len = u16::from_le_bytes(len_buffer);
-        let data = Data::async_read(connector, len as usize).await;
-
-        Self {
+        Ok(Self {
             handle,
             boundary_flag: pb,
             bc_flag: bc,
             data,
-        }
+        })
     }
 
     fn decode_raw_handle(
@@ -108,7 +110,12 @@ impl AclPacket {
     }
 
     // including type (0x02)
-    pub fn encode(handle: u16, pb: BoundaryFlag, bc: HostBroadcastFlag, payload: Data) -> Data {
+    pub fn encode<const N: usize>(
+        handle: u16,
+        pb: BoundaryFlag,
+        bc: HostBroadcastFlag,
+        payload: Data<N>,
+    ) -> Data<ACL_PACKET_LEN> {
         let mut data = Data::new(&[0x02]);
 
         let mut raw_handle = handle;
@@ -137,3 +144,131 @@ impl AclPacket {
         data
     }
 }
+
+/// Size and number of the controller's ACL data buffers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AclBufferSize {
+    /// Maximum payload length of a single ACL packet
+    pub packet_len: u16,
+    /// Number of ACL packets the controller can buffer
+    pub packet_count: u16,
+}
+
+/// Keeps track of the free ACL buffers in the controller ([Vol 4] Part E, Section 4.1).
+///
+/// Until the buffer size is known no flow control is done and packets aren't fragmented.
+/// Packets in flight are counted per connection handle for up to `N` connections since a
+/// disconnection implicitly frees all buffers used by that connection.
+#[derive(Debug)]
+pub struct AclFlowControl<const N: usize> {
+    buffer_size: Option<AclBufferSize>,
+    free: u16,
+    in_flight: [Option<(u16, u16)>; N],
+}
+
+impl<const N: usize> Default for AclFlowControl<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> AclFlowControl<N> {
+    pub fn new() -> Self {
+        Self {
+            buffer_size: None,
+            free: 0,
+            in_flight: [None; N],
+        }
+    }
+
+    pub fn buffer_size(&self) -> Option<AclBufferSize> {
+        self.buffer_size
+    }
+
+    /// Sets the buffer size read from the controller, all buffers are considered free
+    pub fn set_buffer_size(&mut self, buffer_size: AclBufferSize) {
+        self.buffer_size = Some(buffer_size);
+        self.free = buffer_size.packet_count;
+        self.in_flight = [None; N];
+    }
+
+    /// The maximum payload length of a single ACL packet sent to the controller
+    pub fn fragment_len(&self) -> usize {
+        match self.buffer_size {
+            Some(buffer_size) => buffer_size.packet_len as usize,
+            None => usize::MAX,
+        }
+    }
+
+    /// Number of ACL buffers currently free in the controller
+    pub fn free(&self) -> u16 {
+        self.free
+    }
+
+    /// Takes a buffer for a packet to be sent on `handle`, returns false if none is available
+    pub fn try_acquire(&mut self, handle: u16) -> bool {
+        if self.buffer_size.is_none() {
+            return true;
+        }
+
+        if self.free == 0 {
+            return false;
+        }
+
+        let index = match self.find(handle) {
+            Some(index) => index,
+            None => match self.in_flight.iter().position(Option::is_none) {
+                Some(index) => {
+                    self.in_flight[index] = Some((handle, 0));
+                    index
+                }
+                None => {
+                    log::warn!("Too many connections to track ACL buffers for {}", handle);
+                    return false;
+                }
+            },
+        };
+
+        if let Some((_, count)) = self.in_flight[index].as_mut() {
+            *count += 1;
+        }
+        self.free -= 1;
+        true
+    }
+
+    /// The controller reported `count` packets on `handle` as completed
+    pub fn complete(&mut self, handle: u16, count: u16) {
+        if self.buffer_size.is_none() {
+            return;
+        }
+
+        let Some(index) = self.find(handle) else {
+            log::warn!("Completed packets for unknown handle {}", handle);
+            return;
+        };
+
+        if let Some((_, in_flight)) = self.in_flight[index].as_mut() {
+            let count = count.min(*in_flight);
+            *in_flight -= count;
+            self.free += count;
+            if *in_flight == 0 {
+                self.in_flight[index] = None;
+            }
+        }
+    }
+
+    /// All buffers used by a connection are freed when it's disconnected
+    pub fn disconnected(&mut self, handle: u16) {
+        if let Some(index) = self.find(handle) {
+            if let Some((_, in_flight)) = self.in_flight[index].take() {
+                self.free += in_flight;
+            }
+        }
+    }
+
+    fn find(&self, handle: u16) -> Option<usize> {
+        self.in_flight
+            .iter()
+            .position(|slot| matches!(slot, Some((h, _)) if *h == handle))
+    }
+}
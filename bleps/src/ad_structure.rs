@@ -1,4 +1,4 @@
-use crate::{att::Uuid, Data};
+use crate::{att::Uuid, CapacityError, Data};
 
 pub const AD_FLAG_LE_LIMITED_DISCOVERABLE: u8 = 0b00000001;
 pub const LE_GENERAL_DISCOVERABLE: u8 = 0b00000010;
@@ -6,12 +6,148 @@ pub const BR_EDR_NOT_SUPPORTED: u8 = 0b00000100;
 pub const SIMUL_LE_BR_CONTROLLER: u8 = 0b00001000;
 pub const SIMUL_LE_BR_HOST: u8 = 0b00010000;
 
-#[derive(Debug, Copy, Clone)]
+const AD_TYPE_FLAGS: u8 = 0x01;
+const AD_TYPE_INCOMPLETE_SERVICE_UUIDS_16: u8 = 0x02;
+const AD_TYPE_SERVICE_UUIDS_16: u8 = 0x03;
+const AD_TYPE_INCOMPLETE_SERVICE_UUIDS_32: u8 = 0x04;
+const AD_TYPE_SERVICE_UUIDS_32: u8 = 0x05;
+const AD_TYPE_INCOMPLETE_SERVICE_UUIDS_128: u8 = 0x06;
+const AD_TYPE_SERVICE_UUIDS_128: u8 = 0x07;
+const AD_TYPE_SHORTENED_LOCAL_NAME: u8 = 0x08;
+const AD_TYPE_COMPLETE_LOCAL_NAME: u8 = 0x09;
+const AD_TYPE_TX_POWER_LEVEL: u8 = 0x0a;
+const AD_TYPE_PERIPHERAL_CONNECTION_INTERVAL_RANGE: u8 = 0x12;
+const AD_TYPE_SERVICE_SOLICITATION_16: u8 = 0x14;
+const AD_TYPE_SERVICE_SOLICITATION_128: u8 = 0x15;
+const AD_TYPE_SERVICE_DATA_16: u8 = 0x16;
+const AD_TYPE_APPEARANCE: u8 = 0x19;
+const AD_TYPE_ADVERTISING_INTERVAL: u8 = 0x1a;
+const AD_TYPE_LE_ROLE: u8 = 0x1c;
+const AD_TYPE_SERVICE_SOLICITATION_32: u8 = 0x1f;
+const AD_TYPE_SERVICE_DATA_32: u8 = 0x20;
+const AD_TYPE_SERVICE_DATA_128: u8 = 0x21;
+const AD_TYPE_URI: u8 = 0x24;
+const AD_TYPE_LE_SUPPORTED_FEATURES: u8 = 0x27;
+const AD_TYPE_MANUFACTURER_SPECIFIC_DATA: u8 = 0xff;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum AdvertisementDataError {
+    /// The AD structures don't fit into 31 bytes, or an AD structure is longer than its length
+    /// byte can express
     TooLong,
+    /// The length of the AD structure at `offset` runs past the end of the data
+    Truncated { offset: usize },
+    /// The AD structure at `offset` has a length its type `ty` doesn't allow
+    InvalidLength { offset: usize, ty: u8 },
+}
+
+impl From<CapacityError> for AdvertisementDataError {
+    fn from(_: CapacityError) -> Self {
+        AdvertisementDataError::TooLong
+    }
 }
 
+impl core::fmt::Display for AdvertisementDataError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            AdvertisementDataError::TooLong => write!(f, "advertising data longer than 31 bytes"),
+            AdvertisementDataError::Truncated { offset } => {
+                write!(f, "AD structure at offset {} is truncated", offset)
+            }
+            AdvertisementDataError::InvalidLength { offset, ty } => write!(
+                f,
+                "AD structure of type {:#04x} at offset {} has an invalid length",
+                ty, offset
+            ),
+        }
+    }
+}
+
+/// The service UUIDs of a list or solicitation AD structure
+///
+/// All UUIDs need to have the width of the AD type they are sent in. Slices and arrays of
+/// [`Uuid`] convert into it with `into()`.
 #[derive(Debug, Copy, Clone)]
+pub enum UuidList<'a> {
+    /// 16-bit or 128-bit UUIDs
+    Uuids(&'a [Uuid]),
+    /// 32-bit UUIDs
+    Uuids32(&'a [u32]),
+    /// The UUIDs in little-endian byte order as sent on air, this is what the parser returns
+    Raw(&'a [u8]),
+}
+
+impl<'a> UuidList<'a> {
+    /// The UUIDs in little-endian byte order as sent on air
+    pub fn bytes(&self) -> impl Iterator<Item = u8> + 'a {
+        let (uuids, uuids32, raw): (&[Uuid], &[u32], &[u8]) = match *self {
+            UuidList::Uuids(uuids) => (uuids, &[], &[]),
+            UuidList::Uuids32(uuids) => (&[], uuids, &[]),
+            UuidList::Raw(raw) => (&[], &[], raw),
+        };
+
+        uuids
+            .iter()
+            .flat_map(|uuid| {
+                let mut bytes = [0u8; 16];
+                let len = match uuid {
+                    Uuid::Uuid16(uuid) => {
+                        bytes[..2].copy_from_slice(&uuid.to_le_bytes());
+                        2
+                    }
+                    Uuid::Uuid128(uuid) => {
+                        bytes.copy_from_slice(uuid);
+                        16
+                    }
+                };
+                bytes.into_iter().take(len)
+            })
+            .chain(uuids32.iter().flat_map(|uuid| uuid.to_le_bytes()))
+            .chain(raw.iter().copied())
+    }
+}
+
+impl<'a> PartialEq for UuidList<'a> {
+    fn eq(&self, other: &Self) -> bool {
+        self.bytes().eq(other.bytes())
+    }
+}
+
+impl<'a> From<&'a [Uuid]> for UuidList<'a> {
+    fn from(uuids: &'a [Uuid]) -> Self {
+        UuidList::Uuids(uuids)
+    }
+}
+
+impl<'a, const N: usize> From<&'a [Uuid; N]> for UuidList<'a> {
+    fn from(uuids: &'a [Uuid; N]) -> Self {
+        UuidList::Uuids(uuids)
+    }
+}
+
+/// The LE role of a device, see [`AdStructure::LeRole`]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LeRole {
+    PeripheralOnly = 0x00,
+    CentralOnly = 0x01,
+    PeripheralPreferred = 0x02,
+    CentralPreferred = 0x03,
+}
+
+impl LeRole {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x00 => Some(LeRole::PeripheralOnly),
+            0x01 => Some(LeRole::CentralOnly),
+            0x02 => Some(LeRole::PeripheralPreferred),
+            0x03 => Some(LeRole::CentralPreferred),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum AdStructure<'a> {
     /// Device flags and baseband capabilities.
     ///
@@ -21,8 +157,25 @@ pub enum AdStructure<'a> {
     /// Must not be used in scan response data.
     Flags(u8),
 
-    ServiceUuids16(&'a [Uuid]),
-    ServiceUuids128(&'a [Uuid]),
+    /// Complete list of 16-bit service UUIDs
+    ServiceUuids16(UuidList<'a>),
+    /// Incomplete list of 16-bit service UUIDs
+    IncompleteServiceUuids16(UuidList<'a>),
+    /// Complete list of 32-bit service UUIDs
+    ServiceUuids32(UuidList<'a>),
+    /// Incomplete list of 32-bit service UUIDs
+    IncompleteServiceUuids32(UuidList<'a>),
+    /// Complete list of 128-bit service UUIDs
+    ServiceUuids128(UuidList<'a>),
+    /// Incomplete list of 128-bit service UUIDs
+    IncompleteServiceUuids128(UuidList<'a>),
+
+    /// 16-bit service UUIDs a central should offer.
+    ServiceSolicitation16(UuidList<'a>),
+    /// 32-bit service UUIDs a central should offer.
+    ServiceSolicitation32(UuidList<'a>),
+    /// 128-bit service UUIDs a central should offer.
+    ServiceSolicitation128(UuidList<'a>),
 
     /// Service data with 16-bit service UUID.
     ServiceData16 {
@@ -32,6 +185,22 @@ pub enum AdStructure<'a> {
         data: &'a [u8],
     },
 
+    /// Service data with 32-bit service UUID.
+    ServiceData32 {
+        /// The 32-bit service UUID.
+        uuid: u32,
+        /// The associated service data. May be empty.
+        data: &'a [u8],
+    },
+
+    /// Service data with 128-bit service UUID.
+    ServiceData128 {
+        /// The 128-bit service UUID in little-endian byte order.
+        uuid: [u8; 16],
+        /// The associated service data. May be empty.
+        data: &'a [u8],
+    },
+
     /// Sets the full (unabbreviated) device name.
     ///
     /// This will be shown to the user when this device is found.
@@ -40,6 +209,29 @@ pub enum AdStructure<'a> {
     /// Sets the shortened device name.
     ShortenedLocalName(&'a str),
 
+    /// Transmitted power level of the packet in dBm.
+    TxPowerLevel(i8),
+
+    /// External appearance of the device, as assigned by the Bluetooth SIG.
+    Appearance(u16),
+
+    /// Preferred connection interval range in units of 1.25 ms, 0xffff means no specific limit.
+    PeripheralConnectionIntervalRange { min: u16, max: u16 },
+
+    /// A URI whose scheme is replaced by the code point assigned to it, e.g.
+    /// `"\u{17}//example.com"` for `https://example.com`.
+    Uri(&'a str),
+
+    /// Supported and preferred LE roles.
+    LeRole(LeRole),
+
+    /// Advertising interval in units of 0.625 ms.
+    AdvertisingInterval(u16),
+
+    /// The LE supported features bit field in little-endian byte order, trailing zero bytes may
+    /// be omitted.
+    LeSupportedFeatures(&'a [u8]),
+
     /// Set manufacturer specific data
     ManufacturerSpecificData {
         company_identifier: u16,
@@ -56,70 +248,424 @@ pub enum AdStructure<'a> {
 }
 
 impl Data {
+    fn append_ad_uuid_list(
+        &mut self,
+        ty: u8,
+        uuids: &UuidList,
+    ) -> Result<(), AdvertisementDataError> {
+        self.try_append(&[0, ty])?;
+        for byte in uuids.bytes() {
+            self.try_append(&[byte])?;
+        }
+        Ok(())
+    }
+
+    /// Appends the encoded `src`, panics if it doesn't fit
     pub fn append_ad_structure(&mut self, src: &AdStructure) {
+        if let Err(err) = self.try_append_ad_structure(src) {
+            panic!("{:?} can't be appended: {}", src, err);
+        }
+    }
+
+    /// Appends the encoded `src` if it fits into the remaining capacity and its length into the
+    /// length byte, leaves the buffer untouched if not
+    pub fn try_append_ad_structure(
+        &mut self,
+        src: &AdStructure,
+    ) -> Result<(), AdvertisementDataError> {
+        let start = self.len;
+        let res = self.encode_ad_structure(src).and_then(|()| {
+            let len =
+                u8::try_from(self.len - start - 1).map_err(|_| AdvertisementDataError::TooLong)?;
+            self.set(start, len);
+            Ok(())
+        });
+        if res.is_err() {
+            self.len = start;
+        }
+        res
+    }
+
+    /// Appends `src` with a zero length byte
+    fn encode_ad_structure(&mut self, src: &AdStructure) -> Result<(), AdvertisementDataError> {
         match src {
             AdStructure::Flags(flags) => {
-                self.append(&[0x02, 0x01, *flags]);
+                self.try_append(&[0, AD_TYPE_FLAGS, *flags])?;
             }
             AdStructure::ServiceUuids16(uuids) => {
-                self.append(&[(uuids.len() * 2 + 1) as u8, 0x02]);
-                for uuid in uuids.iter() {
-                    self.append_uuid(uuid);
-                }
+                self.append_ad_uuid_list(AD_TYPE_SERVICE_UUIDS_16, uuids)?;
+            }
+            AdStructure::IncompleteServiceUuids16(uuids) => {
+                self.append_ad_uuid_list(AD_TYPE_INCOMPLETE_SERVICE_UUIDS_16, uuids)?;
+            }
+            AdStructure::ServiceUuids32(uuids) => {
+                self.append_ad_uuid_list(AD_TYPE_SERVICE_UUIDS_32, uuids)?;
+            }
+            AdStructure::IncompleteServiceUuids32(uuids) => {
+                self.append_ad_uuid_list(AD_TYPE_INCOMPLETE_SERVICE_UUIDS_32, uuids)?;
             }
             AdStructure::ServiceUuids128(uuids) => {
-                self.append(&[(uuids.len() * 16 + 1) as u8, 0x07]);
-                for uuid in uuids.iter() {
-                    self.append_uuid(uuid);
-                }
+                self.append_ad_uuid_list(AD_TYPE_SERVICE_UUIDS_128, uuids)?;
+            }
+            AdStructure::IncompleteServiceUuids128(uuids) => {
+                self.append_ad_uuid_list(AD_TYPE_INCOMPLETE_SERVICE_UUIDS_128, uuids)?;
+            }
+            AdStructure::ServiceSolicitation16(uuids) => {
+                self.append_ad_uuid_list(AD_TYPE_SERVICE_SOLICITATION_16, uuids)?;
+            }
+            AdStructure::ServiceSolicitation32(uuids) => {
+                self.append_ad_uuid_list(AD_TYPE_SERVICE_SOLICITATION_32, uuids)?;
+            }
+            AdStructure::ServiceSolicitation128(uuids) => {
+                self.append_ad_uuid_list(AD_TYPE_SERVICE_SOLICITATION_128, uuids)?;
             }
             AdStructure::ShortenedLocalName(name) => {
-                self.append(&[(name.len() + 1) as u8, 0x08]);
-                self.append(name.as_bytes());
+                self.try_append(&[0, AD_TYPE_SHORTENED_LOCAL_NAME])?;
+                self.try_append(name.as_bytes())?;
             }
             AdStructure::CompleteLocalName(name) => {
-                self.append(&[(name.len() + 1) as u8, 0x09]);
-                self.append(name.as_bytes());
+                self.try_append(&[0, AD_TYPE_COMPLETE_LOCAL_NAME])?;
+                self.try_append(name.as_bytes())?;
             }
             AdStructure::ServiceData16 { uuid, data } => {
-                self.append(&[(data.len() + 3) as u8, 0x16]);
-                self.append_value(*uuid);
-                self.append(data);
+                self.try_append(&[0, AD_TYPE_SERVICE_DATA_16])?;
+                self.try_append_value(*uuid)?;
+                self.try_append(data)?;
+            }
+            AdStructure::ServiceData32 { uuid, data } => {
+                self.try_append(&[0, AD_TYPE_SERVICE_DATA_32])?;
+                self.try_append_value(*uuid)?;
+                self.try_append(data)?;
+            }
+            AdStructure::ServiceData128 { uuid, data } => {
+                self.try_append(&[0, AD_TYPE_SERVICE_DATA_128])?;
+                self.try_append(uuid)?;
+                self.try_append(data)?;
+            }
+            AdStructure::TxPowerLevel(level) => {
+                self.try_append(&[0, AD_TYPE_TX_POWER_LEVEL, *level as u8])?;
+            }
+            AdStructure::Appearance(appearance) => {
+                self.try_append(&[0, AD_TYPE_APPEARANCE])?;
+                self.try_append_value(*appearance)?;
+            }
+            AdStructure::PeripheralConnectionIntervalRange { min, max } => {
+                self.try_append(&[0, AD_TYPE_PERIPHERAL_CONNECTION_INTERVAL_RANGE])?;
+                self.try_append_value(*min)?;
+                self.try_append_value(*max)?;
+            }
+            AdStructure::Uri(uri) => {
+                self.try_append(&[0, AD_TYPE_URI])?;
+                self.try_append(uri.as_bytes())?;
+            }
+            AdStructure::LeRole(role) => {
+                self.try_append(&[0, AD_TYPE_LE_ROLE, *role as u8])?;
+            }
+            AdStructure::AdvertisingInterval(interval) => {
+                self.try_append(&[0, AD_TYPE_ADVERTISING_INTERVAL])?;
+                self.try_append_value(*interval)?;
+            }
+            AdStructure::LeSupportedFeatures(features) => {
+                self.try_append(&[0, AD_TYPE_LE_SUPPORTED_FEATURES])?;
+                self.try_append(features)?;
             }
             AdStructure::ManufacturerSpecificData {
                 company_identifier,
                 payload,
             } => {
-                self.append(&[(payload.len() + 3) as u8, 0xff]);
-                self.append_value(*company_identifier);
-                self.append(payload);
+                self.try_append(&[0, AD_TYPE_MANUFACTURER_SPECIFIC_DATA])?;
+                self.try_append_value(*company_identifier)?;
+                self.try_append(payload)?;
             }
             AdStructure::Unknown { ty, data } => {
-                self.append(&[(data.len() + 1) as u8, *ty]);
-                self.append(data);
+                self.try_append(&[0, *ty])?;
+                self.try_append(data)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> AdStructure<'a> {
+    /// Decodes the data of an AD structure of type `ty`
+    ///
+    /// Names and URIs which aren't valid UTF-8 and reserved LE roles are returned as
+    /// [`AdStructure::Unknown`].
+    fn decode(ty: u8, data: &'a [u8], offset: usize) -> Result<Self, AdvertisementDataError> {
+        let invalid_length = AdvertisementDataError::InvalidLength { offset, ty };
+        let uuid_list = |width: usize| {
+            if !data.len().is_multiple_of(width) {
+                return Err(invalid_length);
+            }
+            Ok(UuidList::Raw(data))
+        };
+        let exactly = |len: usize| {
+            if data.len() != len {
+                return Err(invalid_length);
+            }
+            Ok(data)
+        };
+        let at_least = |len: usize| {
+            if data.len() < len {
+                return Err(invalid_length);
+            }
+            Ok(data)
+        };
+
+        let res = match ty {
+            AD_TYPE_FLAGS => AdStructure::Flags(*data.first().ok_or(invalid_length)?),
+            AD_TYPE_SERVICE_UUIDS_16 => AdStructure::ServiceUuids16(uuid_list(2)?),
+            AD_TYPE_INCOMPLETE_SERVICE_UUIDS_16 => {
+                AdStructure::IncompleteServiceUuids16(uuid_list(2)?)
             }
+            AD_TYPE_SERVICE_UUIDS_32 => AdStructure::ServiceUuids32(uuid_list(4)?),
+            AD_TYPE_INCOMPLETE_SERVICE_UUIDS_32 => {
+                AdStructure::IncompleteServiceUuids32(uuid_list(4)?)
+            }
+            AD_TYPE_SERVICE_UUIDS_128 => AdStructure::ServiceUuids128(uuid_list(16)?),
+            AD_TYPE_INCOMPLETE_SERVICE_UUIDS_128 => {
+                AdStructure::IncompleteServiceUuids128(uuid_list(16)?)
+            }
+            AD_TYPE_SERVICE_SOLICITATION_16 => AdStructure::ServiceSolicitation16(uuid_list(2)?),
+            AD_TYPE_SERVICE_SOLICITATION_32 => AdStructure::ServiceSolicitation32(uuid_list(4)?),
+            AD_TYPE_SERVICE_SOLICITATION_128 => AdStructure::ServiceSolicitation128(uuid_list(16)?),
+            AD_TYPE_SHORTENED_LOCAL_NAME | AD_TYPE_COMPLETE_LOCAL_NAME | AD_TYPE_URI => {
+                match core::str::from_utf8(data) {
+                    Ok(name) if ty == AD_TYPE_SHORTENED_LOCAL_NAME => {
+                        AdStructure::ShortenedLocalName(name)
+                    }
+                    Ok(name) if ty == AD_TYPE_COMPLETE_LOCAL_NAME => {
+                        AdStructure::CompleteLocalName(name)
+                    }
+                    Ok(uri) => AdStructure::Uri(uri),
+                    Err(_) => AdStructure::Unknown { ty, data },
+                }
+            }
+            AD_TYPE_SERVICE_DATA_16 => {
+                let data = at_least(2)?;
+                AdStructure::ServiceData16 {
+                    uuid: u16::from_le_bytes([data[0], data[1]]),
+                    data: &data[2..],
+                }
+            }
+            AD_TYPE_SERVICE_DATA_32 => {
+                let data = at_least(4)?;
+                AdStructure::ServiceData32 {
+                    uuid: u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+                    data: &data[4..],
+                }
+            }
+            AD_TYPE_SERVICE_DATA_128 => {
+                let data = at_least(16)?;
+                AdStructure::ServiceData128 {
+                    uuid: data[..16].try_into().unwrap(),
+                    data: &data[16..],
+                }
+            }
+            AD_TYPE_TX_POWER_LEVEL => AdStructure::TxPowerLevel(exactly(1)?[0] as i8),
+            AD_TYPE_APPEARANCE => {
+                let data = exactly(2)?;
+                AdStructure::Appearance(u16::from_le_bytes([data[0], data[1]]))
+            }
+            AD_TYPE_PERIPHERAL_CONNECTION_INTERVAL_RANGE => {
+                let data = exactly(4)?;
+                AdStructure::PeripheralConnectionIntervalRange {
+                    min: u16::from_le_bytes([data[0], data[1]]),
+                    max: u16::from_le_bytes([data[2], data[3]]),
+                }
+            }
+            AD_TYPE_LE_ROLE => match LeRole::from_u8(exactly(1)?[0]) {
+                Some(role) => AdStructure::LeRole(role),
+                None => AdStructure::Unknown { ty, data },
+            },
+            AD_TYPE_ADVERTISING_INTERVAL => {
+                let data = exactly(2)?;
+                AdStructure::AdvertisingInterval(u16::from_le_bytes([data[0], data[1]]))
+            }
+            AD_TYPE_LE_SUPPORTED_FEATURES => AdStructure::LeSupportedFeatures(data),
+            AD_TYPE_MANUFACTURER_SPECIFIC_DATA => {
+                let data = at_least(2)?;
+                AdStructure::ManufacturerSpecificData {
+                    company_identifier: u16::from_le_bytes([data[0], data[1]]),
+                    payload: &data[2..],
+                }
+            }
+            _ => AdStructure::Unknown { ty, data },
+        };
+
+        Ok(res)
+    }
+}
+
+/// Iterates over the AD structures of an advertising or scan response payload
+///
+/// Created by [`parse_advertising_data`]. The iteration ends after the first error.
+#[derive(Debug, Clone)]
+pub struct AdStructureIter<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Iterator for AdStructureIter<'a> {
+    type Item = Result<AdStructure<'a>, AdvertisementDataError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.offset;
+        let (&len, rest) = self.data.get(offset..)?.split_first()?;
+
+        // a zero length ends the significant part, the rest is padding
+        if len == 0 {
+            self.offset = self.data.len();
+            return None;
         }
+
+        let len = len as usize;
+        let Some((&ty, data)) = rest
+            .get(..len)
+            .and_then(|structure| structure.split_first())
+        else {
+            self.offset = self.data.len();
+            return Some(Err(AdvertisementDataError::Truncated { offset }));
+        };
+
+        self.offset += 1 + len;
+        let res = AdStructure::decode(ty, data, offset);
+        if res.is_err() {
+            self.offset = self.data.len();
+        }
+        Some(res)
     }
 }
 
+/// Parses the AD structures in `data` as sent on air, without the length prefix of the HCI
+/// command
+pub fn parse_advertising_data(data: &[u8]) -> AdStructureIter<'_> {
+    AdStructureIter { data, offset: 0 }
+}
+
+/// Space for AD structures in advertising and scan response data
+const MAX_ADVERTISING_DATA_LEN: usize = 31;
+
 pub fn create_advertising_data(ad: &[AdStructure]) -> Result<Data, AdvertisementDataError> {
     let mut data = Data::default();
-    data.append(&[0]);
 
     for item in ad.iter() {
-        data.append_ad_structure(&item);
+        data.try_append_ad_structure(item)?;
     }
 
-    let len = data.len - 1;
-    data.set(0, len as u8);
-
-    if len > 31 {
+    if data.len() > MAX_ADVERTISING_DATA_LEN {
         return Err(AdvertisementDataError::TooLong);
     }
 
-    for _ in 0..(31 - len) {
-        data.append(&[0]);
+    Ok(pad_advertising_data(&data))
+}
+
+/// Prefixes the AD structures in `ad` with their length and pads them as the HCI commands expect
+fn pad_advertising_data(ad: &Data) -> Data {
+    let mut data = Data::new(&[ad.len() as u8]);
+    data.append(ad.as_slice());
+    data.append(&[0; MAX_ADVERTISING_DATA_LEN][ad.len()..]);
+    data
+}
+
+/// Where [`split_advertising_data`] placed an AD structure
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Placement {
+    AdvertisingData,
+    ScanResponseData,
+    /// A [`AdStructure::CompleteLocalName`] sent as [`AdStructure::ShortenedLocalName`] with the
+    /// first `len` bytes of the name
+    ShortenedInAdvertisingData {
+        len: usize,
+    },
+    /// Like [`Placement::ShortenedInAdvertisingData`] but in the scan response data
+    ShortenedInScanResponseData {
+        len: usize,
+    },
+    /// There was no space left for it
+    NotPlaced,
+}
+
+/// The payloads created by [`split_advertising_data`]
+#[derive(Debug)]
+pub struct SplitAdvertisingData<const N: usize> {
+    /// Data for [`crate::Ble::cmd_set_le_advertising_data`]
+    pub advertising_data: Data,
+    /// Data for [`crate::Ble::cmd_set_le_scan_rsp_data`]
+    pub scan_response_data: Data,
+    /// The placement of each AD structure passed in
+    pub placements: [Placement; N],
+}
+
+impl<const N: usize> SplitAdvertisingData<N> {
+    /// Whether every AD structure was placed, possibly shortened
+    pub fn all_placed(&self) -> bool {
+        !self.placements.contains(&Placement::NotPlaced)
+    }
+}
+
+/// Places the AD structures in `ad`, ordered by priority, into the advertising data and, once
+/// that is full, the scan response data
+///
+/// A complete local name fitting into neither is shortened to fit the payload with more space
+/// left. Flags are never placed in the scan response data.
+pub fn split_advertising_data<const N: usize>(ad: &[AdStructure; N]) -> SplitAdvertisingData<N> {
+    let mut advertising_data = Data::default();
+    let mut scan_response_data = Data::default();
+    let mut placements = [Placement::NotPlaced; N];
+
+    for (item, placement) in ad.iter().zip(placements.iter_mut()) {
+        let mut encoded = Data::default();
+        // too long for either payload, only a complete local name can still be shortened
+        let encoded_len = match encoded.try_append_ad_structure(item) {
+            Ok(()) => encoded.len(),
+            Err(_) => usize::MAX,
+        };
+
+        let advertising_space = MAX_ADVERTISING_DATA_LEN - advertising_data.len();
+        let scan_response_space = match item {
+            AdStructure::Flags(_) => 0,
+            _ => MAX_ADVERTISING_DATA_LEN - scan_response_data.len(),
+        };
+
+        *placement = if encoded_len <= advertising_space {
+            advertising_data.append(encoded.as_slice());
+            Placement::AdvertisingData
+        } else if encoded_len <= scan_response_space {
+            scan_response_data.append(encoded.as_slice());
+            Placement::ScanResponseData
+        } else if let AdStructure::CompleteLocalName(name) = item {
+            let in_advertising_data = advertising_space >= scan_response_space;
+            let (data, space) = if in_advertising_data {
+                (&mut advertising_data, advertising_space)
+            } else {
+                (&mut scan_response_data, scan_response_space)
+            };
+
+            // the shortened name must not end in the middle of a character
+            let len = (1..=space.saturating_sub(2))
+                .rev()
+                .find(|len| name.is_char_boundary(*len))
+                .unwrap_or(0);
+            if len == 0 {
+                Placement::NotPlaced
+            } else {
+                data.append_ad_structure(&AdStructure::ShortenedLocalName(&name[..len]));
+                if in_advertising_data {
+                    Placement::ShortenedInAdvertisingData { len }
+                } else {
+                    Placement::ShortenedInScanResponseData { len }
+                }
+            }
+        } else {
+            Placement::NotPlaced
+        };
     }
 
-    Ok(data)
+    SplitAdvertisingData {
+        advertising_data: pad_advertising_data(&advertising_data),
+        scan_response_data: pad_advertising_data(&scan_response_data),
+        placements,
+    }
 }
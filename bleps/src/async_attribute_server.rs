@@ -1,10 +1,7 @@
 #[cfg(not(feature = "crypto"))]
 use core::marker::PhantomData;
 
-use core::cell::RefCell;
-
-use critical_section::Mutex;
-use futures::future::Either;
+use futures::future::{select, Either};
 use futures::pin_mut;
 use rand_core::{CryptoRng, RngCore};
 
@@ -16,20 +13,26 @@ use crate::{
     att::Uuid,
     attribute::Attribute,
     attribute_server::{AttributeServerError, NotificationData, WorkResult},
-    Addr,
+    clock::AsyncClock,
+    Addr, PENDING_POLL_RESULTS,
 };
 
-pub struct AttributeServer<'a, T, R: CryptoRng + RngCore>
-where
+pub struct AttributeServer<
+    'a,
+    T,
+    R: CryptoRng + RngCore,
+    K = fn() -> u64,
+    const P: usize = PENDING_POLL_RESULTS,
+> where
     T: embedded_io_async::Read + embedded_io_async::Write,
 {
-    pub(crate) ble: &'a mut Ble<T>,
+    pub(crate) ble: &'a mut Ble<T, K, P>,
     pub(crate) src_handle: u16,
     pub(crate) mtu: u16,
     pub(crate) attributes: &'a mut [Attribute<'a>],
 
     #[cfg(feature = "crypto")]
-    pub(crate) security_manager: AsyncSecurityManager<'a, Ble<T>, R>,
+    pub(crate) security_manager: AsyncSecurityManager<'a, Ble<T, K, P>, R>,
 
     #[cfg(feature = "crypto")]
     pub(crate) pin_callback: Option<&'a mut dyn FnMut(u32)>,
@@ -38,18 +41,19 @@ where
     phantom: PhantomData<R>,
 }
 
-impl<'a, T, R: CryptoRng + RngCore> AttributeServer<'a, T, R>
+impl<'a, T, R: CryptoRng + RngCore, K, const P: usize> AttributeServer<'a, T, R, K, P>
 where
     T: embedded_io_async::Read + embedded_io_async::Write,
+    K: AsyncClock,
 {
     /// Create a new instance of the AttributeServer
     ///
     /// When _NOT_ using the `crypto` feature you can pass a mutual reference to `bleps::no_rng::NoRng`
     pub fn new(
-        ble: &'a mut Ble<T>,
+        ble: &'a mut Ble<T, K, P>,
         attributes: &'a mut [Attribute<'a>],
         rng: &'a mut R,
-    ) -> AttributeServer<'a, T, R> {
+    ) -> AttributeServer<'a, T, R, K, P> {
         AttributeServer::new_with_ltk(
             ble,
             attributes,
@@ -61,12 +65,12 @@ where
 
     /// Create a new instance, optionally provide an LTK
     pub fn new_with_ltk(
-        ble: &'a mut Ble<T>,
+        ble: &'a mut Ble<T, K, P>,
         attributes: &'a mut [Attribute<'a>],
         _local_addr: Addr,
         _ltk: Option<u128>,
         _rng: &'a mut R,
-    ) -> AttributeServer<'a, T, R> {
+    ) -> AttributeServer<'a, T, R, K, P> {
         for (i, attr) in attributes.iter_mut().enumerate() {
             attr.handle = i as u16 + 1;
         }
@@ -117,78 +121,73 @@ where
     }
 
     /// Run the GATT server until disconnect
+    ///
+    /// Only the wait for the next packet is raced against `notifier`, polling is cancel safe.
+    /// Handling a packet and sending a notification always run to completion.
     pub async fn run<F, N>(&mut self, notifier: &'a mut F) -> Result<(), AttributeServerError>
     where
         F: FnMut() -> N,
         N: core::future::Future<Output = NotificationData>,
     {
-        let notification_to_send = Mutex::new(RefCell::new(None));
+        let mut notification: Option<NotificationData> = None;
         loop {
-            let notifier_future = async { notifier().await };
-            let worker_future = async {
-                let notification: Option<NotificationData> =
-                    critical_section::with(|cs| notification_to_send.borrow_ref_mut(cs).take());
-
-                // check if notifications are enabled for the characteristic handle
-                let notification = if let Some(notification) = notification {
-                    let attr = self
-                        .attributes
-                        .iter()
-                        .enumerate()
-                        .find(|(_idx, attr)| attr.handle == notification.handle);
-                    let enabled = if let Some((idx, _)) = attr {
-                        // assume the next descriptor is the "Client Characteristic Configuration" Descriptor
-                        // which is always true when using the macro
-                        if self.attributes.len() > idx + 1
-                            && self.attributes[idx + 1].uuid == Uuid::Uuid16(0x2902)
-                        {
-                            let mut cccd = [0u8; 1];
-                            let cccd_len =
-                                self.get_characteristic_value((idx + 2) as u16, 0, &mut cccd[..]);
-                            if let Some(1) = cccd_len {
-                                cccd[0] == 1
-                            } else {
-                                false
-                            }
-                        } else {
-                            false
-                        }
-                    } else {
-                        false
-                    };
-                    if enabled {
-                        Some(notification)
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                };
-
-                self.do_work_with_notification(notification).await
+            if self.ble.needs_reset() {
+                self.recover().await?;
+                break;
+            }
+
+            let notification_to_send = notification
+                .take()
+                .filter(|notification| self.notifications_enabled(notification.handle));
+            self.send_notification(notification_to_send).await;
+
+            let polled = {
+                let notifier_future = notifier();
+                let poll_future = self.poll_packet();
+                pin_mut!(notifier_future);
+                pin_mut!(poll_future);
+
+                match select(notifier_future, poll_future).await {
+                    Either::Left((next, _)) => Either::Left(next),
+                    Either::Right((packet, _)) => Either::Right(packet?),
+                }
             };
-            pin_mut!(notifier_future);
-            pin_mut!(worker_future);
-
-            let notification = match futures::future::select(notifier_future, worker_future).await {
-                Either::Left((notification, _)) => Some(notification),
-                Either::Right((value, _)) => {
-                    if value? == WorkResult::GotDisconnected {
-                        break;
-                    }
-                    None
+
+            let packet = match polled {
+                Either::Left(next) => {
+                    notification = Some(next);
+                    continue;
                 }
+                Either::Right(packet) => packet,
             };
 
-            if let Some(notification) = notification {
-                critical_section::with(|cs| {
-                    notification_to_send
-                        .borrow_ref_mut(cs)
-                        .replace(notification);
-                });
+            if self.handle_packet(packet).await? == WorkResult::GotDisconnected {
+                break;
             }
         }
 
         Ok(())
     }
+
+    /// Whether the client enabled notifications for the characteristic value at `handle`
+    pub(crate) fn notifications_enabled(&mut self, handle: u16) -> bool {
+        let Some(idx) = self
+            .attributes
+            .iter()
+            .position(|attr| attr.handle == handle)
+        else {
+            return false;
+        };
+
+        // assume the next descriptor is the "Client Characteristic Configuration" Descriptor
+        // which is always true when using the macro
+        if self.attributes.len() > idx + 1 && self.attributes[idx + 1].uuid == Uuid::Uuid16(0x2902)
+        {
+            let mut cccd = [0u8; 1];
+            let cccd_len = self.get_characteristic_value((idx + 2) as u16, 0, &mut cccd[..]);
+            matches!(cccd_len, Some(1)) && cccd[0] == 1
+        } else {
+            false
+        }
+    }
 }
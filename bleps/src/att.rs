@@ -1,4 +1,7 @@
-use crate::{l2cap::L2capPacket, Data};
+use crate::{attribute_server::MTU, l2cap::L2capPacket, Data};
+
+/// Capacity of an ATT PDU, the largest MTU the attribute server negotiates
+pub const ATT_PDU_LEN: usize = MTU as usize;
 
 pub const ATT_READ_BY_GROUP_TYPE_REQUEST_OPCODE: u8 = 0x10;
 const ATT_READ_BY_GROUP_TYPE_RESPONSE_OPCODE: u8 = 0x11;
@@ -30,7 +33,7 @@ pub enum Uuid {
     Uuid128([u8; 16]),
 }
 
-impl Data {
+impl<const N: usize> Data<N> {
     pub fn append_uuid(&mut self, uuid: &Uuid) {
         match uuid {
             Uuid::Uuid16(uuid) => {
@@ -66,8 +69,8 @@ impl Uuid {
     }
 }
 
-impl From<Data> for Uuid {
-    fn from(data: Data) -> Self {
+impl<const N: usize> From<Data<N>> for Uuid {
+    fn from(data: Data<N>) -> Self {
         match data.len() {
             2 => Uuid::Uuid16(u16::from_le_bytes(data.as_slice().try_into().unwrap())),
             16 => {
@@ -131,7 +134,8 @@ pub enum AttErrorCode {
 }
 
 #[derive(Debug)]
-pub enum Att {
+/// An ATT PDU, written values are borrowed from the L2CAP PDU
+pub enum Att<'a> {
     ReadByGroupTypeReq {
         start: u16,
         end: u16,
@@ -147,11 +151,11 @@ pub enum Att {
     },
     WriteReq {
         handle: u16,
-        data: Data,
+        data: &'a [u8],
     },
     WriteCmd {
         handle: u16,
-        data: Data,
+        data: &'a [u8],
     },
     ExchangeMtu {
         mtu: u16,
@@ -169,7 +173,7 @@ pub enum Att {
     PrepareWriteReq {
         handle: u16,
         offset: u16,
-        value: Data,
+        value: &'a [u8],
     },
     ExecuteWriteReq {
         flags: u8,
@@ -182,30 +186,96 @@ pub enum Att {
 
 #[derive(Debug)]
 pub enum AttDecodeError {
-    Other,
-    UnknownOpcode(u8, Data),
-    UnexpectedPayload,
+    /// The PDU doesn't even contain an opcode
+    Empty,
+    /// The PDU is too short for the parameters of `opcode`
+    Truncated {
+        opcode: u8,
+    },
+    UnknownOpcode(u8),
+    /// The parameters of the `opcode` request for `handle` have an unexpected length
+    UnexpectedPayload {
+        opcode: u8,
+        handle: u16,
+    },
 }
 
-impl Att {
-    pub fn decode(packet: L2capPacket) -> Result<Self, AttDecodeError> {
-        let opcode = packet.payload.as_slice()[0];
-        let payload = &packet.payload.as_slice()[1..];
+impl core::fmt::Display for AttDecodeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            AttDecodeError::Empty => write!(f, "empty ATT PDU"),
+            AttDecodeError::Truncated { opcode } => {
+                write!(f, "ATT PDU with opcode {:#04x} is truncated", opcode)
+            }
+            AttDecodeError::UnknownOpcode(opcode) => {
+                write!(f, "unknown ATT opcode {:#04x}", opcode)
+            }
+            AttDecodeError::UnexpectedPayload { opcode, handle } => write!(
+                f,
+                "unexpected parameters in ATT PDU with opcode {:#04x} for handle {:#06x}",
+                opcode, handle
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for AttDecodeError {
+    fn format(&self, fmt: defmt::Formatter) {
+        match self {
+            AttDecodeError::Empty => defmt::write!(fmt, "Empty"),
+            AttDecodeError::Truncated { opcode } => {
+                defmt::write!(fmt, "Truncated {{ opcode: {=u8:#04x} }}", opcode)
+            }
+            AttDecodeError::UnknownOpcode(opcode) => {
+                defmt::write!(fmt, "UnknownOpcode({=u8:#04x})", opcode)
+            }
+            AttDecodeError::UnexpectedPayload { opcode, handle } => defmt::write!(
+                fmt,
+                "UnexpectedPayload {{ opcode: {=u8:#04x}, handle: {=u16:#06x} }}",
+                opcode,
+                handle
+            ),
+        }
+    }
+}
+
+/// Makes sure the parameters of `opcode` contain at least `len` bytes
+fn check_len(opcode: u8, payload: &[u8], len: usize) -> Result<(), AttDecodeError> {
+    if payload.len() < len {
+        log::warn!(
+            "ATT PDU too short, expected {} bytes, got {:02x?}",
+            len,
+            payload
+        );
+        return Err(AttDecodeError::Truncated { opcode });
+    }
+
+    Ok(())
+}
+
+impl<'a> Att<'a> {
+    pub fn decode(packet: L2capPacket<'a>) -> Result<Self, AttDecodeError> {
+        let (opcode, payload) = match packet.payload.split_first() {
+            Some((opcode, payload)) => (*opcode, payload),
+            None => return Err(AttDecodeError::Empty),
+        };
 
         match opcode {
             ATT_READ_BY_GROUP_TYPE_REQUEST_OPCODE => {
+                check_len(opcode, payload, 6)?;
                 let start_handle = (payload[0] as u16) + ((payload[1] as u16) << 8);
                 let end_handle = (payload[2] as u16) + ((payload[3] as u16) << 8);
 
                 let group_type = if payload.len() == 6 {
                     Uuid::Uuid16((payload[4] as u16) + ((payload[5] as u16) << 8))
                 } else if payload.len() == 20 {
-                    let uuid = payload[4..20]
-                        .try_into()
-                        .map_err(|_| AttDecodeError::Other)?;
-                    Uuid::Uuid128(uuid)
+                    Uuid::Uuid128(payload[4..20].try_into().unwrap())
                 } else {
-                    return Err(AttDecodeError::UnexpectedPayload);
+                    return Err(AttDecodeError::UnexpectedPayload {
+                        opcode,
+                        handle: start_handle,
+                    });
                 };
 
                 Ok(Self::ReadByGroupTypeReq {
@@ -215,18 +285,19 @@ impl Att {
                 })
             }
             ATT_READ_BY_TYPE_REQUEST_OPCODE => {
+                check_len(opcode, payload, 6)?;
                 let start_handle = (payload[0] as u16) + ((payload[1] as u16) << 8);
                 let end_handle = (payload[2] as u16) + ((payload[3] as u16) << 8);
 
                 let attribute_type = if payload.len() == 6 {
                     Uuid::Uuid16((payload[4] as u16) + ((payload[5] as u16) << 8))
                 } else if payload.len() == 20 {
-                    let uuid = payload[4..20]
-                        .try_into()
-                        .map_err(|_| AttDecodeError::Other)?;
-                    Uuid::Uuid128(uuid)
+                    Uuid::Uuid128(payload[4..20].try_into().unwrap())
                 } else {
-                    return Err(AttDecodeError::UnexpectedPayload);
+                    return Err(AttDecodeError::UnexpectedPayload {
+                        opcode,
+                        handle: start_handle,
+                    });
                 };
 
                 Ok(Self::ReadByTypeReq {
@@ -236,29 +307,32 @@ impl Att {
                 })
             }
             ATT_READ_REQUEST_OPCODE => {
+                check_len(opcode, payload, 2)?;
                 let handle = (payload[0] as u16) + ((payload[1] as u16) << 8);
 
                 Ok(Self::ReadReq { handle })
             }
             ATT_WRITE_REQUEST_OPCODE => {
+                check_len(opcode, payload, 2)?;
                 let handle = (payload[0] as u16) + ((payload[1] as u16) << 8);
-                let mut data = Data::default();
-                data.append(&payload[2..]);
+                let data = &payload[2..];
 
                 Ok(Self::WriteReq { handle, data })
             }
             ATT_WRITE_CMD_OPCODE => {
+                check_len(opcode, payload, 2)?;
                 let handle = (payload[0] as u16) + ((payload[1] as u16) << 8);
-                let mut data = Data::default();
-                data.append(&payload[2..]);
+                let data = &payload[2..];
 
                 Ok(Self::WriteCmd { handle, data })
             }
             ATT_EXCHANGE_MTU_REQUEST_OPCODE => {
+                check_len(opcode, payload, 2)?;
                 let mtu = (payload[0] as u16) + ((payload[1] as u16) << 8);
                 Ok(Self::ExchangeMtu { mtu })
             }
             ATT_FIND_BY_TYPE_VALUE_REQUEST_OPCODE => {
+                check_len(opcode, payload, 8)?;
                 let start_handle = (payload[0] as u16) + ((payload[1] as u16) << 8);
                 let end_handle = (payload[2] as u16) + ((payload[3] as u16) << 8);
                 let att_type = (payload[4] as u16) + ((payload[5] as u16) << 8);
@@ -272,6 +346,7 @@ impl Att {
                 })
             }
             ATT_FIND_INFORMATION_REQ_OPCODE => {
+                check_len(opcode, payload, 4)?;
                 let start_handle = (payload[0] as u16) + ((payload[1] as u16) << 8);
                 let end_handle = (payload[2] as u16) + ((payload[3] as u16) << 8);
 
@@ -281,30 +356,33 @@ impl Att {
                 })
             }
             ATT_PREPARE_WRITE_REQ_OPCODE => {
+                check_len(opcode, payload, 4)?;
                 let handle = (payload[0] as u16) + ((payload[1] as u16) << 8);
                 let offset = (payload[2] as u16) + ((payload[3] as u16) << 8);
                 let value = &payload[4..];
                 Ok(Self::PrepareWriteReq {
                     handle,
                     offset,
-                    value: Data::new(value),
+                    value,
                 })
             }
             ATT_EXECUTE_WRITE_REQ_OPCODE => {
+                check_len(opcode, payload, 1)?;
                 let flags = payload[0];
                 Ok(Self::ExecuteWriteReq { flags })
             }
             ATT_READ_BLOB_REQ_OPCODE => {
+                check_len(opcode, payload, 4)?;
                 let handle = (payload[0] as u16) + ((payload[1] as u16) << 8);
                 let offset = (payload[2] as u16) + ((payload[3] as u16) << 8);
                 Ok(Self::ReadBlobReq { handle, offset })
             }
-            _ => Err(AttDecodeError::UnknownOpcode(opcode, Data::new(payload))),
+            _ => Err(AttDecodeError::UnknownOpcode(opcode)),
         }
     }
 }
 
-impl Data {
+impl Data<ATT_PDU_LEN> {
     pub fn append_attribute_data(
         &mut self,
         attribute_handle: u16,
@@ -394,6 +472,10 @@ impl Data {
     }
 
     pub fn append_att_find_information_response(&mut self, handle: u16, uuid: &Uuid) -> bool {
+        if self.len() + 2 + uuid.len() > self.capacity() {
+            return false;
+        }
+
         if self.data[1] == 0 {
             self.data[1] = uuid.get_type();
         } else if self.data[1] != uuid.get_type() {
@@ -1,7 +1,7 @@
 use core::{fmt, mem::size_of, slice};
 
 use crate::{
-    att::{AttErrorCode, Uuid},
+    att::{AttErrorCode, Uuid, ATT_PDU_LEN},
     Data,
 };
 
@@ -349,7 +349,7 @@ impl<'a> Attribute<'a> {
         }
     }
 
-    pub(crate) fn value(&mut self) -> Result<Data, AttErrorCode> {
+    pub(crate) fn value(&mut self) -> Result<Data<ATT_PDU_LEN>, AttErrorCode> {
         let mut data = Data::default();
         if self.data.readable() {
             let len = self.data.read(0, data.as_slice_mut())?;
@@ -7,18 +7,17 @@ use rand_core::{CryptoRng, RngCore};
 #[cfg(feature = "crypto")]
 use crate::sm::SecurityManager;
 use crate::{
-    acl::{AclPacket, BoundaryFlag, HostBroadcastFlag},
     att::{
         Att, AttDecodeError, AttErrorCode, Uuid, ATT_FIND_BY_TYPE_VALUE_REQUEST_OPCODE,
-        ATT_FIND_INFORMATION_REQ_OPCODE, ATT_PREPARE_WRITE_REQ_OPCODE, ATT_READ_BLOB_REQ_OPCODE,
-        ATT_READ_BY_GROUP_TYPE_REQUEST_OPCODE, ATT_READ_BY_TYPE_REQUEST_OPCODE,
-        ATT_READ_REQUEST_OPCODE, ATT_WRITE_REQUEST_OPCODE,
+        ATT_FIND_INFORMATION_REQ_OPCODE, ATT_PDU_LEN, ATT_PREPARE_WRITE_REQ_OPCODE,
+        ATT_READ_BLOB_REQ_OPCODE, ATT_READ_BY_GROUP_TYPE_REQUEST_OPCODE,
+        ATT_READ_BY_TYPE_REQUEST_OPCODE, ATT_READ_REQUEST_OPCODE, ATT_WRITE_REQUEST_OPCODE,
     },
     attribute::Attribute,
-    command::{Command, LE_OGF, SET_ADVERTISING_DATA_OCF},
-    event::EventType,
+    command::{Command, DISCONNECT_OCF, LINK_CONTROL_OGF},
+    event::{ErrorCode, EventType},
     l2cap::{L2capDecodeError, L2capPacket},
-    Addr, Ble, Data, Error,
+    Addr, Ble, CapacityError, Data, Error, PENDING_POLL_RESULTS,
 };
 
 pub const PRIMARY_SERVICE_UUID16: Uuid = Uuid::Uuid16(0x2800);
@@ -48,7 +47,45 @@ pub enum WorkResult {
 pub enum AttributeServerError {
     L2capError(L2capDecodeError),
     AttError(AttDecodeError),
-    SecurityManagerError,
+    /// Pairing failed for the given reason, the peer has been told
+    #[cfg(feature = "crypto")]
+    SecurityManagerError(crate::sm::SecurityManagerError),
+    HciError(Error),
+}
+
+impl core::fmt::Display for AttributeServerError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            AttributeServerError::L2capError(err) => write!(f, "{}", err),
+            AttributeServerError::AttError(err) => write!(f, "{}", err),
+            #[cfg(feature = "crypto")]
+            AttributeServerError::SecurityManagerError(reason) => {
+                write!(f, "pairing failed: {}", reason)
+            }
+            AttributeServerError::HciError(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for AttributeServerError {
+    fn format(&self, fmt: defmt::Formatter) {
+        match self {
+            AttributeServerError::L2capError(err) => defmt::write!(fmt, "L2capError({})", err),
+            AttributeServerError::AttError(err) => defmt::write!(fmt, "AttError({})", err),
+            #[cfg(feature = "crypto")]
+            AttributeServerError::SecurityManagerError(reason) => {
+                defmt::write!(fmt, "SecurityManagerError({})", reason)
+            }
+            AttributeServerError::HciError(err) => defmt::write!(fmt, "HciError({})", err),
+        }
+    }
+}
+
+impl From<Error> for AttributeServerError {
+    fn from(err: Error) -> Self {
+        AttributeServerError::HciError(err)
+    }
 }
 
 impl From<L2capDecodeError> for AttributeServerError {
@@ -63,8 +100,8 @@ impl From<AttDecodeError> for AttributeServerError {
     }
 }
 
-pub struct AttributeServer<'a, R: CryptoRng + RngCore> {
-    ble: &'a mut Ble<'a>,
+pub struct AttributeServer<'a, R: CryptoRng + RngCore, const P: usize = PENDING_POLL_RESULTS> {
+    ble: &'a mut Ble<'a, P>,
     // The MTU negotiated for this server. In principle this can be different per-client,
     // but we only support one client at a time, so we only need to store one value
     // (and reset it to the default when disconnecting).
@@ -73,7 +110,7 @@ pub struct AttributeServer<'a, R: CryptoRng + RngCore> {
     attributes: &'a mut [Attribute<'a>],
 
     #[cfg(feature = "crypto")]
-    security_manager: SecurityManager<'a, Ble<'a>, R>,
+    security_manager: SecurityManager<'a, Ble<'a, P>, R>,
 
     #[cfg(feature = "crypto")]
     pub(crate) pin_callback: Option<&'a mut dyn FnMut(u32)>,
@@ -85,10 +122,11 @@ pub struct AttributeServer<'a, R: CryptoRng + RngCore> {
 // Using the bleps-dedup proc-macro to de-duplicate the async/sync code
 // The macro will remove async/await for the SYNC implementation
 bleps_dedup::dedup! {
-    impl<'a, R: CryptoRng + RngCore> SYNC AttributeServer<'a, R>
-    impl<'a, T, R: CryptoRng + RngCore> ASYNC crate::async_attribute_server::AttributeServer<'a, T, R>
+    impl<'a, R: CryptoRng + RngCore, const P: usize> SYNC AttributeServer<'a, R, P>
+    impl<'a, T, R: CryptoRng + RngCore, K, const P: usize> ASYNC crate::async_attribute_server::AttributeServer<'a, T, R, K, P>
         where
             T: embedded_io_async::Read + embedded_io_async::Write,
+            K: crate::clock::AsyncClock,
     {
         pub fn get_characteristic_value(
             &mut self,
@@ -106,18 +144,12 @@ bleps_dedup::dedup! {
         }
 
         pub async fn update_le_advertising_data(&mut self, data: Data) -> Result<EventType, Error> {
-            self.ble
-                .write_bytes(Command::LeSetAdvertisingData { data }.encode().as_slice())
-                .await;
-            self.ble
-                .wait_for_command_complete(LE_OGF, SET_ADVERTISING_DATA_OCF)
-                .await?
-                .check_command_completed()
+            self.ble.cmd_set_le_advertising_data(data).await
         }
 
-        pub async fn disconnect(&mut self, reason: u8) -> Result<EventType, Error> {
+        pub async fn disconnect(&mut self, reason: ErrorCode) -> Result<EventType, Error> {
             self.ble
-                .write_bytes(
+                .write_command(
                     Command::Disconnect {
                         connection_handle: 0,
                         reason,
@@ -125,8 +157,12 @@ bleps_dedup::dedup! {
                     .encode()
                     .as_slice(),
                 )
-                .await;
-            Ok(EventType::Unknown)
+                .await?;
+            // the controller answers with Command Status, Disconnection Complete follows later
+            self.ble
+                .wait_for_command_complete(LINK_CONTROL_OGF, DISCONNECT_OCF)
+                .await?
+                .check_command_completed()
         }
 
         pub async fn do_work(&mut self) -> Result<WorkResult, AttributeServerError> {
@@ -137,20 +173,56 @@ bleps_dedup::dedup! {
             &mut self,
             notification_data: Option<NotificationData>,
         ) -> Result<WorkResult, AttributeServerError> {
+            if self.ble.needs_reset() {
+                return self.recover().await;
+            }
+
+            self.send_notification(notification_data).await;
+            let packet = self.poll_packet().await?;
+            self.handle_packet(packet).await
+        }
+
+        pub(crate) async fn send_notification(
+            &mut self,
+            notification_data: Option<NotificationData>,
+        ) {
             if let Some(notification_data) = notification_data {
                 let mut answer = notification_data.data;
                 answer.limit_len(self.mtu as usize - 3);
                 let mut data = Data::new_att_value_ntf(notification_data.handle);
-                data.append(&answer.as_slice());
+                if data.try_append(answer.as_slice()).is_err() {
+                    log::warn!("Notification doesn't fit into an ATT PDU, dropping it");
+                    return;
+                }
                 self.write_att(self.src_handle, data).await;
             }
+        }
 
-            let packet = self.ble.poll().await;
+        /// Polls the controller, errors the HCI reader recovers from by itself are only logged
+        pub(crate) async fn poll_packet(
+            &mut self,
+        ) -> Result<Option<crate::PollResult>, AttributeServerError> {
+            let packet = match self.ble.poll().await {
+                Ok(packet) => packet,
+                Err(err @ (Error::UnknownPacketType(_) | Error::Truncated)) => {
+                    // the HCI reader resynchronises by itself, so just carry on
+                    log::warn!("Error polling HCI: {:?}", err);
+                    None
+                }
+                Err(err) => return Err(err.into()),
+            };
 
             if packet.is_some() {
                 log::trace!("polled: {:?}", packet);
             }
 
+            Ok(packet)
+        }
+
+        pub(crate) async fn handle_packet(
+            &mut self,
+            packet: Option<crate::PollResult>,
+        ) -> Result<WorkResult, AttributeServerError> {
             match packet {
                 None => Ok(WorkResult::DidWork),
                 Some(packet) => match packet {
@@ -165,12 +237,13 @@ bleps_dedup::dedup! {
                     }
                     crate::PollResult::Event(EventType::ConnectionComplete {
                         status: _status,
-                        handle: _,
-                        role: _,
                         peer_address: _peer_address,
-                        interval: _,
-                        latency: _,
-                        timeout: _,
+                        ..
+                    })
+                    | crate::PollResult::Event(EventType::EnhancedConnectionComplete {
+                        status: _status,
+                        peer_address: _peer_address,
+                        ..
                     }) => {
                         #[cfg(feature = "crypto")]
                         if _status == 0 {
@@ -197,9 +270,11 @@ bleps_dedup::dedup! {
                         }
                         Ok(WorkResult::DidWork)
                     }
+                    crate::PollResult::Event(EventType::HardwareError { .. }) => self.recover().await,
                     crate::PollResult::Event(_) => Ok(WorkResult::DidWork),
+                    crate::PollResult::SyncData(_) | crate::PollResult::IsoData(_) => Ok(WorkResult::DidWork),
                     crate::PollResult::AsyncData(packet) => {
-                        let (src_handle, l2cap_packet) = L2capPacket::decode(packet)?;
+                        let (src_handle, l2cap_packet) = L2capPacket::decode(&packet)?;
                         if l2cap_packet.channel == 6 {
                             // handle SM
                             #[cfg(feature = "crypto")]
@@ -296,6 +371,13 @@ bleps_dedup::dedup! {
             }
         }
 
+        /// Resets the failed controller, the connection is gone afterwards
+        pub(crate) async fn recover(&mut self) -> Result<WorkResult, AttributeServerError> {
+            self.ble.reset_controller().await?;
+            self.mtu = BASE_MTU;
+            Ok(WorkResult::GotDisconnected)
+        }
+
         async fn handle_read_by_group_type_req(
             &mut self,
             src_handle: u16,
@@ -397,15 +479,19 @@ bleps_dedup::dedup! {
             self.write_att(src_handle, response).await;
         }
 
-        fn handle_write(&mut self, handle: u16, data: Data) -> Result<(), AttErrorCode> {
+        fn handle_write(&mut self, handle: u16, data: &[u8]) -> Result<(), AttErrorCode> {
             let Some((index, att)) = self.attributes.iter_mut().enumerate().find(|(_, att)| att.handle == handle) else {
                 return Err(AttErrorCode::InvalidHandle);
             };
             if !att.data.writable() {
                 return Err(AttErrorCode::WriteNotPermitted);
             }
+            let is_cccd = att.uuid == Uuid::Uuid16(0x2902);
+            if is_cccd && data.is_empty() {
+                return Err(AttErrorCode::InvalidAttributeValueLength);
+            }
 
-            let err = att.data.write(0, data.as_slice());
+            let err = att.data.write(0, data);
             if let Err(e) = err {
                 log::debug!("write error: {e:?}");
                 return Err(e);
@@ -413,22 +499,22 @@ bleps_dedup::dedup! {
 
             // If this is a Client Characteristic Configuration descriptor, notify the parent of a change
             // otherwise return immediatly.
-            if att.uuid != Uuid::Uuid16(0x2902) {
+            if !is_cccd {
                 return Ok(());
             }
 
             // Here we make the same assumption made in async_atribute_server that the CCCD directly follows
             // the charactaristic attribute.
             let parrent_att = &mut self.attributes[index-1];
-            parrent_att.data.enable_notification(data.as_slice()[0] & 0x1 == 0x1)
+            parrent_att.data.enable_notification(data[0] & 0x1 == 0x1)
         }
 
-        async fn handle_write_cmd(&mut self, _src_handle: u16, handle: u16, data: Data) {
+        async fn handle_write_cmd(&mut self, _src_handle: u16, handle: u16, data: &[u8]) {
             // Write commands can't respond with an error.
             let _ = self.handle_write(handle, data);
         }
 
-        async fn handle_write_req(&mut self, src_handle: u16, handle: u16, data: Data) {
+        async fn handle_write_req(&mut self, src_handle: u16, handle: u16, data: &[u8]) {
             let err = self.handle_write(handle, data);
 
             let response = match err {
@@ -504,7 +590,7 @@ bleps_dedup::dedup! {
             src_handle: u16,
             handle: u16,
             offset: u16,
-            value: Data,
+            value: &[u8],
         ) {
             let mut data = Data::new_att_prepare_write_response(handle, offset);
             let mut err = Err(AttErrorCode::AttributeNotFound);
@@ -512,9 +598,12 @@ bleps_dedup::dedup! {
             for att in self.attributes.iter_mut() {
                 if att.handle == handle {
                     if att.data.writable() {
-                        err = att.data.write(offset as usize, value.as_slice());
+                        err = att.data.write(offset as usize, value);
+                    }
+                    // the response echoes the value
+                    if err.is_ok() && data.try_append(value).is_err() {
+                        err = Err(AttErrorCode::InvalidAttributeValueLength);
                     }
-                    data.append(value.as_slice());
                     break;
                 }
             }
@@ -560,26 +649,21 @@ bleps_dedup::dedup! {
             self.write_att(src_handle, response).await;
         }
 
-        async fn write_att(&mut self, handle: u16, data: Data) {
+        async fn write_att(&mut self, handle: u16, data: Data<ATT_PDU_LEN>) {
             log::debug!("src_handle {}", handle);
             log::debug!("data {:x?}", data.as_slice());
 
             let res = L2capPacket::encode(data);
             log::trace!("encoded_l2cap {:x?}", res.as_slice());
 
-            let res = AclPacket::encode(
-                handle,
-                BoundaryFlag::FirstAutoFlushable,
-                HostBroadcastFlag::NoBroadcast,
-                res,
-            );
-            log::trace!("writing {:x?}", res.as_slice());
-            self.ble.write_bytes(res.as_slice()).await;
+            if let Err(err) = self.ble.write_acl(handle, res).await {
+                log::warn!("Failed to write ATT PDU: {:?}", err);
+            }
         }
     }
 }
 
-impl<'a, R: CryptoRng + RngCore> AttributeServer<'a, R> {
+impl<'a, R: CryptoRng + RngCore, const P: usize> AttributeServer<'a, R, P> {
     pub fn get_conn_handle(&self) -> ReadRssi {
         return ReadRssi::new(ConnHandle::new(self.src_handle));
     }
@@ -587,10 +671,10 @@ impl<'a, R: CryptoRng + RngCore> AttributeServer<'a, R> {
     ///
     /// When _NOT_ using the `crypto` feature you can pass a mutual reference to `bleps::no_rng::NoRng`
     pub fn new(
-        ble: &'a mut Ble<'a>,
+        ble: &'a mut Ble<'a, P>,
         attributes: &'a mut [Attribute<'a>],
         rng: &'a mut R,
-    ) -> AttributeServer<'a, R> {
+    ) -> AttributeServer<'a, R, P> {
         AttributeServer::new_with_ltk(
             ble,
             attributes,
@@ -602,12 +686,12 @@ impl<'a, R: CryptoRng + RngCore> AttributeServer<'a, R> {
 
     /// Create a new instance, optionally provide an LTK
     pub fn new_with_ltk(
-        ble: &'a mut Ble<'a>,
+        ble: &'a mut Ble<'a, P>,
         attributes: &'a mut [Attribute<'a>],
         _local_addr: Addr,
         _ltk: Option<u128>,
         _rng: &'a mut R,
-    ) -> AttributeServer<'a, R> {
+    ) -> AttributeServer<'a, R, P> {
         for (i, attr) in attributes.iter_mut().enumerate() {
             attr.handle = i as u16 + 1;
         }
@@ -665,14 +749,18 @@ impl<'a, R: CryptoRng + RngCore> AttributeServer<'a, R> {
 #[derive(Debug)]
 pub struct NotificationData {
     pub(crate) handle: u16,
-    pub(crate) data: Data,
+    pub(crate) data: Data<ATT_PDU_LEN>,
 }
 
 impl NotificationData {
-    pub fn new(handle: u16, data: &[u8]) -> Self {
-        Self {
+    /// `data` is cut to the negotiated MTU minus 3 bytes when sent, data not even fitting into
+    /// [`ATT_PDU_LEN`] is rejected
+    pub fn new(handle: u16, data: &[u8]) -> Result<Self, CapacityError> {
+        let mut value = Data::default();
+        value.try_append(data)?;
+        Ok(Self {
             handle,
-            data: Data::new(data),
-        }
+            data: value,
+        })
     }
 }
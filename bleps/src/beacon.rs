@@ -0,0 +1,268 @@
+use core::time::Duration;
+
+use crate::{
+    ad_structure::{
+        create_advertising_data, AdStructure, UuidList, BR_EDR_NOT_SUPPORTED,
+        LE_GENERAL_DISCOVERABLE,
+    },
+    att::Uuid,
+    AdvertisingParameters, AdvertisingParametersError, AdvertisingType, Data,
+};
+
+/// The interval beacons are usually sent with
+pub const BEACON_ADVERTISING_INTERVAL: Duration = Duration::from_millis(100);
+
+pub const APPLE_COMPANY_IDENTIFIER: u16 = 0x004c;
+pub const EDDYSTONE_SERVICE_UUID16: u16 = 0xfeaa;
+
+const EDDYSTONE_SERVICE_UUIDS: &[Uuid] = &[Uuid::Uuid16(EDDYSTONE_SERVICE_UUID16)];
+const BEACON_FLAGS: u8 = LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED;
+
+const EDDYSTONE_UID_FRAME: u8 = 0x00;
+const EDDYSTONE_URL_FRAME: u8 = 0x10;
+const EDDYSTONE_TLM_FRAME: u8 = 0x20;
+const EDDYSTONE_EID_FRAME: u8 = 0x30;
+
+/// The URL schemes replaced by their index in an Eddystone-URL frame
+const EDDYSTONE_URL_SCHEMES: [&str; 4] = ["http://www.", "https://www.", "http://", "https://"];
+
+/// The URL parts replaced by their index in an Eddystone-URL frame
+const EDDYSTONE_URL_EXPANSIONS: [&str; 14] = [
+    ".com/", ".org/", ".edu/", ".net/", ".info/", ".biz/", ".gov/", ".com", ".org", ".edu", ".net",
+    ".info", ".biz", ".gov",
+];
+
+/// The encoded URL of an Eddystone-URL frame is limited to 17 bytes
+const EDDYSTONE_URL_LEN: usize = 17;
+
+/// The AD structures of a beacon
+pub type BeaconAdStructures<'a> = heapless::Vec<AdStructure<'a>, 3>;
+
+/// Advertising parameters for a beacon nobody can connect to, sent every `interval`
+///
+/// `interval` is rounded down to a multiple of 0.625 ms and needs to be within 20 ms to 10.24 s.
+pub fn non_connectable_advertising_parameters(
+    interval: Duration,
+) -> Result<AdvertisingParameters, AdvertisingParametersError> {
+    AdvertisingParameters::builder()
+        .interval(interval, interval)
+        .advertising_type(AdvertisingType::AdvNonConnInd)
+        .build()
+}
+
+pub trait Beacon {
+    /// The AD structures announcing the beacon
+    fn ad_structures(&self) -> BeaconAdStructures<'_>;
+
+    /// Data for [`crate::Ble::cmd_set_le_advertising_data`]
+    fn advertising_data(&self) -> Data {
+        create_advertising_data(&self.ad_structures()).expect("beacons fit into 31 bytes")
+    }
+
+    /// Parameters for [`crate::Ble::cmd_set_le_advertising_parameters_custom`] sending the beacon
+    /// every [`BEACON_ADVERTISING_INTERVAL`]
+    fn advertising_parameters(&self) -> AdvertisingParameters {
+        non_connectable_advertising_parameters(BEACON_ADVERTISING_INTERVAL)
+            .expect("the beacon interval is valid")
+    }
+}
+
+fn beacon_ad_structures<'a>(structures: &[AdStructure<'a>]) -> BeaconAdStructures<'a> {
+    BeaconAdStructures::from_slice(structures).unwrap()
+}
+
+/// An Apple iBeacon
+#[derive(Debug, Clone, Copy)]
+pub struct IBeacon {
+    payload: [u8; 23],
+}
+
+impl IBeacon {
+    /// `uuid` is the proximity UUID in the order it's written, `measured_power` the RSSI in dBm
+    /// measured at 1 m distance
+    pub fn new(uuid: [u8; 16], major: u16, minor: u16, measured_power: i8) -> Self {
+        let mut payload = [0u8; 23];
+        payload[..2].copy_from_slice(&[0x02, 0x15]);
+        payload[2..18].copy_from_slice(&uuid);
+        payload[18..20].copy_from_slice(&major.to_be_bytes());
+        payload[20..22].copy_from_slice(&minor.to_be_bytes());
+        payload[22] = measured_power as u8;
+        IBeacon { payload }
+    }
+}
+
+impl Beacon for IBeacon {
+    fn ad_structures(&self) -> BeaconAdStructures<'_> {
+        beacon_ad_structures(&[
+            AdStructure::Flags(BEACON_FLAGS),
+            AdStructure::ManufacturerSpecificData {
+                company_identifier: APPLE_COMPANY_IDENTIFIER,
+                payload: &self.payload,
+            },
+        ])
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum EddystoneUrlError {
+    /// The URL doesn't start with `http://` or `https://`
+    UnsupportedScheme,
+    /// The URL contains a space or a character which isn't ASCII
+    InvalidCharacter,
+    /// The encoded URL is longer than 17 bytes
+    TooLong,
+}
+
+impl core::fmt::Display for EddystoneUrlError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            EddystoneUrlError::UnsupportedScheme => write!(f, "URL scheme not supported"),
+            EddystoneUrlError::InvalidCharacter => write!(f, "URL contains an invalid character"),
+            EddystoneUrlError::TooLong => write!(f, "encoded URL longer than 17 bytes"),
+        }
+    }
+}
+
+/// A Google Eddystone frame
+#[derive(Debug, Clone, Copy)]
+pub struct Eddystone {
+    frame: [u8; 20],
+    len: usize,
+}
+
+impl Eddystone {
+    /// An Eddystone-UID frame, `tx_power` is the RSSI in dBm measured at 0 m distance
+    pub fn uid(tx_power: i8, namespace: [u8; 10], instance: [u8; 6]) -> Self {
+        let mut frame = [0u8; 20];
+        frame[0] = EDDYSTONE_UID_FRAME;
+        frame[1] = tx_power as u8;
+        frame[2..12].copy_from_slice(&namespace);
+        frame[12..18].copy_from_slice(&instance);
+        Eddystone { frame, len: 20 }
+    }
+
+    /// An Eddystone-URL frame, `tx_power` is the RSSI in dBm measured at 0 m distance
+    ///
+    /// The scheme and common top level domains are compressed, e.g. `https://example.com/`
+    /// takes 8 bytes of the 17 available.
+    pub fn url(tx_power: i8, url: &str) -> Result<Self, EddystoneUrlError> {
+        let (scheme, mut rest) = EDDYSTONE_URL_SCHEMES
+            .iter()
+            .enumerate()
+            .find_map(|(code, scheme)| Some((code, url.strip_prefix(scheme)?)))
+            .ok_or(EddystoneUrlError::UnsupportedScheme)?;
+
+        let mut frame = [0u8; 20];
+        frame[0] = EDDYSTONE_URL_FRAME;
+        frame[1] = tx_power as u8;
+        frame[2] = scheme as u8;
+        let mut len = 3;
+
+        while !rest.is_empty() {
+            if len - 3 == EDDYSTONE_URL_LEN {
+                return Err(EddystoneUrlError::TooLong);
+            }
+
+            if let Some((code, expansion)) = EDDYSTONE_URL_EXPANSIONS
+                .iter()
+                .enumerate()
+                .find(|(_, expansion)| rest.starts_with(*expansion))
+            {
+                frame[len] = code as u8;
+                rest = &rest[expansion.len()..];
+            } else {
+                let byte = rest.as_bytes()[0];
+                if !byte.is_ascii_graphic() {
+                    return Err(EddystoneUrlError::InvalidCharacter);
+                }
+                frame[len] = byte;
+                rest = &rest[1..];
+            }
+            len += 1;
+        }
+
+        Ok(Eddystone { frame, len })
+    }
+
+    /// An unencrypted Eddystone-TLM frame
+    ///
+    /// `battery_voltage` is in mV, 0 if not supported. `temperature` is in °C as 8.8 fixed point
+    /// number, `i16::MIN` if not supported. `uptime` is in units of 0.1 s.
+    pub fn tlm(
+        battery_voltage: u16,
+        temperature: i16,
+        advertising_count: u32,
+        uptime: u32,
+    ) -> Self {
+        let mut frame = [0u8; 20];
+        frame[0] = EDDYSTONE_TLM_FRAME;
+        frame[2..4].copy_from_slice(&battery_voltage.to_be_bytes());
+        frame[4..6].copy_from_slice(&temperature.to_be_bytes());
+        frame[6..10].copy_from_slice(&advertising_count.to_be_bytes());
+        frame[10..14].copy_from_slice(&uptime.to_be_bytes());
+        Eddystone { frame, len: 14 }
+    }
+
+    /// An Eddystone-EID frame, `tx_power` is the RSSI in dBm measured at 0 m distance
+    pub fn eid(tx_power: i8, eid: [u8; 8]) -> Self {
+        let mut frame = [0u8; 20];
+        frame[0] = EDDYSTONE_EID_FRAME;
+        frame[1] = tx_power as u8;
+        frame[2..10].copy_from_slice(&eid);
+        Eddystone { frame, len: 10 }
+    }
+}
+
+impl Beacon for Eddystone {
+    fn ad_structures(&self) -> BeaconAdStructures<'_> {
+        beacon_ad_structures(&[
+            AdStructure::Flags(BEACON_FLAGS),
+            AdStructure::ServiceUuids16(UuidList::Uuids(EDDYSTONE_SERVICE_UUIDS)),
+            AdStructure::ServiceData16 {
+                uuid: EDDYSTONE_SERVICE_UUID16,
+                data: &self.frame[..self.len],
+            },
+        ])
+    }
+}
+
+/// An AltBeacon
+#[derive(Debug, Clone, Copy)]
+pub struct AltBeacon {
+    manufacturer_id: u16,
+    payload: [u8; 24],
+}
+
+impl AltBeacon {
+    /// `manufacturer_id` is the company identifier of the beacon's manufacturer, `reference_rssi`
+    /// the RSSI in dBm measured at 1 m distance
+    pub fn new(
+        manufacturer_id: u16,
+        beacon_id: [u8; 20],
+        reference_rssi: i8,
+        manufacturer_reserved: u8,
+    ) -> Self {
+        let mut payload = [0u8; 24];
+        payload[..2].copy_from_slice(&[0xbe, 0xac]);
+        payload[2..22].copy_from_slice(&beacon_id);
+        payload[22] = reference_rssi as u8;
+        payload[23] = manufacturer_reserved;
+        AltBeacon {
+            manufacturer_id,
+            payload,
+        }
+    }
+}
+
+impl Beacon for AltBeacon {
+    fn ad_structures(&self) -> BeaconAdStructures<'_> {
+        beacon_ad_structures(&[
+            AdStructure::Flags(BEACON_FLAGS),
+            AdStructure::ManufacturerSpecificData {
+                company_identifier: self.manufacturer_id,
+                payload: &self.payload,
+            },
+        ])
+    }
+}
@@ -0,0 +1,76 @@
+/// Source of the time used for timeouts
+///
+/// Functions and closures returning milliseconds implement it, so
+/// `HciConnector::new(hci, current_millis)` keeps working.
+pub trait Clock {
+    /// Milliseconds since an arbitrary but fixed point in time
+    fn now_millis(&self) -> u64;
+}
+
+impl<F> Clock for F
+where
+    F: Fn() -> u64,
+{
+    fn now_millis(&self) -> u64 {
+        self()
+    }
+}
+
+/// A [`Clock`] the async stack can sleep on instead of polling it
+///
+/// An implementation backed by `embassy-time` could look like this:
+///
+/// ```ignore
+/// struct EmbassyClock;
+///
+/// impl Clock for EmbassyClock {
+///     fn now_millis(&self) -> u64 {
+///         embassy_time::Instant::now().as_millis()
+///     }
+/// }
+///
+/// impl AsyncClock for EmbassyClock {
+///     fn wait_until(&self, millis: u64) -> impl core::future::Future<Output = ()> {
+///         embassy_time::Timer::at(embassy_time::Instant::from_millis(millis))
+///     }
+/// }
+/// ```
+#[cfg(feature = "async")]
+pub trait AsyncClock: Clock {
+    /// Completes once [`Clock::now_millis`] reached `millis`
+    fn wait_until(&self, millis: u64) -> impl core::future::Future<Output = ()>;
+}
+
+/// Plain functions have no timer to sleep on, they yield to the executor until the time has come
+#[cfg(feature = "async")]
+impl<F> AsyncClock for F
+where
+    F: Fn() -> u64,
+{
+    async fn wait_until(&self, millis: u64) {
+        while self() < millis {
+            YieldNow(false).await;
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+struct YieldNow(bool);
+
+#[cfg(feature = "async")]
+impl core::future::Future for YieldNow {
+    type Output = ();
+
+    fn poll(
+        mut self: core::pin::Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+    ) -> core::task::Poll<()> {
+        if self.0 {
+            core::task::Poll::Ready(())
+        } else {
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            core::task::Poll::Pending
+        }
+    }
+}
@@ -1,10 +1,12 @@
-use crate::{AdvertisingParameters, Data};
+use crate::{event::ErrorCode, AdvertisingParameters, Data, Error};
 
 pub const CONTROLLER_OGF: u8 = 0x03;
 pub const RESET_OCF: u16 = 0x03;
 pub const SET_EVENT_MASK_OCF: u16 = 0x01;
 
 pub const LE_OGF: u8 = 0x08;
+pub const LE_READ_BUFFER_SIZE_OCF: u16 = 0x02;
+pub const SET_RANDOM_ADDRESS_OCF: u16 = 0x05;
 pub const SET_ADVERTISING_PARAMETERS_OCF: u16 = 0x06;
 pub const SET_ADVERTISING_DATA_OCF: u16 = 0x08;
 pub const SET_SCAN_RSP_DATA_OCF: u16 = 0x09;
@@ -15,6 +17,7 @@ pub const LINK_CONTROL_OGF: u8 = 0x01;
 pub const DISCONNECT_OCF: u16 = 0x06;
 
 pub const INFORMATIONAL_OGF: u8 = 0x04;
+pub const READ_BUFFER_SIZE_OCF: u16 = 0x05;
 pub const READ_BD_ADDR_OCF: u16 = 0x09;
 
 #[derive(Debug)]
@@ -59,13 +62,30 @@ pub enum Command<'a> {
     Reset,
     LeSetAdvertisingParameters,
     LeSetAdvertisingParametersCustom(&'a AdvertisingParameters),
-    LeSetAdvertisingData { data: Data },
-    LeSetScanRspData { data: Data },
+    LeSetAdvertisingData {
+        data: Data,
+    },
+    LeSetScanRspData {
+        data: Data,
+    },
     LeSetAdvertiseEnable(bool),
-    Disconnect { connection_handle: u16, reason: u8 },
-    LeLongTermKeyRequestReply { handle: u16, ltk: u128 },
+    LeSetRandomAddress {
+        address: [u8; 6],
+    },
+    Disconnect {
+        connection_handle: u16,
+        reason: ErrorCode,
+    },
+    LeLongTermKeyRequestReply {
+        handle: u16,
+        ltk: u128,
+    },
     ReadBrAddr,
-    SetEventMask { events: [u8; 8] },
+    ReadBufferSize,
+    LeReadBufferSize,
+    SetEventMask {
+        events: [u8; 8],
+    },
 }
 
 impl<'a> Command<'a> {
@@ -80,13 +100,8 @@ impl<'a> Command<'a> {
                 Data::new(&data)
             }
             Command::LeSetAdvertisingParameters => {
-                let mut data = [0u8; 4 + 0xf];
-                data[0] = 0x01;
-                CommandHeader::from_ogf_ocf(LE_OGF, SET_ADVERTISING_PARAMETERS_OCF, 0x0f)
-                    .write_into(&mut data[1..]);
-                data[4..]
-                    .copy_from_slice(&[0x00, 0x01, 0x00, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 7, 0]);
-                Data::new(&data)
+                Command::LeSetAdvertisingParametersCustom(&AdvertisingParameters::default())
+                    .encode()
             }
             Command::LeSetAdvertisingParametersCustom(params) => {
                 let mut data = [0u8; 4 + 0xf];
@@ -94,9 +109,9 @@ impl<'a> Command<'a> {
                 CommandHeader::from_ogf_ocf(LE_OGF, SET_ADVERTISING_PARAMETERS_OCF, 0x0f)
                     .write_into(&mut data[1..]);
 
-                let mut adv_params = Data::new(&[]);
-                adv_params.append(&params.advertising_interval_min.to_be_bytes());
-                adv_params.append(&params.advertising_interval_max.to_be_bytes());
+                let mut adv_params = Data::<0xf>::default();
+                adv_params.append(&params.advertising_interval_min.to_le_bytes());
+                adv_params.append(&params.advertising_interval_max.to_le_bytes());
                 adv_params.append(&[params.advertising_type as u8]);
                 adv_params.append(&[params.own_address_type as u8]);
                 adv_params.append(&[params.peer_address_type as u8]);
@@ -133,6 +148,14 @@ impl<'a> Command<'a> {
                 data[4] = if enable { 1 } else { 0 };
                 Data::new(&data)
             }
+            Command::LeSetRandomAddress { address } => {
+                let mut data = [0u8; 10];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(LE_OGF, SET_RANDOM_ADDRESS_OCF, 0x06)
+                    .write_into(&mut data[1..]);
+                data[4..].copy_from_slice(&address);
+                Data::new(&data)
+            }
             Command::Disconnect {
                 connection_handle,
                 reason,
@@ -142,7 +165,7 @@ impl<'a> Command<'a> {
                 CommandHeader::from_ogf_ocf(LINK_CONTROL_OGF, DISCONNECT_OCF, 0x03)
                     .write_into(&mut data[1..]);
                 data[4..][..2].copy_from_slice(&connection_handle.to_le_bytes());
-                data[6] = reason;
+                data[6] = reason.into();
                 Data::new(&data)
             }
             Command::LeLongTermKeyRequestReply { handle, ltk } => {
@@ -162,6 +185,20 @@ impl<'a> Command<'a> {
                     .write_into(&mut data[1..]);
                 Data::new(&data)
             }
+            Command::ReadBufferSize => {
+                let mut data = [0u8; 4];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(INFORMATIONAL_OGF, READ_BUFFER_SIZE_OCF, 0x00)
+                    .write_into(&mut data[1..]);
+                Data::new(&data)
+            }
+            Command::LeReadBufferSize => {
+                let mut data = [0u8; 4];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(LE_OGF, LE_READ_BUFFER_SIZE_OCF, 0x00)
+                    .write_into(&mut data[1..]);
+                Data::new(&data)
+            }
             Command::SetEventMask { events } => {
                 log::debug!("command set event mask");
                 let mut data = [0u8; 12];
@@ -174,3 +211,17 @@ impl<'a> Command<'a> {
         }
     }
 }
+
+/// Encodes any [`bt_hci`] command including the packet type (0x01)
+pub fn encode_hci_cmd<C: bt_hci::cmd::Cmd>(cmd: &C) -> Result<Data, Error> {
+    let len = bt_hci::WriteHci::size(cmd) + 1;
+    let mut data = Data::default();
+    if len > data.data.len() {
+        return Err(Error::Truncated);
+    }
+
+    data.data[0] = 0x01;
+    bt_hci::WriteHci::write_hci(cmd, &mut data.data[1..len]).map_err(|_| Error::Truncated)?;
+    data.set_len(len);
+    Ok(data)
+}
@@ -0,0 +1,86 @@
+use bt_hci::{
+    cmd::info::ReadLocalVersionInformationReturn,
+    param::{CmdMask, LeEventMask, LeFeatureMask, LmpFeatureMask},
+};
+
+use crate::{acl::AclBufferSize, AdvertisingParameters, Data};
+
+/// What the controller reported about itself during [`crate::Ble::init`]
+#[derive(Debug, Clone, Copy)]
+pub struct ControllerInfo {
+    pub version: ReadLocalVersionInformationReturn,
+    pub supported_commands: CmdMask,
+    pub features: LmpFeatureMask,
+    pub le_features: LeFeatureMask,
+    pub acl_buffer_size: AclBufferSize,
+    /// `None` if the controller doesn't support LE Read Supported States
+    pub le_states: Option<[u8; 8]>,
+}
+
+impl ControllerInfo {
+    pub fn supports_data_length_extension(&self) -> bool {
+        self.le_features.supports_le_data_packet_length_extension()
+    }
+
+    pub fn supports_2m_phy(&self) -> bool {
+        self.le_features.supports_le_2m_phy()
+    }
+
+    pub fn supports_coded_phy(&self) -> bool {
+        self.le_features.supports_le_coded_phy()
+    }
+
+    pub fn supports_extended_advertising(&self) -> bool {
+        self.le_features.supports_le_ext_adv()
+    }
+
+    pub fn supports_privacy(&self) -> bool {
+        self.le_features.supports_ll_privacy()
+    }
+}
+
+/// LE events the stack decodes, limited to what the controller's features can produce
+pub fn le_event_mask(le_features: &LeFeatureMask) -> LeEventMask {
+    let mut mask = LeEventMask::new()
+        .enable_le_conn_complete(true)
+        .enable_le_conn_update_complete(true)
+        .enable_le_read_remote_features_complete(true)
+        .enable_le_long_term_key_request(true);
+
+    if le_features.supports_conn_parameters_request_procedure() {
+        mask = mask.enable_le_remote_conn_parameter_request(true);
+    }
+    if le_features.supports_le_data_packet_length_extension() {
+        mask = mask.enable_le_data_length_change(true);
+    }
+    if le_features.supports_ll_privacy() || le_features.supports_le_ext_adv() {
+        mask = mask.enable_le_enhanced_conn_complete(true);
+    }
+    if le_features.supports_le_2m_phy() || le_features.supports_le_coded_phy() {
+        mask = mask.enable_le_phy_update_complete(true);
+    }
+    if le_features.supports_le_ext_adv() {
+        mask = mask.enable_le_adv_set_terminated(true);
+    }
+    if le_features.supports_channel_selection_algorithm_2() {
+        mask = mask.enable_le_channel_selection_algorithm(true);
+    }
+
+    mask
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum StoredAdvertisingParameters {
+    Default,
+    Custom(AdvertisingParameters),
+}
+
+/// The advertising setup last accepted by the controller, replayed after a reset
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct AdvertisingState {
+    pub random_address: Option<[u8; 6]>,
+    pub parameters: Option<StoredAdvertisingParameters>,
+    pub data: Option<Data>,
+    pub scan_response: Option<Data>,
+    pub enabled: bool,
+}
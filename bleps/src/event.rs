@@ -1,17 +1,30 @@
-use crate::{Addr, Data, Error, HciConnection};
+use crate::{h4::RawPacket, read_exact, Addr, Data, Error, HciConnection};
 
-#[derive(Debug)]
-pub struct Event {
-    code: u8,
-    data: Data,
-}
+/// Capacity for the parameters of an event, their length is a single byte
+pub const EVENT_DATA_LEN: usize = 255;
 
+/// An event borrowed from the buffer it was received into
 #[derive(Debug, Clone, Copy)]
+pub struct Event<'a> {
+    pub code: u8,
+    /// The event parameters
+    pub data: &'a [u8],
+}
+
+/// The most handles a Number Of Completed Packets event can carry in its 255 parameter bytes
+pub const MAX_COMPLETED_PACKETS_HANDLES: usize = 63;
+
+#[derive(Debug, Clone)]
 pub enum EventType {
     CommandComplete {
         num_packets: u8,
         opcode: u16,
-        data: Data,
+        data: Data<EVENT_DATA_LEN>,
+    },
+    CommandStatus {
+        status: u8,
+        num_packets: u8,
+        opcode: u16,
     },
     DisconnectComplete {
         handle: u16,
@@ -19,29 +32,179 @@ pub enum EventType {
         reason: ErrorCode,
     },
     NumberOfCompletedPackets {
-        number_of_connection_handles: u8,
-        connection_handles: u16, // should be list
-        completed_packets: u16,  // should be list
+        /// Pairs of connection handle and number of completed packets
+        completed_packets: heapless::Vec<(u16, u16), MAX_COMPLETED_PACKETS_HANDLES>,
     },
     ConnectionComplete {
         status: u8,
         handle: u16,
-        role: u8,
+        role: Role,
         peer_address: Addr,
         interval: u16,
         latency: u16,
         timeout: u16,
+        central_clock_accuracy: u8,
+    },
+    /// LE Enhanced Connection Complete, `advertising_handle` and `sync_handle` are only present
+    /// in version 2 of the event
+    EnhancedConnectionComplete {
+        status: u8,
+        handle: u16,
+        role: Role,
+        peer_address: Addr,
+        local_resolvable_private_address: [u8; 6],
+        peer_resolvable_private_address: [u8; 6],
+        interval: u16,
+        latency: u16,
+        timeout: u16,
+        central_clock_accuracy: u8,
+        advertising_handle: Option<u8>,
+        sync_handle: Option<u16>,
     },
     LongTermKeyRequest {
         handle: u16,
         random: u64,
         diversifier: u16,
     },
+    ConnectionUpdateComplete {
+        status: u8,
+        handle: u16,
+        interval: u16,
+        latency: u16,
+        timeout: u16,
+    },
+    ReadRemoteFeaturesComplete {
+        status: u8,
+        handle: u16,
+        features: u64,
+    },
+    RemoteConnectionParameterRequest {
+        handle: u16,
+        interval_min: u16,
+        interval_max: u16,
+        latency: u16,
+        timeout: u16,
+    },
+    DataLengthChange {
+        handle: u16,
+        max_tx_octets: u16,
+        max_tx_time: u16,
+        max_rx_octets: u16,
+        max_rx_time: u16,
+    },
+    PhyUpdateComplete {
+        status: u8,
+        handle: u16,
+        tx_phy: Phy,
+        rx_phy: Phy,
+    },
+    AdvertisingSetTerminated {
+        status: u8,
+        advertising_handle: u8,
+        handle: u16,
+        completed_events: u8,
+    },
+    ChannelSelectionAlgorithm {
+        handle: u16,
+        algorithm: u8,
+    },
+    /// Encryption Change, `key_size` is only present in version 2 of the event
+    EncryptionChange {
+        status: u8,
+        handle: u16,
+        enabled: u8,
+        key_size: Option<u8>,
+    },
+    EncryptionKeyRefreshComplete {
+        status: u8,
+        handle: u16,
+    },
+    /// The controller failed, it needs to be reset before it can be used again
+    HardwareError {
+        code: u8,
+    },
     Unknown,
 }
 
-#[derive(Debug, Clone, Copy)]
-pub enum ErrorCode {
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum Role {
+    Central = 0x00,
+    Peripheral = 0x01,
+    /// A reserved value, only reported by failed events
+    Other(u8),
+}
+
+impl Role {
+    pub fn from_u8(value: u8) -> Option<Role> {
+        match value {
+            0x00 => Some(Role::Central),
+            0x01 => Some(Role::Peripheral),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum Phy {
+    Le1M = 0x01,
+    Le2M = 0x02,
+    LeCoded = 0x03,
+    /// A reserved value, only reported by failed events
+    Other(u8),
+}
+
+impl Phy {
+    pub fn from_u8(value: u8) -> Option<Phy> {
+        match value {
+            0x01 => Some(Phy::Le1M),
+            0x02 => Some(Phy::Le2M),
+            0x03 => Some(Phy::LeCoded),
+            _ => None,
+        }
+    }
+}
+
+macro_rules! error_codes {
+    ($($name:ident = $value:literal,)*) => {
+        /// Controller error codes ([Vol 1] Part F, Section 1.3).
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+        pub enum ErrorCode {
+            $($name,)*
+            /// A code not listed in the spec, the raw value is kept.
+            Other(u8),
+        }
+
+        impl ErrorCode {
+            pub fn from_u8(value: u8) -> ErrorCode {
+                match value {
+                    $($value => ErrorCode::$name,)*
+                    _ => ErrorCode::Other(value),
+                }
+            }
+
+            pub fn to_u8(self) -> u8 {
+                match self {
+                    $(ErrorCode::$name => $value,)*
+                    ErrorCode::Other(value) => value,
+                }
+            }
+        }
+
+        impl core::fmt::Display for ErrorCode {
+            fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                match self {
+                    $(ErrorCode::$name => write!(f, "{} ({:#04x})", stringify!($name), $value),)*
+                    ErrorCode::Other(value) => write!(f, "unknown error ({:#04x})", value),
+                }
+            }
+        }
+    };
+}
+
+error_codes! {
     Okay = 0x00,
     UnknownHciCommand = 0x01,
     UnknownConnectionIdentifier = 0x02,
@@ -52,54 +215,124 @@ pub enum ErrorCode {
     MemoryCapacityExceeded = 0x07,
     ConnectionTimeout = 0x08,
     ConnectionLimitExceeded = 0x09,
+    SynchronousConnectionLimitExceeded = 0x0a,
     AclConnectionAlreadyExists = 0x0b,
     CommandDisallowed = 0x0c,
+    ConnectionRejectedLimitedResources = 0x0d,
+    ConnectionRejectedSecurityReasons = 0x0e,
+    ConnectionRejectedUnacceptableBdAddr = 0x0f,
+    ConnectionAcceptTimeoutExceeded = 0x10,
+    UnsupportedFeatureOrParameterValue = 0x11,
+    InvalidHciCommandParameters = 0x12,
     RemoteUserTerminatedConnection = 0x13,
-    // see Error Codes Description in spec
-    Unknown = 0xff,
+    RemoteDeviceTerminatedConnectionLowResources = 0x14,
+    RemoteDeviceTerminatedConnectionPowerOff = 0x15,
+    ConnectionTerminatedByLocalHost = 0x16,
+    RepeatedAttempts = 0x17,
+    PairingNotAllowed = 0x18,
+    UnknownLmpPdu = 0x19,
+    UnsupportedRemoteFeature = 0x1a,
+    ScoOffsetRejected = 0x1b,
+    ScoIntervalRejected = 0x1c,
+    ScoAirModeRejected = 0x1d,
+    InvalidLmpOrLlParameters = 0x1e,
+    UnspecifiedError = 0x1f,
+    UnsupportedLmpOrLlParameterValue = 0x20,
+    RoleChangeNotAllowed = 0x21,
+    LmpOrLlResponseTimeout = 0x22,
+    LmpOrLlErrorTransactionCollision = 0x23,
+    LmpPduNotAllowed = 0x24,
+    EncryptionModeNotAcceptable = 0x25,
+    LinkKeyCannotBeChanged = 0x26,
+    RequestedQosNotSupported = 0x27,
+    InstantPassed = 0x28,
+    PairingWithUnitKeyNotSupported = 0x29,
+    DifferentTransactionCollision = 0x2a,
+    QosUnacceptableParameter = 0x2c,
+    QosRejected = 0x2d,
+    ChannelClassificationNotSupported = 0x2e,
+    InsufficientSecurity = 0x2f,
+    ParameterOutOfMandatoryRange = 0x30,
+    RoleSwitchPending = 0x32,
+    ReservedSlotViolation = 0x34,
+    RoleSwitchFailed = 0x35,
+    ExtendedInquiryResponseTooLarge = 0x36,
+    SecureSimplePairingNotSupportedByHost = 0x37,
+    HostBusyPairing = 0x38,
+    ConnectionRejectedNoSuitableChannelFound = 0x39,
+    ControllerBusy = 0x3a,
+    UnacceptableConnectionParameters = 0x3b,
+    AdvertisingTimeout = 0x3c,
+    ConnectionTerminatedMicFailure = 0x3d,
+    ConnectionFailedToBeEstablished = 0x3e,
+    MacConnectionFailed = 0x3f,
+    CoarseClockAdjustmentRejected = 0x40,
+    Type0SubmapNotDefined = 0x41,
+    UnknownAdvertisingIdentifier = 0x42,
+    LimitReached = 0x43,
+    OperationCancelledByHost = 0x44,
+    PacketTooLong = 0x45,
+    TooLate = 0x46,
+    TooEarly = 0x47,
+    InsufficientChannels = 0x48,
 }
 
-impl ErrorCode {
-    pub fn from_u8(value: u8) -> ErrorCode {
-        match value {
-            0x00 => ErrorCode::Okay,
-            0x01 => ErrorCode::UnknownHciCommand,
-            0x02 => ErrorCode::UnknownConnectionIdentifier,
-            0x03 => ErrorCode::HardwareFailure,
-            0x04 => ErrorCode::PageTimeout,
-            0x05 => ErrorCode::AuthenticationFailure,
-            0x06 => ErrorCode::PinOrKeyMissing,
-            0x07 => ErrorCode::MemoryCapacityExceeded,
-            0x08 => ErrorCode::ConnectionTimeout,
-            0x09 => ErrorCode::ConnectionLimitExceeded,
-            0x0b => ErrorCode::AclConnectionAlreadyExists,
-            0x0c => ErrorCode::CommandDisallowed,
-            0x13 => ErrorCode::RemoteUserTerminatedConnection,
-
-            _ => ErrorCode::Unknown,
-        }
+impl From<u8> for ErrorCode {
+    fn from(value: u8) -> Self {
+        ErrorCode::from_u8(value)
+    }
+}
+
+impl From<ErrorCode> for u8 {
+    fn from(value: ErrorCode) -> Self {
+        value.to_u8()
     }
 }
 
 const EVENT_COMMAND_COMPLETE: u8 = 0x0e;
+const EVENT_COMMAND_STATUS: u8 = 0x0f;
 const EVENT_DISCONNECTION_COMPLETE: u8 = 0x05;
+const EVENT_ENCRYPTION_CHANGE_V1: u8 = 0x08;
+const EVENT_ENCRYPTION_KEY_REFRESH_COMPLETE: u8 = 0x30;
+const EVENT_ENCRYPTION_CHANGE_V2: u8 = 0x59;
+const EVENT_HARDWARE_ERROR: u8 = 0x10;
 const EVENT_NUMBER_OF_COMPLETED_PACKETS: u8 = 0x13;
 const EVENT_LE_META: u8 = 0x3e;
 const EVENT_LE_META_CONNECTION_COMPLETE: u8 = 0x01;
-// TODO ENHANCED_CONNECTION_COMPLETE
+const EVENT_LE_META_CONNECTION_UPDATE_COMPLETE: u8 = 0x03;
+const EVENT_LE_META_READ_REMOTE_FEATURES_COMPLETE: u8 = 0x04;
 const EVENT_LE_META_LONG_TERM_KEY_REQUEST: u8 = 0x05;
+const EVENT_LE_META_REMOTE_CONNECTION_PARAMETER_REQUEST: u8 = 0x06;
+const EVENT_LE_META_DATA_LENGTH_CHANGE: u8 = 0x07;
+const EVENT_LE_META_ENHANCED_CONNECTION_COMPLETE_V1: u8 = 0x0a;
+const EVENT_LE_META_PHY_UPDATE_COMPLETE: u8 = 0x0c;
+const EVENT_LE_META_ADVERTISING_SET_TERMINATED: u8 = 0x12;
+const EVENT_LE_META_CHANNEL_SELECTION_ALGORITHM: u8 = 0x14;
+const EVENT_LE_META_ENHANCED_CONNECTION_COMPLETE_V2: u8 = 0x29;
 
 impl EventType {
     pub fn check_command_completed(self) -> Result<Self, Error> {
         if let Self::CommandComplete {
             num_packets: _,
-            opcode: _,
+            opcode,
             data,
         } = self
         {
-            let status = data.as_slice()[0];
+            let status = *data.as_slice().first().ok_or(Error::Truncated)?;
             if status != 0 {
-                return Err(Error::Failed(status));
+                return Err(Error::Failed {
+                    opcode,
+                    status: ErrorCode::from_u8(status),
+                });
+            }
+        }
+
+        if let Self::CommandStatus { status, opcode, .. } = self {
+            if status != 0 {
+                return Err(Error::Failed {
+                    opcode,
+                    status: ErrorCode::from_u8(status),
+                });
             }
         }
 
@@ -107,23 +340,55 @@ impl EventType {
     }
 
     /// Reads and decodes an event and assumes the packet type (0x04) is already read.
-    pub fn read(connector: &dyn HciConnection) -> Self {
-        let event = Event::read(connector);
+    pub fn read(connector: &dyn HciConnection) -> Result<Self, Error> {
+        let mut header = [0u8; 2];
+        read_exact(connector, &mut header)?;
 
-        match event.code {
+        let mut data = [0u8; EVENT_DATA_LEN];
+        let data = &mut data[..header[1] as usize];
+        read_exact(connector, data)?;
+
+        Self::decode(Event {
+            code: header[0],
+            data,
+        })
+    }
+
+    /// Decodes an event collected by [`crate::h4::H4Reader`].
+    pub fn from_raw(packet: &RawPacket) -> Result<Self, Error> {
+        Self::decode(Event {
+            code: packet.header[0],
+            data: packet.data.as_slice(),
+        })
+    }
+
+    /// Decodes an event, only the return parameters of Command Complete are copied
+    pub fn decode(event: Event<'_>) -> Result<Self, Error> {
+        let res = match event.code {
             EVENT_COMMAND_COMPLETE => {
-                let data = event.data.as_slice();
+                let data = check_len(event.data, 3)?;
                 let num_packets = data[0];
                 let opcode = ((data[2] as u16) << 8) + data[1] as u16;
-                let data = event.data.subdata_from(3);
+                let data = Data::new(&event.data[3..]);
                 Self::CommandComplete {
                     num_packets,
                     opcode,
                     data,
                 }
             }
+            EVENT_COMMAND_STATUS => {
+                let data = check_len(event.data, 4)?;
+                let status = data[0];
+                let num_packets = data[1];
+                let opcode = ((data[3] as u16) << 8) + data[2] as u16;
+                Self::CommandStatus {
+                    status,
+                    num_packets,
+                    opcode,
+                }
+            }
             EVENT_DISCONNECTION_COMPLETE => {
-                let data = event.data.as_slice();
+                let data = check_len(event.data, 4)?;
                 let status = data[0];
                 let handle = ((data[2] as u16) << 8) + data[1] as u16;
                 let reason = data[3];
@@ -135,31 +400,60 @@ impl EventType {
                     reason,
                 }
             }
+            EVENT_ENCRYPTION_CHANGE_V1 | EVENT_ENCRYPTION_CHANGE_V2 => {
+                let v2 = event.code == EVENT_ENCRYPTION_CHANGE_V2;
+                let data = check_len(event.data, if v2 { 5 } else { 4 })?;
+                let status = data[0];
+                let handle = ((data[2] as u16) << 8) + data[1] as u16;
+                let enabled = data[3];
+                let key_size = if v2 { Some(data[4]) } else { None };
+                Self::EncryptionChange {
+                    status,
+                    handle,
+                    enabled,
+                    key_size,
+                }
+            }
+            EVENT_ENCRYPTION_KEY_REFRESH_COMPLETE => {
+                let data = check_len(event.data, 3)?;
+                let status = data[0];
+                let handle = ((data[2] as u16) << 8) + data[1] as u16;
+                Self::EncryptionKeyRefreshComplete { status, handle }
+            }
+            EVENT_HARDWARE_ERROR => {
+                let code = check_len(event.data, 1)?[0];
+                Self::HardwareError { code }
+            }
             EVENT_NUMBER_OF_COMPLETED_PACKETS => {
-                let data = event.data.as_slice();
-                let num_handles = data[0];
-                let connection_handle = ((data[2] as u16) << 8) + data[1] as u16;
-                let completed_packet = ((data[4] as u16) << 8) + data[3] as u16;
-                Self::NumberOfCompletedPackets {
-                    number_of_connection_handles: num_handles,
-                    connection_handles: connection_handle,
-                    completed_packets: completed_packet,
+                let num_handles = check_len(event.data, 1)?[0] as usize;
+                let data = check_len(&event.data[1..], num_handles * 4)?;
+
+                let mut completed_packets = heapless::Vec::new();
+                for pair in data[..num_handles * 4].chunks_exact(4) {
+                    let connection_handle = ((pair[1] as u16) << 8) + pair[0] as u16;
+                    let completed_packet = ((pair[3] as u16) << 8) + pair[2] as u16;
+                    // can't fail, a full event has room for at most this many handles
+                    completed_packets
+                        .push((connection_handle, completed_packet))
+                        .ok();
                 }
+                Self::NumberOfCompletedPackets { completed_packets }
             }
             EVENT_LE_META => {
-                let sub_event = event.data.as_slice()[0];
-                let data = &event.data.as_slice()[1..];
+                let sub_event = check_len(event.data, 1)?[0];
+                let data = &event.data[1..];
 
                 match sub_event {
                     EVENT_LE_META_CONNECTION_COMPLETE => {
+                        let data = check_len(data, 18)?;
                         let status = data[0];
                         let handle = ((data[2] as u16) << 8) + data[1] as u16;
-                        let role = data[3];
-                        let peer_address =
-                            Addr::from_le_bytes(data[4] != 0, data[5..][..6].try_into().unwrap());
-                        let interval = ((data[2] as u16) << 8) + data[1] as u16;
-                        let latency = ((data[2] as u16) << 8) + data[1] as u16;
-                        let timeout = ((data[2] as u16) << 8) + data[1] as u16;
+                        let role = decode_role(status, data[3])?;
+                        let peer_address = decode_peer_address(data[4], &data[5..][..6]);
+                        let interval = ((data[12] as u16) << 8) + data[11] as u16;
+                        let latency = ((data[14] as u16) << 8) + data[13] as u16;
+                        let timeout = ((data[16] as u16) << 8) + data[15] as u16;
+                        let central_clock_accuracy = data[17];
 
                         Self::ConnectionComplete {
                             status,
@@ -169,9 +463,49 @@ impl EventType {
                             interval,
                             latency,
                             timeout,
+                            central_clock_accuracy,
+                        }
+                    }
+                    EVENT_LE_META_ENHANCED_CONNECTION_COMPLETE_V1
+                    | EVENT_LE_META_ENHANCED_CONNECTION_COMPLETE_V2 => {
+                        let v2 = sub_event == EVENT_LE_META_ENHANCED_CONNECTION_COMPLETE_V2;
+                        let data = check_len(data, if v2 { 33 } else { 30 })?;
+                        let status = data[0];
+                        let handle = ((data[2] as u16) << 8) + data[1] as u16;
+                        let role = decode_role(status, data[3])?;
+                        let peer_address = decode_peer_address(data[4], &data[5..][..6]);
+                        let local_resolvable_private_address = data[11..][..6].try_into().unwrap();
+                        let peer_resolvable_private_address = data[17..][..6].try_into().unwrap();
+                        let interval = ((data[24] as u16) << 8) + data[23] as u16;
+                        let latency = ((data[26] as u16) << 8) + data[25] as u16;
+                        let timeout = ((data[28] as u16) << 8) + data[27] as u16;
+                        let central_clock_accuracy = data[29];
+                        let (advertising_handle, sync_handle) = if v2 {
+                            (
+                                Some(data[30]),
+                                Some(((data[32] as u16) << 8) + data[31] as u16),
+                            )
+                        } else {
+                            (None, None)
+                        };
+
+                        Self::EnhancedConnectionComplete {
+                            status,
+                            handle,
+                            role,
+                            peer_address,
+                            local_resolvable_private_address,
+                            peer_resolvable_private_address,
+                            interval,
+                            latency,
+                            timeout,
+                            central_clock_accuracy,
+                            advertising_handle,
+                            sync_handle,
                         }
                     }
                     EVENT_LE_META_LONG_TERM_KEY_REQUEST => {
+                        let data = check_len(data, 12)?;
                         let handle = ((data[1] as u16) << 8) + data[0] as u16;
                         let random = u64::from_be_bytes((&data[2..][..8]).try_into().unwrap());
                         let diversifier = ((data[11] as u16) << 8) + data[10] as u16;
@@ -181,106 +515,94 @@ impl EventType {
                             diversifier,
                         }
                     }
-                    _ => {
-                        log::warn!(
-                            "Ignoring unknown le-meta event {:02x} data = {:02x?}",
-                            sub_event,
-                            data
-                        );
-                        Self::Unknown
-                    }
-                }
-            }
-            _ => {
-                log::warn!(
-                    "Ignoring unknown event {:02x} data = {:02x?}",
-                    event.code,
-                    event.data.as_slice()
-                );
-                Self::Unknown
-            }
-        }
-    }
-
-    #[cfg(feature = "async")]
-    /// Reads and decodes an event and assumes the packet type (0x04) is already read.
-    pub async fn async_read<T>(connector: &mut T) -> Self
-    where
-        T: embedded_io_async::Read,
-    {
-        let event = Event::async_read(connector).await;
-
-        match event.code {
-            EVENT_COMMAND_COMPLETE => {
-                let data = event.data.as_slice();
-                let num_packets = data[0];
-                let opcode = ((data[2] as u16) << 8) + data[1] as u16;
-                let data = event.data.subdata_from(3);
-                Self::CommandComplete {
-                    num_packets,
-                    opcode,
-                    data,
-                }
-            }
-            EVENT_DISCONNECTION_COMPLETE => {
-                let data = event.data.as_slice();
-                let status = data[0];
-                let handle = ((data[2] as u16) << 8) + data[1] as u16;
-                let reason = data[3];
-                let status = ErrorCode::from_u8(status);
-                let reason = ErrorCode::from_u8(reason);
-                Self::DisconnectComplete {
-                    handle,
-                    status,
-                    reason,
-                }
-            }
-            EVENT_NUMBER_OF_COMPLETED_PACKETS => {
-                let data = event.data.as_slice();
-                let num_handles = data[0];
-                let connection_handle = ((data[2] as u16) << 8) + data[1] as u16;
-                let completed_packet = ((data[4] as u16) << 8) + data[3] as u16;
-                Self::NumberOfCompletedPackets {
-                    number_of_connection_handles: num_handles,
-                    connection_handles: connection_handle,
-                    completed_packets: completed_packet,
-                }
-            }
-            EVENT_LE_META => {
-                let sub_event = event.data.as_slice()[0];
-                let data = &event.data.as_slice()[1..];
-
-                match sub_event {
-                    EVENT_LE_META_CONNECTION_COMPLETE => {
+                    EVENT_LE_META_CONNECTION_UPDATE_COMPLETE => {
+                        let data = check_len(data, 9)?;
                         let status = data[0];
                         let handle = ((data[2] as u16) << 8) + data[1] as u16;
-                        let role = data[3];
-                        let peer_address =
-                            Addr::from_le_bytes(data[4] != 0, data[5..][..6].try_into().unwrap());
-                        let interval = ((data[2] as u16) << 8) + data[1] as u16;
-                        let latency = ((data[2] as u16) << 8) + data[1] as u16;
-                        let timeout = ((data[2] as u16) << 8) + data[1] as u16;
-
-                        Self::ConnectionComplete {
+                        let interval = ((data[4] as u16) << 8) + data[3] as u16;
+                        let latency = ((data[6] as u16) << 8) + data[5] as u16;
+                        let timeout = ((data[8] as u16) << 8) + data[7] as u16;
+                        Self::ConnectionUpdateComplete {
                             status,
                             handle,
-                            role,
-                            peer_address,
                             interval,
                             latency,
                             timeout,
                         }
                     }
-                    EVENT_LE_META_LONG_TERM_KEY_REQUEST => {
+                    EVENT_LE_META_READ_REMOTE_FEATURES_COMPLETE => {
+                        let data = check_len(data, 11)?;
+                        let status = data[0];
+                        let handle = ((data[2] as u16) << 8) + data[1] as u16;
+                        let features = u64::from_le_bytes(data[3..][..8].try_into().unwrap());
+                        Self::ReadRemoteFeaturesComplete {
+                            status,
+                            handle,
+                            features,
+                        }
+                    }
+                    EVENT_LE_META_REMOTE_CONNECTION_PARAMETER_REQUEST => {
+                        let data = check_len(data, 10)?;
                         let handle = ((data[1] as u16) << 8) + data[0] as u16;
-                        let random = u64::from_be_bytes((&data[2..][..8]).try_into().unwrap());
-                        let diversifier = ((data[11] as u16) << 8) + data[10] as u16;
-                        Self::LongTermKeyRequest {
+                        let interval_min = ((data[3] as u16) << 8) + data[2] as u16;
+                        let interval_max = ((data[5] as u16) << 8) + data[4] as u16;
+                        let latency = ((data[7] as u16) << 8) + data[6] as u16;
+                        let timeout = ((data[9] as u16) << 8) + data[8] as u16;
+                        Self::RemoteConnectionParameterRequest {
                             handle,
-                            random,
-                            diversifier,
+                            interval_min,
+                            interval_max,
+                            latency,
+                            timeout,
                         }
                     }
+                    EVENT_LE_META_DATA_LENGTH_CHANGE => {
+                        let data = check_len(data, 10)?;
+                        let handle = ((data[1] as u16) << 8) + data[0] as u16;
+                        let max_tx_octets = ((data[3] as u16) << 8) + data[2] as u16;
+                        let max_tx_time = ((data[5] as u16) << 8) + data[4] as u16;
+                        let max_rx_octets = ((data[7] as u16) << 8) + data[6] as u16;
+                        let max_rx_time = ((data[9] as u16) << 8) + data[8] as u16;
+                        Self::DataLengthChange {
+                            handle,
+                            max_tx_octets,
+                            max_tx_time,
+                            max_rx_octets,
+                            max_rx_time,
+                        }
+                    }
+                    EVENT_LE_META_PHY_UPDATE_COMPLETE => {
+                        let data = check_len(data, 5)?;
+                        let status = data[0];
+                        let handle = ((data[2] as u16) << 8) + data[1] as u16;
+                        let tx_phy = decode_phy(status, data[3])?;
+                        let rx_phy = decode_phy(status, data[4])?;
+                        Self::PhyUpdateComplete {
+                            status,
+                            handle,
+                            tx_phy,
+                            rx_phy,
+                        }
+                    }
+                    EVENT_LE_META_ADVERTISING_SET_TERMINATED => {
+                        let data = check_len(data, 5)?;
+                        let status = data[0];
+                        let advertising_handle = data[1];
+                        let handle = ((data[3] as u16) << 8) + data[2] as u16;
+                        let completed_events = data[4];
+                        Self::AdvertisingSetTerminated {
+                            status,
+                            advertising_handle,
+                            handle,
+                            completed_events,
+                        }
+                    }
+                    EVENT_LE_META_CHANNEL_SELECTION_ALGORITHM => {
+                        let data = check_len(data, 3)?;
+                        let handle = ((data[1] as u16) << 8) + data[0] as u16;
+                        let algorithm = data[2];
+                        Self::ChannelSelectionAlgorithm { handle, algorithm }
+                    }
                     _ => {
                         log::warn!(
                             "Ignoring unknown le-meta event {:02x} data = {:02x?}",
@@ -295,35 +617,53 @@ impl EventType {
                 log::warn!(
                     "Ignoring unknown event {:02x} data = {:02x?}",
                     event.code,
-                    event.data.as_slice()
+                    event.data
                 );
                 Self::Unknown
             }
-        }
+        };
+
+        Ok(res)
     }
 }
 
-impl Event {
-    fn read(connector: &dyn HciConnection) -> Self {
-        let code = connector.read().unwrap() as u8;
-        let len = connector.read().unwrap() as usize;
-        let data = Data::read(connector, len);
-        Self { code, data }
+/// The role of a failed event is whatever the controller left there, it isn't validated so the
+/// failure still gets reported.
+fn decode_role(status: u8, value: u8) -> Result<Role, Error> {
+    match Role::from_u8(value) {
+        Some(role) => Ok(role),
+        None if status != 0 => Ok(Role::Other(value)),
+        None => {
+            log::warn!("Invalid role {:02x}", value);
+            Err(Error::InvalidValue)
+        }
     }
+}
 
-    #[cfg(feature = "async")]
-    async fn async_read<T>(connector: &mut T) -> Self
-    where
-        T: embedded_io_async::Read,
-    {
-        let mut buffer = [0u8];
-        let _code_len = connector.read(&mut buffer).await.unwrap();
-        let code = buffer[0];
+/// Like [`decode_role`] the PHYs of a failed event aren't validated
+fn decode_phy(status: u8, value: u8) -> Result<Phy, Error> {
+    match Phy::from_u8(value) {
+        Some(phy) => Ok(phy),
+        None if status != 0 => Ok(Phy::Other(value)),
+        None => {
+            log::warn!("Invalid PHY {:02x}", value);
+            Err(Error::InvalidValue)
+        }
+    }
+}
 
-        let _len_len = connector.read(&mut buffer).await.unwrap();
-        let len = buffer[0] as usize;
+/// Peer address types 0x02 and 0x03 are resolved identity addresses, the lowest bit tells
+/// whether it's a random address.
+fn decode_peer_address(address_type: u8, address: &[u8]) -> Addr {
+    Addr::from_le_bytes(address_type & 0x01 != 0, address.try_into().unwrap())
+}
 
-        let data = Data::async_read(connector, len).await;
-        Self { code, data }
+/// Makes sure the event parameters contain at least `len` bytes.
+fn check_len(data: &[u8], len: usize) -> Result<&[u8], Error> {
+    if data.len() < len {
+        log::warn!("Event too short, expected {} bytes, got {:02x?}", len, data);
+        return Err(Error::Truncated);
     }
+
+    Ok(data)
 }
@@ -0,0 +1,225 @@
+use crate::{Data, Error};
+
+/// The longest header following a packet indicator, used by ACL and ISO data
+const MAX_HEADER_LEN: usize = 4;
+
+/// Packet indicators of the UART transport layer ([Vol 4] Part A, Section 2).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PacketType {
+    Command = 0x01,
+    AclData = 0x02,
+    SyncData = 0x03,
+    Event = 0x04,
+    IsoData = 0x05,
+}
+
+impl PacketType {
+    /// Decodes a packet indicator sent by the controller.
+    ///
+    /// Command packets only travel from host to controller, so `0x01` is not accepted here.
+    pub fn from_u8(value: u8) -> Option<PacketType> {
+        match value {
+            0x02 => Some(PacketType::AclData),
+            0x03 => Some(PacketType::SyncData),
+            0x04 => Some(PacketType::Event),
+            0x05 => Some(PacketType::IsoData),
+            _ => None,
+        }
+    }
+
+    /// Length of the header following the packet indicator.
+    pub fn header_len(self) -> usize {
+        match self {
+            PacketType::Command | PacketType::SyncData => 3,
+            PacketType::AclData | PacketType::IsoData => 4,
+            PacketType::Event => 2,
+        }
+    }
+
+    /// Length of the payload announced by a complete `header`.
+    pub fn payload_len(self, header: &[u8]) -> usize {
+        match self {
+            PacketType::Command | PacketType::SyncData => header[2] as usize,
+            PacketType::AclData => u16::from_le_bytes([header[2], header[3]]) as usize,
+            PacketType::IsoData => (u16::from_le_bytes([header[2], header[3]]) & 0x3fff) as usize,
+            PacketType::Event => header[1] as usize,
+        }
+    }
+}
+
+/// Keeps track of the synchronisation with the H4 byte stream.
+///
+/// The first unknown packet indicator is reported as [`Error::UnknownPacketType`]. After that
+/// bytes are silently discarded until a valid packet indicator shows up again.
+#[derive(Debug, Default)]
+pub struct H4Framer {
+    dropped: usize,
+    resyncing: bool,
+}
+
+impl H4Framer {
+    pub fn new() -> H4Framer {
+        H4Framer::default()
+    }
+
+    /// Checks the byte expected to be a packet indicator.
+    ///
+    /// Returns `Ok(None)` for bytes dropped while resynchronising.
+    pub fn accept(&mut self, indicator: u8) -> Result<Option<PacketType>, Error> {
+        match PacketType::from_u8(indicator) {
+            Some(packet_type) => {
+                if self.resyncing {
+                    log::info!("Resynchronised after {} dropped bytes", self.dropped);
+                    self.resyncing = false;
+                }
+                Ok(Some(packet_type))
+            }
+            None => {
+                self.dropped += 1;
+                if self.resyncing {
+                    Ok(None)
+                } else {
+                    self.resyncing = true;
+                    Err(Error::UnknownPacketType(indicator))
+                }
+            }
+        }
+    }
+
+    /// Number of bytes discarded so far while looking for a packet indicator.
+    pub fn dropped_bytes(&self) -> usize {
+        self.dropped
+    }
+}
+
+/// A packet collected by [`H4Reader`], not decoded yet.
+#[derive(Debug, Clone, Copy)]
+pub struct RawPacket {
+    pub packet_type: PacketType,
+    /// The header following the packet indicator, padded with zeros
+    pub header: [u8; MAX_HEADER_LEN],
+    /// The payload, cut to the capacity of [`Data`]
+    pub data: Data,
+}
+
+/// Collects packets from the H4 byte stream, whatever pieces the transport hands them out in.
+///
+/// All progress is kept in the reader. A read that is abandoned half-way through a packet, e.g.
+/// because the future doing it was dropped, is picked up again by the next one without losing
+/// the framing.
+#[derive(Debug)]
+pub struct H4Reader {
+    framer: H4Framer,
+    packet_type: Option<PacketType>,
+    header: [u8; MAX_HEADER_LEN],
+    header_received: usize,
+    data: Data,
+    /// Payload bytes still to come, including those which don't fit into `data`
+    remaining: usize,
+    discard: [u8; 32],
+}
+
+impl Default for H4Reader {
+    fn default() -> Self {
+        H4Reader::new()
+    }
+}
+
+impl H4Reader {
+    pub fn new() -> H4Reader {
+        H4Reader {
+            framer: H4Framer::new(),
+            packet_type: None,
+            header: [0u8; MAX_HEADER_LEN],
+            header_received: 0,
+            data: Data::new(&[]),
+            remaining: 0,
+            discard: [0u8; 32],
+        }
+    }
+
+    /// Number of bytes discarded so far while looking for a packet indicator.
+    pub fn dropped_bytes(&self) -> usize {
+        self.framer.dropped_bytes()
+    }
+
+    /// Where the next bytes from the transport go, never empty.
+    ///
+    /// Read as many bytes into it as are available, then pass their number to
+    /// [`H4Reader::advance`].
+    pub fn buffer(&mut self) -> &mut [u8] {
+        match self.packet_type {
+            None => &mut self.header[..1],
+            Some(packet_type) if self.header_received < packet_type.header_len() => {
+                &mut self.header[self.header_received..packet_type.header_len()]
+            }
+            Some(_) => {
+                let kept = self.data.len;
+                if kept < self.data.data.len() {
+                    let end = self.data.data.len().min(kept + self.remaining);
+                    &mut self.data.data[kept..end]
+                } else {
+                    let len = self.discard.len().min(self.remaining);
+                    &mut self.discard[..len]
+                }
+            }
+        }
+    }
+
+    /// Accounts for `len` bytes read into [`H4Reader::buffer`].
+    ///
+    /// Returns the packet once it's complete.
+    pub fn advance(&mut self, len: usize) -> Result<Option<RawPacket>, Error> {
+        let packet_type = match self.packet_type {
+            None => {
+                if len > 0 {
+                    self.packet_type = self.framer.accept(self.header[0])?;
+                    self.header = [0u8; MAX_HEADER_LEN];
+                    self.header_received = 0;
+                }
+                // every packet has a header, so it can't be complete yet
+                return Ok(None);
+            }
+            Some(packet_type) => packet_type,
+        };
+
+        if self.header_received < packet_type.header_len() {
+            self.header_received += len;
+            if self.header_received == packet_type.header_len() {
+                self.data = Data::new(&[]);
+                self.remaining = packet_type.payload_len(&self.header);
+            }
+        } else {
+            if self.data.len < self.data.data.len() {
+                self.data.len += len;
+            }
+            self.remaining -= len;
+        }
+
+        if self.header_received < packet_type.header_len() || self.remaining > 0 {
+            return Ok(None);
+        }
+
+        let dropped = packet_type.payload_len(&self.header) - self.data.len;
+        if dropped > 0 {
+            log::warn!("Dropped {} bytes of an oversized packet", dropped);
+        }
+
+        self.packet_type = None;
+        Ok(Some(RawPacket {
+            packet_type,
+            header: self.header,
+            data: self.data,
+        }))
+    }
+
+    /// Whether a packet was started but isn't complete yet.
+    pub fn in_packet(&self) -> bool {
+        self.packet_type.is_some()
+    }
+
+    /// Forgets the packet read so far, the next byte is expected to be a packet indicator.
+    pub fn discard_packet(&mut self) {
+        self.packet_type = None;
+    }
+}
@@ -0,0 +1,475 @@
+use core::cell::Cell;
+
+use bt_hci::cmd::{AsyncCmd, SyncCmd};
+use embassy_sync::{
+    blocking_mutex::{self, raw::RawMutex},
+    channel::{Channel, TrySendError},
+    mutex::Mutex,
+};
+use futures::future::{select, Either};
+use futures::pin_mut;
+use rand_core::{CryptoRng, RngCore};
+
+use crate::{
+    async_attribute_server::AttributeServer,
+    asynch::Ble,
+    att::ATT_PDU_LEN,
+    attribute::Attribute,
+    attribute_server::{AttributeServerError, WorkResult, BASE_MTU},
+    clock::AsyncClock,
+    command::encode_hci_cmd,
+    decode_return_params,
+    event::EventType,
+    l2cap::{L2capPacket, L2CAP_PDU_LEN},
+    Data, Error, PollResult, PENDING_POLL_RESULTS,
+};
+
+/// How many received packets wait for [`Control::receive`]
+///
+/// When the queue is full, connection events wait for room while other packets are dropped, see
+/// [`Control::dropped_packets`].
+pub const RECEIVED_QUEUE_LEN: usize = 8;
+
+enum Request {
+    /// An encoded command, answered by its Command Complete or Command Status event
+    Command { bytes: Data, opcode: u16 },
+    Acl {
+        handle: u16,
+        pdu: Data<L2CAP_PDU_LEN>,
+    },
+    Notify {
+        handle: u16,
+        attribute_handle: u16,
+        value: Data<ATT_PDU_LEN>,
+    },
+}
+
+/// The outcome of a successful [`Request`]
+// boxing the event would need an allocator
+#[allow(clippy::large_enum_variant)]
+enum Response {
+    /// The Command Complete or Command Status event answering a [`Request::Command`]
+    Command(EventType),
+    /// The PDU of a [`Request::Acl`] or [`Request::Notify`] was handed to the controller
+    Sent,
+}
+
+/// What [`Control::receive`] hands out
+// boxing the packet would need an allocator
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum HostEvent {
+    /// A packet received from the controller
+    Packet(PollResult),
+    /// The runner reset the failed controller, every connection and its handle is gone
+    ControllerReset,
+}
+
+/// The channels connecting a [`Runner`] with its [`Control`] handles
+///
+/// Usually placed in a `static` so the handles can be passed to other tasks.
+pub struct HostResources<M: RawMutex> {
+    requests: Channel<M, (u32, Request), 1>,
+    responses: Channel<M, (u32, Result<Response, Error>), 1>,
+    received: Channel<M, HostEvent, RECEIVED_QUEUE_LEN>,
+    /// Number of packets dropped because the handles didn't receive them in time
+    dropped: blocking_mutex::Mutex<M, Cell<u32>>,
+    /// Held while a request is in flight, counts the requests to match their responses
+    request_id: Mutex<M, u32>,
+}
+
+impl<M: RawMutex> HostResources<M> {
+    pub const fn new() -> HostResources<M> {
+        HostResources {
+            requests: Channel::new(),
+            responses: Channel::new(),
+            received: Channel::new(),
+            dropped: blocking_mutex::Mutex::new(Cell::new(0)),
+            request_id: Mutex::new(0),
+        }
+    }
+}
+
+impl<M: RawMutex> Default for HostResources<M> {
+    fn default() -> Self {
+        HostResources::new()
+    }
+}
+
+/// Owns the controller: reads everything it sends and executes the requests of the [`Control`]
+/// handles
+pub struct Runner<'d, M: RawMutex, T, K = fn() -> u64, const P: usize = PENDING_POLL_RESULTS>
+where
+    T: embedded_io_async::Read + embedded_io_async::Write,
+{
+    ble: Ble<T, K, P>,
+    resources: &'d HostResources<M>,
+}
+
+impl<'d, M, T, K, const P: usize> Runner<'d, M, T, K, P>
+where
+    M: RawMutex,
+    T: embedded_io_async::Read + embedded_io_async::Write,
+    K: AsyncClock,
+{
+    pub fn new(ble: Ble<T, K, P>, resources: &'d HostResources<M>) -> (Self, Control<'d, M>) {
+        (Runner { ble, resources }, Control { resources })
+    }
+
+    /// The controller, e.g. to call [`Ble::init`] before [`Runner::run`]
+    pub fn ble(&mut self) -> &mut Ble<T, K, P> {
+        &mut self.ble
+    }
+
+    /// Serves the controller until the transport fails
+    ///
+    /// A controller which reported a Hardware Error or stopped answering is reset, the handles
+    /// receive [`HostEvent::ControllerReset`] then. Without an attribute server nobody can enable
+    /// notifications, so [`Control::notify`] fails with [`Error::NotificationsDisabled`].
+    pub async fn run(&mut self) -> Result<(), Error> {
+        serve(&mut self.ble, self.resources).await
+    }
+
+    /// Serves the controller and a GATT server on `attributes` until the transport fails
+    ///
+    /// ATT and Security Manager PDUs are handled by the attribute server, everything else reaches
+    /// the handles as with [`Runner::run`].
+    ///
+    /// When _NOT_ using the `crypto` feature you can pass a mutual reference to `bleps::no_rng::NoRng`
+    pub async fn run_with_attribute_server<'a, R>(
+        &'a mut self,
+        attributes: &'a mut [Attribute<'a>],
+        rng: &'a mut R,
+    ) -> Result<(), Error>
+    where
+        R: CryptoRng + RngCore,
+    {
+        let mut server = AttributeServer::new(&mut self.ble, attributes, rng);
+        serve(&mut server, self.resources).await
+    }
+}
+
+/// What a [`Runner`] serves: the bare controller or an attribute server on top of it
+trait Host<T, K, const P: usize>
+where
+    T: embedded_io_async::Read + embedded_io_async::Write,
+    K: AsyncClock,
+{
+    fn ble(&mut self) -> &mut Ble<T, K, P>;
+
+    /// Handles what is meant for the host itself, returns what is meant for the handles
+    async fn handle(&mut self, packet: PollResult) -> Result<Option<PollResult>, Error>;
+
+    /// Resets the failed controller
+    async fn recover(&mut self) -> Result<(), Error>;
+
+    async fn notify(
+        &mut self,
+        handle: u16,
+        attribute_handle: u16,
+        value: &[u8],
+    ) -> Result<(), Error>;
+}
+
+impl<T, K, const P: usize> Host<T, K, P> for Ble<T, K, P>
+where
+    T: embedded_io_async::Read + embedded_io_async::Write,
+    K: AsyncClock,
+{
+    fn ble(&mut self) -> &mut Ble<T, K, P> {
+        self
+    }
+
+    async fn handle(&mut self, packet: PollResult) -> Result<Option<PollResult>, Error> {
+        Ok(Some(packet))
+    }
+
+    async fn recover(&mut self) -> Result<(), Error> {
+        self.reset_controller().await
+    }
+
+    async fn notify(&mut self, _: u16, _: u16, _: &[u8]) -> Result<(), Error> {
+        Err(Error::NotificationsDisabled)
+    }
+}
+
+impl<'a, T, R, K, const P: usize> Host<T, K, P> for AttributeServer<'a, T, R, K, P>
+where
+    T: embedded_io_async::Read + embedded_io_async::Write,
+    R: CryptoRng + RngCore,
+    K: AsyncClock,
+{
+    fn ble(&mut self) -> &mut Ble<T, K, P> {
+        self.ble
+    }
+
+    async fn handle(&mut self, packet: PollResult) -> Result<Option<PollResult>, Error> {
+        match packet {
+            PollResult::AsyncData(ref data) if matches!(L2capPacket::decode(data), Ok((_, l2cap)) if l2cap.channel == 4 || l2cap.channel == 6) =>
+            {
+                served(self.handle_packet(Some(packet)).await)?;
+                Ok(None)
+            }
+            // the runner resets the controller itself
+            PollResult::Event(EventType::HardwareError { .. }) => Ok(Some(packet)),
+            PollResult::Event(ref event) => {
+                // the server tracks the connection, the handles get to see the event too
+                served(
+                    self.handle_packet(Some(PollResult::Event(event.clone())))
+                        .await,
+                )?;
+                Ok(Some(packet))
+            }
+            packet => Ok(Some(packet)),
+        }
+    }
+
+    async fn recover(&mut self) -> Result<(), Error> {
+        self.ble.reset_controller().await?;
+        self.mtu = BASE_MTU;
+        Ok(())
+    }
+
+    async fn notify(
+        &mut self,
+        handle: u16,
+        attribute_handle: u16,
+        value: &[u8],
+    ) -> Result<(), Error> {
+        if !self.notifications_enabled(attribute_handle) {
+            return Err(Error::NotificationsDisabled);
+        }
+        if value.len() > self.mtu as usize - 3 {
+            return Err(Error::Truncated);
+        }
+
+        let mut data = Data::new_att_value_ntf(attribute_handle);
+        data.try_append(value)?;
+        self.ble.write_acl(handle, L2capPacket::encode(data)).await
+    }
+}
+
+/// Only a failing transport stops the runner, whatever a peer sent is just logged
+fn served(res: Result<WorkResult, AttributeServerError>) -> Result<(), Error> {
+    match res {
+        Ok(_) => Ok(()),
+        Err(AttributeServerError::HciError(err @ Error::Io(_))) => Err(err),
+        Err(err) => {
+            log::warn!("Error serving the connection: {:?}", err);
+            Ok(())
+        }
+    }
+}
+
+async fn serve<M, T, K, H, const P: usize>(
+    host: &mut H,
+    resources: &HostResources<M>,
+) -> Result<(), Error>
+where
+    M: RawMutex,
+    T: embedded_io_async::Read + embedded_io_async::Write,
+    K: AsyncClock,
+    H: Host<T, K, P>,
+{
+    loop {
+        if host.ble().needs_reset() {
+            host.recover().await?;
+            // the handles must learn about this, so wait for room instead of dropping it
+            resources.received.send(HostEvent::ControllerReset).await;
+        }
+
+        // polling and receiving are both cancel safe, the loser of the race loses nothing
+        let next = {
+            let poll = host.ble().poll();
+            let request = resources.requests.receive();
+            pin_mut!(poll);
+            pin_mut!(request);
+
+            match select(poll, request).await {
+                Either::Left((polled, _)) => Either::Left(polled),
+                Either::Right((request, _)) => Either::Right(request),
+            }
+        };
+
+        match next {
+            Either::Left(Ok(Some(res))) => dispatch(host, resources, res).await?,
+            Either::Left(Ok(None)) => (),
+            Either::Left(Err(err @ (Error::UnknownPacketType(_) | Error::Truncated))) => {
+                log::warn!("Error polling HCI: {:?}", err);
+            }
+            Either::Left(Err(err)) => return Err(err),
+            Either::Right((id, request)) => {
+                let response = execute(host, request).await;
+                resources.responses.send((id, response)).await;
+            }
+        }
+
+        // everything received while handling the packet or executing the request was kept
+        while let Some(res) = host.ble().pending.pop_front() {
+            dispatch(host, resources, res).await?;
+        }
+    }
+}
+
+async fn dispatch<M, T, K, H, const P: usize>(
+    host: &mut H,
+    resources: &HostResources<M>,
+    res: PollResult,
+) -> Result<(), Error>
+where
+    M: RawMutex,
+    T: embedded_io_async::Read + embedded_io_async::Write,
+    K: AsyncClock,
+    H: Host<T, K, P>,
+{
+    if let Some(res) = host.handle(res).await? {
+        deliver(resources, res).await;
+    }
+    Ok(())
+}
+
+async fn deliver<M: RawMutex>(resources: &HostResources<M>, res: PollResult) {
+    let is_connection_event = matches!(
+        res,
+        PollResult::Event(
+            EventType::ConnectionComplete { .. }
+                | EventType::EnhancedConnectionComplete { .. }
+                | EventType::DisconnectComplete { .. }
+        )
+    );
+
+    if is_connection_event {
+        // the handles track their connections by these, so wait for room instead of dropping them
+        resources.received.send(HostEvent::Packet(res)).await;
+    } else if let Err(TrySendError::Full(event)) =
+        resources.received.try_send(HostEvent::Packet(res))
+    {
+        let dropped = resources.dropped.lock(|dropped| {
+            dropped.set(dropped.get().wrapping_add(1));
+            dropped.get()
+        });
+        log::warn!("Nobody receives, dropped {} packets: {:?}", dropped, event);
+    }
+}
+
+async fn execute<T, K, H, const P: usize>(host: &mut H, request: Request) -> Result<Response, Error>
+where
+    T: embedded_io_async::Read + embedded_io_async::Write,
+    K: AsyncClock,
+    H: Host<T, K, P>,
+{
+    match request {
+        Request::Command { bytes, opcode } => {
+            let ble = host.ble();
+            ble.write_command(bytes.as_slice()).await?;
+            let event = ble.wait_for_command_event(opcode).await?;
+            Ok(Response::Command(event))
+        }
+        Request::Acl { handle, pdu } => {
+            host.ble().write_acl(handle, pdu).await?;
+            Ok(Response::Sent)
+        }
+        Request::Notify {
+            handle,
+            attribute_handle,
+            value,
+        } => {
+            host.notify(handle, attribute_handle, value.as_slice())
+                .await?;
+            Ok(Response::Sent)
+        }
+    }
+}
+
+/// A cheap handle to the controller served by a [`Runner`], it can be copied into any task
+#[derive(Clone, Copy)]
+pub struct Control<'d, M: RawMutex> {
+    resources: &'d HostResources<M>,
+}
+
+impl<'d, M: RawMutex> Control<'d, M> {
+    /// Executes a [`bt_hci`] command answered by Command Complete and returns its return parameters
+    pub async fn exec<C: SyncCmd>(&self, cmd: &C) -> Result<C::Return, Error> {
+        let event = self.command(cmd).await?.check_command_completed()?;
+        decode_return_params::<C>(event)
+    }
+
+    /// Executes a [`bt_hci`] command answered by Command Status
+    pub async fn exec_async_cmd<C: AsyncCmd>(&self, cmd: &C) -> Result<(), Error> {
+        self.command(cmd).await?.check_command_completed()?;
+        Ok(())
+    }
+
+    /// Sends an L2CAP PDU on the connection `handle`
+    pub async fn write_acl(&self, handle: u16, pdu: Data<L2CAP_PDU_LEN>) -> Result<(), Error> {
+        self.sent(Request::Acl { handle, pdu }).await
+    }
+
+    /// Notifies the client on the connection `handle` about the value of `attribute_handle`
+    ///
+    /// Fails with [`Error::NotificationsDisabled`] unless the client enabled notifications in the
+    /// attribute server of [`Runner::run_with_attribute_server`], and with [`Error::Truncated`]
+    /// if the value doesn't fit into the negotiated MTU minus 3 bytes.
+    pub async fn notify(
+        &self,
+        handle: u16,
+        attribute_handle: u16,
+        value: &[u8],
+    ) -> Result<(), Error> {
+        let mut data = Data::default();
+        data.try_append(value)?;
+        self.sent(Request::Notify {
+            handle,
+            attribute_handle,
+            value: data,
+        })
+        .await
+    }
+
+    /// Waits for the next packet received from the controller or the next controller reset
+    ///
+    /// Every event is received once, by whichever handle asked first.
+    pub async fn receive(&self) -> HostEvent {
+        self.resources.received.receive().await
+    }
+
+    /// Number of received packets dropped so far because no handle received them in time
+    ///
+    /// Connection events and controller resets are never dropped.
+    pub fn dropped_packets(&self) -> u32 {
+        self.resources.dropped.lock(|dropped| dropped.get())
+    }
+
+    async fn command<C: bt_hci::cmd::Cmd>(&self, cmd: &C) -> Result<EventType, Error> {
+        let bytes = encode_hci_cmd(cmd)?;
+        let opcode = C::OPCODE.to_raw();
+        match self.request(Request::Command { bytes, opcode }).await? {
+            Response::Command(event) => Ok(event),
+            Response::Sent => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    async fn sent(&self, request: Request) -> Result<(), Error> {
+        match self.request(request).await? {
+            Response::Sent => Ok(()),
+            Response::Command(_) => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Hands `request` to the runner and waits for its outcome
+    ///
+    /// A request dropped before the runner picked it up is still executed.
+    async fn request(&self, request: Request) -> Result<Response, Error> {
+        let mut request_id = self.resources.request_id.lock().await;
+        *request_id = request_id.wrapping_add(1);
+        self.resources.requests.send((*request_id, request)).await;
+
+        loop {
+            // responses to requests of dropped futures are left over sometimes
+            let (id, response) = self.resources.responses.receive().await;
+            if id == *request_id {
+                return response;
+            }
+        }
+    }
+}
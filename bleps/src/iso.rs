@@ -0,0 +1,63 @@
+use crate::{h4::RawPacket, read_exact, Data, Error, HciConnection};
+
+/// ISO data packet sent by the controller ([Vol 4] Part E, Section 5.4.5).
+#[derive(Debug, Clone, Copy)]
+pub struct IsoPacket {
+    pub handle: u16,
+    pub boundary_flag: IsoBoundaryFlag,
+    pub timestamp_present: bool,
+    /// The raw ISO_Data_Load, cut to the capacity of [`Data`]
+    pub data: Data,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum IsoBoundaryFlag {
+    FirstFragment,
+    ContinuationFragment,
+    Complete,
+    LastFragment,
+}
+
+impl IsoPacket {
+    pub fn read(connector: &dyn HciConnection) -> Result<Self, Error> {
+        let mut header = [0u8; 4];
+        read_exact(connector, &mut header)?;
+
+        let len = u16::from_le_bytes([header[2], header[3]]);
+        let data = Data::read_truncating(connector, (len & 0x3fff) as usize)?;
+        Ok(Self::from_parts([header[0], header[1]], data))
+    }
+
+    /// Decodes a packet collected by [`crate::h4::H4Reader`].
+    pub fn from_raw(packet: &RawPacket) -> Self {
+        Self::from_parts([packet.header[0], packet.header[1]], packet.data)
+    }
+
+    fn from_parts(raw_handle: [u8; 2], data: Data) -> Self {
+        let (pb, ts, handle) = Self::decode_raw_handle(raw_handle);
+
+        Self {
+            handle,
+            boundary_flag: pb,
+            timestamp_present: ts,
+            data,
+        }
+    }
+
+    fn decode_raw_handle(raw_handle_buffer: [u8; 2]) -> (IsoBoundaryFlag, bool, u16) {
+        let raw_handle = u16::from_le_bytes(raw_handle_buffer);
+
+        let pb = match (raw_handle & 0b0011000000000000) >> 12 {
+            0b00 => IsoBoundaryFlag::FirstFragment,
+            0b01 => IsoBoundaryFlag::ContinuationFragment,
+            0b10 => IsoBoundaryFlag::Complete,
+            _ => IsoBoundaryFlag::LastFragment,
+        };
+
+        let ts = raw_handle & 0b0100000000000000 != 0;
+
+        let handle = raw_handle & 0b111111111111;
+
+        (pb, ts, handle)
+    }
+}
@@ -1,24 +1,68 @@
-use crate::{acl::AclPacket, Data};
+use crate::{
+    acl::{AclPacket, BoundaryFlag},
+    att::ATT_PDU_LEN,
+    Data,
+};
 
+/// Capacity of an SMP PDU, the largest being the LE Secure Connections public key
+pub const SM_PDU_LEN: usize = 65;
+
+/// Capacity of an L2CAP PDU sent or received by the host: the basic header and an ATT or SMP PDU
+pub const L2CAP_PDU_LEN: usize = 4 + if ATT_PDU_LEN > SM_PDU_LEN {
+    ATT_PDU_LEN
+} else {
+    SM_PDU_LEN
+};
+
+/// An L2CAP PDU borrowed from the ACL packet it was received in
 #[derive(Debug)]
-pub struct L2capPacket {
+pub struct L2capPacket<'a> {
     pub length: u16,
     pub channel: u16,
-    pub payload: Data,
+    pub payload: &'a [u8],
 }
 
 #[derive(Debug)]
 pub enum L2capDecodeError {
-    Other,
+    /// The data received on the connection `handle` is shorter than the basic L2CAP header
+    Truncated { handle: u16 },
+}
+
+impl core::fmt::Display for L2capDecodeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            L2capDecodeError::Truncated { handle } => {
+                write!(f, "L2CAP PDU on connection {:#06x} is truncated", handle)
+            }
+        }
+    }
 }
 
-impl L2capPacket {
-    pub fn decode(packet: AclPacket) -> Result<(u16, Self), L2capDecodeError> {
+#[cfg(feature = "defmt")]
+impl defmt::Format for L2capDecodeError {
+    fn format(&self, fmt: defmt::Formatter) {
+        match self {
+            L2capDecodeError::Truncated { handle } => {
+                defmt::write!(fmt, "Truncated {{ handle: {=u16:#06x} }}", handle)
+            }
+        }
+    }
+}
+
+impl<'a> L2capPacket<'a> {
+    pub fn decode<const N: usize>(
+        packet: &'a AclPacket<N>,
+    ) -> Result<(u16, Self), L2capDecodeError> {
         let data = packet.data.as_slice();
         log::debug!("L2CAP {:02x?}", data);
+        if data.len() < 4 {
+            return Err(L2capDecodeError::Truncated {
+                handle: packet.handle,
+            });
+        }
         let length = (data[0] as u16) + ((data[1] as u16) << 8);
         let channel = (data[2] as u16) + ((data[3] as u16) << 8);
-        let payload = Data::new(&data[4..]);
+        let payload = &data[4..];
 
         Ok((
             packet.handle,
@@ -30,7 +74,7 @@ impl L2capPacket {
         ))
     }
 
-    pub fn encode(att_data: Data) -> Data {
+    pub fn encode(att_data: Data<ATT_PDU_LEN>) -> Data<L2CAP_PDU_LEN> {
         let mut data = Data::new(&[
             0, 0, // len set later
             0x04, 0x00, // channel
@@ -44,12 +88,12 @@ impl L2capPacket {
         data
     }
 
-    pub fn encode_sm(att_data: Data) -> Data {
+    pub fn encode_sm(sm_data: Data<SM_PDU_LEN>) -> Data<L2CAP_PDU_LEN> {
         let mut data = Data::new(&[
             0, 0, // len set later
             0x06, 0x00, // channel
         ]);
-        data.append(att_data.as_slice());
+        data.append(sm_data.as_slice());
 
         let len = data.len - 4;
         data.set(0, (len & 0xff) as u8);
@@ -58,3 +102,140 @@ impl L2capPacket {
         data
     }
 }
+
+/// The largest L2CAP payload the host receives, an ATT PDU of the local MTU or an SMP PDU
+pub const MAX_SDU_SIZE: usize = L2CAP_PDU_LEN - 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReassemblyError {
+    /// A continuation fragment arrived without a preceding first fragment
+    UnexpectedContinuation(u16),
+    /// The SDU is larger than the reassembler accepts
+    SduTooLarge(u16),
+    /// The fragments carry more data than announced in the L2CAP header
+    LengthMismatch(u16),
+    /// Partial SDUs for too many connections are pending
+    NoFreeSlot(u16),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PartialSdu {
+    packet: AclPacket<L2CAP_PDU_LEN>,
+}
+
+impl PartialSdu {
+    /// The length of the complete PDU including the L2CAP header, if already known
+    fn wanted(&self) -> Option<usize> {
+        let data = self.packet.data.as_slice();
+        if data.len() < 2 {
+            return None;
+        }
+
+        Some(u16::from_le_bytes([data[0], data[1]]) as usize + 4)
+    }
+}
+
+/// Re-assembles L2CAP PDUs from ACL fragments.
+///
+/// Fragments are tracked per connection handle for up to `N` connections at the same time.
+/// A new first fragment for a handle drops the incomplete PDU received so far.
+#[derive(Debug)]
+pub struct L2capReassembler<const N: usize> {
+    slots: [Option<PartialSdu>; N],
+    max_sdu: usize,
+}
+
+impl<const N: usize> L2capReassembler<N> {
+    /// Create a new reassembler accepting SDUs of up to `max_sdu` bytes
+    ///
+    /// `max_sdu` is limited to [`MAX_SDU_SIZE`].
+    pub fn new(max_sdu: usize) -> Self {
+        Self {
+            slots: [None; N],
+            max_sdu: max_sdu.min(MAX_SDU_SIZE),
+        }
+    }
+
+    /// Feed an ACL packet, returns the complete PDU once all fragments are there
+    pub fn push(
+        &mut self,
+        packet: AclPacket,
+    ) -> Result<Option<AclPacket<L2CAP_PDU_LEN>>, ReassemblyError> {
+        let handle = packet.handle;
+        let index = match packet.boundary_flag {
+            BoundaryFlag::Continuing => {
+                let index = self
+                    .find(handle)
+                    .ok_or(ReassemblyError::UnexpectedContinuation(handle))?;
+                let partial = self.slots[index].as_mut().unwrap();
+
+                if partial.packet.data.len() + packet.data.len() > self.max_sdu + 4
+                    || partial
+                        .packet
+                        .data
+                        .try_append(packet.data.as_slice())
+                        .is_err()
+                {
+                    self.slots[index] = None;
+                    return Err(ReassemblyError::LengthMismatch(handle));
+                }
+                index
+            }
+            _ => {
+                if let Some(index) = self.find(handle) {
+                    log::warn!("Dropping incomplete SDU for handle {}", handle);
+                    self.slots[index] = None;
+                }
+
+                let index = self
+                    .slots
+                    .iter()
+                    .position(Option::is_none)
+                    .ok_or(ReassemblyError::NoFreeSlot(handle))?;
+                let mut data = Data::default();
+                data.try_append(packet.data.as_slice())
+                    .map_err(|_| ReassemblyError::SduTooLarge(handle))?;
+                self.slots[index] = Some(PartialSdu {
+                    packet: AclPacket {
+                        handle,
+                        boundary_flag: packet.boundary_flag,
+                        bc_flag: packet.bc_flag,
+                        data,
+                    },
+                });
+                index
+            }
+        };
+
+        let partial = self.slots[index].as_ref().unwrap();
+        let len = partial.packet.data.len();
+        match partial.wanted() {
+            Some(wanted) if wanted > self.max_sdu + 4 => {
+                self.slots[index] = None;
+                Err(ReassemblyError::SduTooLarge(handle))
+            }
+            Some(wanted) if wanted < len => {
+                self.slots[index] = None;
+                Err(ReassemblyError::LengthMismatch(handle))
+            }
+            Some(wanted) if wanted == len => Ok(self.slots[index].take().map(|p| p.packet)),
+            _ => {
+                log::debug!("Need more for handle {}, got {} bytes", handle, len);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Drop any incomplete PDU for the given connection, e.g. after it got disconnected
+    pub fn discard(&mut self, handle: u16) {
+        if let Some(index) = self.find(handle) {
+            self.slots[index] = None;
+        }
+    }
+
+    fn find(&self, handle: u16) -> Option<usize> {
+        self.slots
+            .iter()
+            .position(|slot| matches!(slot, Some(partial) if partial.packet.handle == handle))
+    }
+}
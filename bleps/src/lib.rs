@@ -1,24 +1,48 @@
 #![no_std]
 
 use core::cell::RefCell;
+use core::time::Duration;
 
-use acl::AclPacket;
+use acl::{
+    AclBufferSize, AclFlowControl, AclPacket, BoundaryFlag, HostBroadcastFlag, ACL_DATA_LEN,
+};
+use bt_hci::{
+    cmd::{
+        info::{ReadLocalSupportedCmds, ReadLocalSupportedFeatures, ReadLocalVersionInformation},
+        le::{LeReadLocalSupportedFeatures, LeReadSupportedStates, LeSetEventMask},
+        AsyncCmd, SyncCmd,
+    },
+    FromHciBytes, FromHciBytesError,
+};
+use clock::Clock;
 use command::{
-    opcode, Command, INFORMATIONAL_OGF, LONG_TERM_KEY_REQUEST_REPLY_OCF, READ_BD_ADDR_OCF,
-    SET_ADVERTISE_ENABLE_OCF, SET_ADVERTISING_DATA_OCF, SET_EVENT_MASK_OCF, SET_SCAN_RSP_DATA_OCF,
+    encode_hci_cmd, opcode, Command, INFORMATIONAL_OGF, LONG_TERM_KEY_REQUEST_REPLY_OCF,
+    READ_BD_ADDR_OCF, READ_BUFFER_SIZE_OCF, SET_ADVERTISE_ENABLE_OCF, SET_ADVERTISING_DATA_OCF,
+    SET_EVENT_MASK_OCF, SET_RANDOM_ADDRESS_OCF, SET_SCAN_RSP_DATA_OCF,
 };
-use command::{LE_OGF, SET_ADVERTISING_PARAMETERS_OCF};
-use embedded_io_blocking::{Read, Write};
-use event::EventType;
+use command::{LE_OGF, LE_READ_BUFFER_SIZE_OCF, SET_ADVERTISING_PARAMETERS_OCF};
+use controller::{le_event_mask, AdvertisingState, ControllerInfo, StoredAdvertisingParameters};
+use embedded_io_blocking::{Error as _, Read, Write};
+use event::{ErrorCode, EventType};
+use h4::{H4Framer, PacketType};
+use iso::IsoPacket;
+use l2cap::{L2capReassembler, L2CAP_PDU_LEN, MAX_SDU_SIZE};
+use sco::ScoPacket;
 
 pub mod acl;
 pub mod att;
+pub mod h4;
+pub mod iso;
 pub mod l2cap;
+pub mod sco;
 
+pub mod clock;
 pub mod command;
+pub mod controller;
 pub mod event;
 
 pub mod ad_structure;
+pub mod beacon;
 
 pub mod attribute;
 pub mod attribute_server;
@@ -30,6 +54,8 @@ pub mod sm;
 
 #[cfg(feature = "async")]
 pub mod async_attribute_server;
+#[cfg(feature = "async")]
+pub mod host;
 
 #[cfg(feature = "macros")]
 pub use bleps_macros::gatt;
@@ -39,10 +65,92 @@ use command::RESET_OCF;
 
 const TIMEOUT_MILLIS: u64 = 1000;
 
+/// How long reading a packet may stall before giving up
+const READ_TIMEOUT_MILLIS: u64 = 500;
+
+/// Number of connections the host keeps per-connection state for
+pub const MAX_CONNECTIONS: usize = 2;
+
+/// Number of received packets [`Ble`] keeps by default while waiting for the controller, see
+/// [`Error::PendingFull`] and [`Ble::with_pending_capacity`]
+pub const PENDING_POLL_RESULTS: usize = 4;
+
+/// Number of consecutive timeouts after which the controller is considered stalled
+pub const DEFAULT_TIMEOUTS_BEFORE_RESET: u8 = 3;
+
+/// Size of the receive buffer of [`HciConnector`]
+const HCI_RX_BUFFER_SIZE: usize = 64;
+
 #[derive(Debug)]
 pub enum Error {
+    /// The controller didn't answer in time
     Timeout,
-    Failed(u8),
+    /// The command `opcode` failed with `status`
+    Failed { opcode: u16, status: ErrorCode },
+    /// The controller sent a byte which is not a known H4 packet indicator
+    UnknownPacketType(u8),
+    /// The transport reported an error
+    Io(embedded_io_blocking::ErrorKind),
+    /// A packet was shorter than its header or format requires, or didn't fit into the buffer
+    Truncated,
+    /// A packet contained a value its format doesn't allow
+    InvalidValue,
+    /// The advertising parameters were rejected before sending them
+    InvalidAdvertisingParameters(AdvertisingParametersError),
+    /// As many received packets as [`Ble`] can keep wait for [`Ble::poll`], nothing more is read
+    /// from the controller until they were polled
+    PendingFull,
+    /// The client didn't enable notifications for the attribute
+    NotificationsDisabled,
+    /// A request was answered with the response to a different kind of request
+    UnexpectedResponse,
+}
+
+impl From<FromHciBytesError> for Error {
+    fn from(err: FromHciBytesError) -> Self {
+        match err {
+            FromHciBytesError::InvalidSize => Error::Truncated,
+            FromHciBytesError::InvalidValue => Error::InvalidValue,
+        }
+    }
+}
+
+impl From<embedded_io_blocking::ErrorKind> for Error {
+    fn from(kind: embedded_io_blocking::ErrorKind) -> Self {
+        match kind {
+            embedded_io_blocking::ErrorKind::TimedOut => Error::Timeout,
+            kind => Error::Io(kind),
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<E: embedded_io_async::Error> From<embedded_io_async::ReadExactError<E>> for Error {
+    fn from(err: embedded_io_async::ReadExactError<E>) -> Self {
+        match err {
+            embedded_io_async::ReadExactError::UnexpectedEof => Error::Truncated,
+            embedded_io_async::ReadExactError::Other(err) => err.kind().into(),
+        }
+    }
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Timeout => write!(f, "timed out waiting for the controller"),
+            Error::Failed { opcode, status } => {
+                write!(f, "command {:#06x} failed: {}", opcode, status)
+            }
+            Error::UnknownPacketType(value) => write!(f, "unknown packet type {:#04x}", value),
+            Error::Io(kind) => write!(f, "transport error {:?}", kind),
+            Error::Truncated => write!(f, "packet truncated"),
+            Error::InvalidValue => write!(f, "invalid value in packet"),
+            Error::InvalidAdvertisingParameters(err) => write!(f, "{}", err),
+            Error::PendingFull => write!(f, "too many received packets waiting to be polled"),
+            Error::NotificationsDisabled => write!(f, "notifications are disabled"),
+            Error::UnexpectedResponse => write!(f, "unexpected response to a request"),
+        }
+    }
 }
 
 #[cfg(feature = "defmt")]
@@ -52,8 +160,37 @@ impl defmt::Format for Error {
             Error::Timeout => {
                 defmt::write!(fmt, "Timeout")
             }
-            Error::Failed(value) => {
-                defmt::write!(fmt, "Failed({})", value)
+            Error::Failed { opcode, status } => {
+                defmt::write!(
+                    fmt,
+                    "Failed {{ opcode: {=u16:#06x}, status: {} }}",
+                    opcode,
+                    status
+                )
+            }
+            Error::UnknownPacketType(value) => {
+                defmt::write!(fmt, "UnknownPacketType({=u8:#04x})", value)
+            }
+            Error::Io(kind) => {
+                defmt::write!(fmt, "Io({})", defmt::Debug2Format(kind))
+            }
+            Error::Truncated => {
+                defmt::write!(fmt, "Truncated")
+            }
+            Error::InvalidValue => {
+                defmt::write!(fmt, "InvalidValue")
+            }
+            Error::InvalidAdvertisingParameters(err) => {
+                defmt::write!(fmt, "InvalidAdvertisingParameters({})", err)
+            }
+            Error::PendingFull => {
+                defmt::write!(fmt, "PendingFull")
+            }
+            Error::NotificationsDisabled => {
+                defmt::write!(fmt, "NotificationsDisabled")
+            }
+            Error::UnexpectedResponse => {
+                defmt::write!(fmt, "UnexpectedResponse")
             }
         }
     }
@@ -81,23 +218,52 @@ impl Addr {
 #[derive(Debug)]
 pub enum PollResult {
     Event(EventType),
-    AsyncData(AclPacket),
+    /// An L2CAP PDU, reassembled if it was fragmented
+    AsyncData(AclPacket<L2CAP_PDU_LEN>),
+    SyncData(ScoPacket),
+    IsoData(IsoPacket),
+}
+
+/// Returned when bytes don't fit into the capacity left in a [`Data`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CapacityError;
+
+impl core::fmt::Display for CapacityError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "data doesn't fit into the buffer")
+    }
+}
+
+impl From<CapacityError> for Error {
+    fn from(_: CapacityError) -> Self {
+        Error::Truncated
+    }
 }
 
+/// A buffer holding up to `N` bytes
+///
+/// Each layer picks the capacity it needs, e.g. [`event::EVENT_DATA_LEN`],
+/// [`acl::ACL_DATA_LEN`] or [`att::ATT_PDU_LEN`].
 #[derive(Clone, Copy)]
-pub struct Data {
-    pub data: [u8; 256],
+pub struct Data<const N: usize = 256> {
+    pub data: [u8; N],
     pub len: usize,
 }
 
-impl Data {
-    pub fn new(bytes: &[u8]) -> Data {
-        let mut data = [0u8; 256];
-        data[..bytes.len()].copy_from_slice(bytes);
-        Data {
-            data,
-            len: bytes.len(),
-        }
+impl<const N: usize> Data<N> {
+    /// Creates a buffer holding `bytes`
+    ///
+    /// Panics if `bytes` are longer than `N`, see [`Data::try_append`] for a fallible way.
+    pub fn new(bytes: &[u8]) -> Self {
+        let mut data = Self::default();
+        data.append(bytes);
+        data
+    }
+
+    /// The number of bytes the buffer can hold
+    pub const fn capacity(&self) -> usize {
+        N
     }
 
     pub fn as_slice(&self) -> &[u8] {
@@ -109,11 +275,7 @@ impl Data {
     }
 
     pub fn set_len(&mut self, new_len: usize) {
-        self.len = if new_len > self.data.len() {
-            self.data.len()
-        } else {
-            new_len
-        };
+        self.len = new_len.min(N);
     }
 
     pub fn append_len(&mut self, extra_len: usize) {
@@ -126,38 +288,81 @@ impl Data {
         }
     }
 
-    pub fn subdata_from(&self, from: usize) -> Data {
-        let mut data = [0u8; 256];
-        let new_len = self.len - from;
-        data[..new_len].copy_from_slice(&self.data[from..(from + new_len)]);
-        Data { data, len: new_len }
+    /// The bytes starting at `from`, empty if there are not that many
+    pub fn subdata_from(&self, from: usize) -> Self {
+        Self::new(self.as_slice().get(from..).unwrap_or_default())
     }
 
+    /// Appends `bytes`, panics if they don't fit
     pub fn append(&mut self, bytes: &[u8]) {
-        self.data[self.len..(self.len + bytes.len())].copy_from_slice(bytes);
-        self.len += bytes.len();
+        if self.try_append(bytes).is_err() {
+            panic!(
+                "{} bytes don't fit into Data<{}> holding {}",
+                bytes.len(),
+                N,
+                self.len
+            );
+        }
+    }
+
+    /// Appends `bytes` if they fit into the remaining capacity, leaves the buffer untouched if not
+    pub fn try_append(&mut self, bytes: &[u8]) -> Result<(), CapacityError> {
+        let end = self.len + bytes.len();
+        if end > N {
+            return Err(CapacityError);
+        }
+
+        self.data[self.len..end].copy_from_slice(bytes);
+        self.len = end;
+        Ok(())
     }
 
+    /// Appends `value` in little-endian byte order, panics if it doesn't fit
     pub fn append_value<T: Sized + 'static>(&mut self, value: T) {
+        if self.try_append_value(value).is_err() {
+            panic!("Value doesn't fit into Data<{}> holding {}", N, self.len);
+        }
+    }
+
+    /// Appends `value` in little-endian byte order if it fits into the remaining capacity
+    pub fn try_append_value<T: Sized + 'static>(&mut self, value: T) -> Result<(), CapacityError> {
         let slice = unsafe {
-            core::slice::from_raw_parts(&value as *const _ as *const _, core::mem::size_of::<T>())
+            core::slice::from_raw_parts(&value as *const _ as *const u8, core::mem::size_of::<T>())
         };
 
         #[cfg(target_endian = "little")]
-        self.append(slice);
+        return self.try_append(slice);
 
         #[cfg(target_endian = "big")]
         {
-            let top = slice.len() - 1;
-            for (index, byte) in slice.iter().enumerate() {
-                self.set(top - index, *byte);
+            let end = self.len + slice.len();
+            if end > N {
+                return Err(CapacityError);
+            }
+
+            for (dst, src) in self.data[self.len..end].iter_mut().zip(slice.iter().rev()) {
+                *dst = *src;
             }
-            self.append_len(slice.len());
+            self.len = end;
+            Ok(())
         }
     }
 
+    /// Overwrites the byte at `index`, panics if there is none
     pub fn set(&mut self, index: usize, byte: u8) {
+        if self.try_set(index, byte).is_err() {
+            panic!("Index {} out of range for Data of len {}", index, self.len);
+        }
+    }
+
+    /// Overwrites the byte at `index` if there is one
+    pub fn try_set(&mut self, index: usize, byte: u8) -> Result<(), CapacityError> {
+        if index >= self.len {
+            return Err(CapacityError);
+        }
+
         self.data[index] = byte;
+        Ok(())
     }
 
     pub fn len(&self) -> usize {
@@ -165,20 +370,23 @@ impl Data {
     }
 }
 
-impl Default for Data {
+impl<const N: usize> Default for Data<N> {
     fn default() -> Self {
-        Data::new(&[])
+        Data {
+            data: [0u8; N],
+            len: 0,
+        }
     }
 }
 
-impl core::fmt::Debug for Data {
+impl<const N: usize> core::fmt::Debug for Data<N> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{:x?}", &self.data[..self.len]).expect("Failed to format Data");
         Ok(())
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum AdvertisingType {
     AdvInd = 0x00,
     AdvDirectInd = 0x01,
@@ -187,7 +395,7 @@ pub enum AdvertisingType {
     AdvDirectIndLowDuty = 0x04,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum OwnAddressType {
     Public = 0x00,
     Random = 0x01,
@@ -228,337 +436,404 @@ pub struct AdvertisingParameters {
     pub filter_policy: AdvertisingFilterPolicy,
 }
 
-const PACKET_TYPE_COMMAND: u8 = 0x01;
-const PACKET_TYPE_ASYNC_DATA: u8 = 0x02;
-const PACKET_TYPE_EVENT: u8 = 0x04;
+/// Smallest advertising interval in units of 0.625 ms, 20 ms
+const MIN_ADVERTISING_INTERVAL: u16 = 0x0020;
+
+/// Largest advertising interval in units of 0.625 ms, 10.24 s
+const MAX_ADVERTISING_INTERVAL: u16 = 0x4000;
+
+const ALL_ADVERTISING_CHANNELS: u8 = AdvertisingChannelMapBits::Channel37 as u8
+    | AdvertisingChannelMapBits::Channel38 as u8
+    | AdvertisingChannelMapBits::Channel39 as u8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AdvertisingParametersError {
+    /// An interval is shorter than 20 ms or longer than 10.24 s
+    IntervalOutOfRange,
+    /// The minimum interval is longer than the maximum interval
+    MinIntervalAboveMax,
+    /// The channel map selects no channel or a channel other than 37, 38 and 39
+    InvalidChannelMap,
+    /// Directed advertising and resolvable private addresses need the address of the peer
+    MissingPeerAddress,
+}
 
-pub struct Ble<'a> {
-    connector: &'a dyn HciConnection,
+impl core::fmt::Display for AdvertisingParametersError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            AdvertisingParametersError::IntervalOutOfRange => {
+                write!(f, "advertising interval not within 20 ms to 10.24 s")
+            }
+            AdvertisingParametersError::MinIntervalAboveMax => {
+                write!(f, "minimum advertising interval above the maximum")
+            }
+            AdvertisingParametersError::InvalidChannelMap => {
+                write!(f, "invalid advertising channel map")
+            }
+            AdvertisingParametersError::MissingPeerAddress => write!(f, "peer address missing"),
+        }
+    }
 }
 
-impl<'a> Ble<'a> {
-    pub fn new(connector: &'a dyn HciConnection) -> Ble<'a> {
-        Ble { connector }
+impl Default for AdvertisingParameters {
+    /// Connectable undirected advertising every 160 ms on all channels
+    fn default() -> Self {
+        AdvertisingParameters {
+            advertising_interval_min: 0x0100,
+            advertising_interval_max: 0x0100,
+            advertising_type: AdvertisingType::AdvInd,
+            own_address_type: OwnAddressType::Public,
+            peer_address_type: PeerAddressType::Public,
+            peer_address: [0; 6],
+            advertising_channel_map: ALL_ADVERTISING_CHANNELS,
+            filter_policy: AdvertisingFilterPolicy::All,
+        }
     }
+}
 
-    pub fn init(&mut self) -> Result<(), Error>
-    where
-        Self: Sized,
-    {
-        self.cmd_reset()?;
-        self.cmd_set_event_mask([0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff])?;
-        Ok(())
+impl AdvertisingParameters {
+    pub fn builder() -> AdvertisingParametersBuilder {
+        AdvertisingParametersBuilder {
+            params: AdvertisingParameters::default(),
+            peer_address: false,
+        }
     }
 
-    pub fn cmd_reset(&mut self) -> Result<EventType, Error>
-    where
-        Self: Sized,
-    {
-        self.write_bytes(Command::Reset.encode().as_slice());
-        self.wait_for_command_complete(CONTROLLER_OGF, RESET_OCF)?
-            .check_command_completed()
+    /// Checks the intervals and the channel map
+    ///
+    /// High duty cycle directed advertising has no interval, its intervals aren't checked.
+    pub fn validate(&self) -> Result<(), AdvertisingParametersError> {
+        if self.advertising_type != AdvertisingType::AdvDirectInd {
+            let range = MIN_ADVERTISING_INTERVAL..=MAX_ADVERTISING_INTERVAL;
+            if !range.contains(&self.advertising_interval_min)
+                || !range.contains(&self.advertising_interval_max)
+            {
+                return Err(AdvertisingParametersError::IntervalOutOfRange);
+            }
+            if self.advertising_interval_min > self.advertising_interval_max {
+                return Err(AdvertisingParametersError::MinIntervalAboveMax);
+            }
+        }
+
+        if self.advertising_channel_map == 0
+            || self.advertising_channel_map & !ALL_ADVERTISING_CHANNELS != 0
+        {
+            return Err(AdvertisingParametersError::InvalidChannelMap);
+        }
+
+        Ok(())
     }
+}
 
-    pub fn cmd_set_event_mask(&mut self, events: [u8; 8]) -> Result<EventType, Error>
-    where
-        Self: Sized,
-    {
-        self.write_bytes(Command::SetEventMask { events }.encode().as_slice());
-        self.wait_for_command_complete(CONTROLLER_OGF, SET_EVENT_MASK_OCF)?
-            .check_command_completed()
+/// Builds [`AdvertisingParameters`] the controller accepts
+///
+/// Starts from [`AdvertisingParameters::default`].
+#[derive(Debug, Clone, Copy)]
+pub struct AdvertisingParametersBuilder {
+    params: AdvertisingParameters,
+    peer_address: bool,
+}
+
+impl AdvertisingParametersBuilder {
+    /// Sets the range the controller picks the advertising interval from
+    ///
+    /// Both are rounded down to multiples of 0.625 ms and need to be within 20 ms to 10.24 s.
+    pub fn interval(mut self, min: Duration, max: Duration) -> Self {
+        self.params.advertising_interval_min = advertising_interval(min);
+        self.params.advertising_interval_max = advertising_interval(max);
+        self
     }
 
-    pub fn cmd_set_le_advertising_parameters(&mut self) -> Result<EventType, Error>
-    where
-        Self: Sized,
-    {
-        self.write_bytes(Command::LeSetAdvertisingParameters.encode().as_slice());
-        self.wait_for_command_complete(LE_OGF, SET_ADVERTISING_PARAMETERS_OCF)?
-            .check_command_completed()
+    pub fn advertising_type(mut self, advertising_type: AdvertisingType) -> Self {
+        self.params.advertising_type = advertising_type;
+        self
     }
 
-    pub fn cmd_set_le_advertising_parameters_custom(
-        &mut self,
-        params: &AdvertisingParameters,
-    ) -> Result<EventType, Error>
-    where
-        Self: Sized,
-    {
-        self.write_bytes(
-            Command::LeSetAdvertisingParametersCustom(params)
-                .encode()
-                .as_slice(),
-        );
-        self.wait_for_command_complete(LE_OGF, SET_ADVERTISING_PARAMETERS_OCF)?
-            .check_command_completed()
+    pub fn own_address_type(mut self, own_address_type: OwnAddressType) -> Self {
+        self.params.own_address_type = own_address_type;
+        self
     }
 
-    pub fn cmd_set_le_advertising_data(&mut self, data: Data) -> Result<EventType, Error>
-    where
-        Self: Sized,
-    {
-        self.write_bytes(Command::LeSetAdvertisingData { data }.encode().as_slice());
-        self.wait_for_command_complete(LE_OGF, SET_ADVERTISING_DATA_OCF)?
-            .check_command_completed()
+    /// The peer directed advertising is sent to, also used to find the local IRK for resolvable
+    /// private addresses
+    ///
+    /// `peer_address` is in little-endian byte order as sent to the controller.
+    pub fn peer_address(
+        mut self,
+        peer_address_type: PeerAddressType,
+        peer_address: [u8; 6],
+    ) -> Self {
+        self.params.peer_address_type = peer_address_type;
+        self.params.peer_address = peer_address;
+        self.peer_address = true;
+        self
     }
 
-    pub fn cmd_set_le_scan_rsp_data(&mut self, data: Data) -> Result<EventType, Error>
-    where
-        Self: Sized,
-    {
-        self.write_bytes(Command::LeSetScanRspData { data }.encode().as_slice());
-        self.wait_for_command_complete(LE_OGF, SET_SCAN_RSP_DATA_OCF)?
-            .check_command_completed()
+    /// Selects the channels to advertise on, see [`AdvertisingChannelMapBits`]
+    pub fn channel_map(mut self, channel_map: u8) -> Self {
+        self.params.advertising_channel_map = channel_map;
+        self
     }
 
-    pub fn cmd_set_le_advertise_enable(&mut self, enable: bool) -> Result<EventType, Error>
-    where
-        Self: Sized,
-    {
-        self.write_bytes(Command::LeSetAdvertiseEnable(enable).encode().as_slice());
-        self.wait_for_command_complete(LE_OGF, SET_ADVERTISE_ENABLE_OCF)?
-            .check_command_completed()
+    pub fn filter_policy(mut self, filter_policy: AdvertisingFilterPolicy) -> Self {
+        self.params.filter_policy = filter_policy;
+        self
     }
 
-    pub fn cmd_long_term_key_request_reply(
-        &mut self,
-        handle: u16,
-        ltk: u128,
-    ) -> Result<EventType, Error>
-    where
-        Self: Sized,
-    {
-        log::trace!("before, key = {:x}, hanlde = {:x}", ltk, handle);
-        self.write_bytes(
-            Command::LeLongTermKeyRequestReply { handle, ltk }
-                .encode()
-                .as_slice(),
+    pub fn build(self) -> Result<AdvertisingParameters, AdvertisingParametersError> {
+        self.params.validate()?;
+
+        let directed = matches!(
+            self.params.advertising_type,
+            AdvertisingType::AdvDirectInd | AdvertisingType::AdvDirectIndLowDuty
         );
-        log::trace!("done writing command");
-        let res = self
-            .wait_for_command_complete(LE_OGF, LONG_TERM_KEY_REQUEST_REPLY_OCF)?
-            .check_command_completed();
-        log::trace!("got completion event");
+        let resolvable = matches!(
+            self.params.own_address_type,
+            OwnAddressType::ResolvablePrivateAddress
+                | OwnAddressType::ResolvablePrivateAddressFromIRK
+        );
+        // any other advertising ignores the peer address
+        if (directed || resolvable) && !self.peer_address {
+            return Err(AdvertisingParametersError::MissingPeerAddress);
+        }
 
-        res
+        Ok(self.params)
     }
+}
 
-    pub fn cmd_read_br_addr(&mut self) -> Result<[u8; 6], Error>
-    where
-        Self: Sized,
-    {
-        self.write_bytes(Command::ReadBrAddr.encode().as_slice());
-        let res = self
-            .wait_for_command_complete(INFORMATIONAL_OGF, READ_BD_ADDR_OCF)?
-            .check_command_completed()?;
-        match res {
-            EventType::CommandComplete {
-                num_packets: _,
-                opcode: _,
-                data,
-            } => Ok(data.as_slice()[1..][..6].try_into().unwrap()),
-            _ => Err(Error::Failed(0)),
-        }
-    }
+/// Converts `duration` to units of 0.625 ms, saturating at `u16::MAX`
+fn advertising_interval(duration: Duration) -> u16 {
+    (duration.as_micros() / 625).try_into().unwrap_or(u16::MAX)
+}
 
-    fn wait_for_command_complete(&mut self, ogf: u8, ocf: u16) -> Result<EventType, Error>
-    where
-        Self: Sized,
-    {
-        let timeout_at = self.connector.millis() + TIMEOUT_MILLIS;
-        loop {
-            let res = self.poll();
-            if res.is_some() {
-                log::debug!("polled while waiting {:?}", res);
-            }
+pub struct Ble<'a, const P: usize = PENDING_POLL_RESULTS> {
+    connector: &'a dyn HciConnection,
+    framer: H4Framer,
+    reassembler: L2capReassembler<MAX_CONNECTIONS>,
+    acl_flow: AclFlowControl<MAX_CONNECTIONS>,
+    command_credits: u8,
+    pending: heapless::Deque<PollResult, P>,
+    controller_info: Option<ControllerInfo>,
+    advertising: AdvertisingState,
+    needs_reset: bool,
+    timeouts: u8,
+    timeouts_before_reset: u8,
+}
 
-            match res {
-                Some(PollResult::Event(event)) => match event {
-                    EventType::CommandComplete { opcode: code, .. } if code == opcode(ogf, ocf) => {
-                        return Ok(event);
-                    }
-                    _ => (),
-                },
-                _ => (),
-            }
+impl<'a> Ble<'a> {
+    pub fn new(connector: &'a dyn HciConnection) -> Ble<'a> {
+        Ble::with_pending_capacity(connector)
+    }
+}
 
-            if self.connector.millis() > timeout_at {
-                return Err(Error::Timeout);
-            }
+impl<'a, const P: usize> Ble<'a, P> {
+    /// Creates an instance keeping up to `P` received packets while waiting for the controller
+    ///
+    /// Each of them takes `size_of::<PollResult>()` bytes, [`Ble::new`] keeps
+    /// [`PENDING_POLL_RESULTS`].
+    pub fn with_pending_capacity(connector: &'a dyn HciConnection) -> Ble<'a, P> {
+        Ble {
+            connector,
+            framer: H4Framer::new(),
+            reassembler: L2capReassembler::new(MAX_SDU_SIZE),
+            acl_flow: AclFlowControl::new(),
+            // the controller accepts one command until it reports otherwise
+            command_credits: 1,
+            pending: heapless::Deque::new(),
+            controller_info: None,
+            advertising: AdvertisingState::default(),
+            needs_reset: false,
+            timeouts: 0,
+            timeouts_before_reset: DEFAULT_TIMEOUTS_BEFORE_RESET,
         }
     }
 
-    pub fn poll(&mut self) -> Option<PollResult>
+    /// Number of bytes discarded while resynchronising to the HCI byte stream
+    pub fn dropped_bytes(&self) -> usize {
+        self.framer.dropped_bytes()
+    }
+
+    fn millis(&self) -> u64 {
+        self.connector.millis()
+    }
+
+    /// Reads the next packet
+    ///
+    /// Without a `deadline` it returns `Ok(None)` as soon as nothing is available. With one it
+    /// keeps reading until there is something to return, `Ok(None)` means the deadline passed.
+    fn poll_hci(&mut self, deadline: Option<u64>) -> Result<Option<PollResult>, Error>
     where
         Self: Sized,
     {
-        // poll & process input
-        let packet_type = self.connector.read();
-
-        match packet_type {
-            Some(packet_type) => match packet_type {
-                PACKET_TYPE_COMMAND => {}
-                PACKET_TYPE_ASYNC_DATA => {
-                    let mut acl_packet = AclPacket::read(self.connector);
-                    let wanted =
-                        u16::from_le_bytes(acl_packet.data.as_slice()[..2].try_into().unwrap())
-                            as usize;
-
-                    // somewhat dirty way to handle re-assembling fragmented packets
-                    loop {
-                        log::debug!("Wanted = {}, actual = {}", wanted, acl_packet.data.len());
-
-                        if wanted == acl_packet.data.len() - 4 {
-                            break;
-                        }
-
-                        log::debug!("Need more!");
-                        if self.connector.read() != Some(PACKET_TYPE_ASYNC_DATA) {
-                            log::error!("Expected async data");
-                        }
-
-                        let next_acl_packet = AclPacket::read(self.connector);
-                        acl_packet.data.append(next_acl_packet.data.as_slice());
+        loop {
+            let packet_type = loop {
+                let mut byte = [0u8];
+                if self.connector.read(&mut byte)? == 0 {
+                    match deadline {
+                        Some(deadline) if self.millis() < deadline => continue,
+                        _ => return Ok(None),
                     }
+                }
 
-                    return Some(PollResult::AsyncData(acl_packet));
+                if let Some(packet_type) = self.framer.accept(byte[0])? {
+                    break packet_type;
                 }
-                PACKET_TYPE_EVENT => {
-                    let event = EventType::read(self.connector);
-                    return Some(PollResult::Event(event));
+            };
+
+            let res = match packet_type {
+                PacketType::AclData => {
+                    let acl_packet = AclPacket::read(self.connector)?;
+                    self.reassemble(acl_packet)
                 }
-                _ => {
-                    // this is a serious error
-                    panic!("Unknown packet type {}", packet_type);
+                PacketType::SyncData => {
+                    Some(PollResult::SyncData(ScoPacket::read(self.connector)?))
                 }
-            },
-            None => {}
-        }
-
-        None
-    }
+                PacketType::Event => {
+                    let event = EventType::read(self.connector)?;
+                    self.track_event(&event);
+                    Some(PollResult::Event(event))
+                }
+                PacketType::IsoData => Some(PollResult::IsoData(IsoPacket::read(self.connector)?)),
+                PacketType::Command => None,
+            };
 
-    fn write_bytes(&mut self, bytes: &[u8]) {
-        for b in bytes {
-            self.connector.write(*b);
+            if res.is_some() || deadline.is_none() {
+                return Ok(res);
+            }
         }
     }
-}
 
-impl Data {
-    fn read(connector: &dyn HciConnection, len: usize) -> Self {
-        let mut data = [0u8; 256];
-        for i in 0..len {
-            loop {
-                match connector.read() {
-                    Some(byte) => {
-                        data[i] = byte;
-                        break;
-                    }
-                    None => {
-                        // TODO timeout?
-                    }
-                };
-            }
-        }
-        let mut data = Self::new(&data);
-        data.len = len;
-        data
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.connector.write(bytes)?;
+        self.connector.flush()?;
+        Ok(())
     }
 }
 
-pub trait HciConnection {
-    fn read(&self) -> Option<u8>;
+// Using the bleps-dedup proc-macro to de-duplicate the async/sync code
+// The macro will remove async/await for the SYNC implementation
+bleps_dedup::dedup! {
+    impl<'a, const P: usize> SYNC Ble<'a, P>
+    impl<T, K, const P: usize> ASYNC asynch::Ble<T, K, P>
+        where
+            T: embedded_io_async::Read + embedded_io_async::Write,
+            K: clock::AsyncClock,
+    {
+        pub async fn init(&mut self) -> Result<(), Error>
+        where
+            Self: Sized,
+        {
+            self.cmd_reset().await?;
+            self.cmd_set_event_mask([0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff])
+                .await?;
 
-    fn write(&self, data: u8);
+            let version = self.exec(&ReadLocalVersionInformation::new()).await?;
+            let supported_commands = self.exec(&ReadLocalSupportedCmds::new()).await?;
+            let features = self.exec(&ReadLocalSupportedFeatures::new()).await?;
+            let le_features = self.exec(&LeReadLocalSupportedFeatures::new()).await?;
+            self.exec(&LeSetEventMask::new(le_event_mask(&le_features)))
+                .await?;
 
-    fn millis(&self) -> u64;
-}
+            // controllers without dedicated LE buffers report a length of zero
+            let mut buffer_size = self.cmd_le_read_buffer_size().await?;
+            if buffer_size.packet_len == 0 {
+                buffer_size = self.cmd_read_buffer_size().await?;
+            }
+            log::debug!("ACL buffer size {:?}", buffer_size);
+            self.acl_flow.set_buffer_size(buffer_size);
 
-pub struct HciConnector<T>
-where
-    T: Read + Write,
-{
-    hci: RefCell<T>,
-    get_millis: fn() -> u64,
-}
+            let le_states = if supported_commands.le_read_supported_states() {
+                Some(self.exec(&LeReadSupportedStates::new()).await?)
+            } else {
+                None
+            };
 
-impl<T> HciConnector<T>
-where
-    T: Read + Write,
-{
-    pub fn new(hci: T, get_millis: fn() -> u64) -> HciConnector<T> {
-        HciConnector {
-            hci: RefCell::new(hci),
-            get_millis,
-        }
-    }
-}
+            self.controller_info = Some(ControllerInfo {
+                version,
+                supported_commands,
+                features,
+                le_features,
+                acl_buffer_size: buffer_size,
+                le_states,
+            });
 
-impl<T> HciConnection for HciConnector<T>
-where
-    T: Read + Write,
-{
-    fn read(&self) -> Option<u8> {
-        let mut buf = [0u8];
-        let res = self.hci.borrow_mut().read(&mut buf);
-        match res {
-            Ok(len) if len == 1 => Some(buf[0]),
-            _ => None,
+            Ok(())
         }
-    }
-
-    fn write(&self, data: u8) {
-        self.hci.borrow_mut().write(&[data]).ok();
-    }
-
-    fn millis(&self) -> u64 {
-        (self.get_millis)()
-    }
-}
 
-#[cfg(feature = "async")]
-pub mod asynch {
-    use super::*;
+        /// The controller's ACL buffers, known after [`Ble::init`]
+        pub fn acl_buffer_size(&self) -> Option<AclBufferSize> {
+            self.acl_flow.buffer_size()
+        }
 
-    pub struct Ble<T>
-    where
-        T: embedded_io_async::Read + embedded_io_async::Write,
-    {
-        hci: RefCell<T>,
-        get_millis: fn() -> u64,
-    }
+        /// What the controller reported during [`Ble::init`]
+        pub fn controller_info(&self) -> Option<&ControllerInfo> {
+            self.controller_info.as_ref()
+        }
 
-    impl<T> Ble<T>
-    where
-        T: embedded_io_async::Read + embedded_io_async::Write,
-    {
-        pub fn new(hci: T, get_millis: fn() -> u64) -> Ble<T> {
-            Ble {
-                hci: RefCell::new(hci),
-                get_millis,
-            }
+        /// Whether the controller reported a Hardware Error or stopped answering commands
+        pub fn needs_reset(&self) -> bool {
+            self.needs_reset
         }
 
-        fn millis(&self) -> u64 {
-            (self.get_millis)()
+        /// Sets after how many consecutive command timeouts the controller is considered
+        /// stalled, see [`Ble::needs_reset`]
+        ///
+        /// Defaults to [`DEFAULT_TIMEOUTS_BEFORE_RESET`], 0 never considers it stalled.
+        pub fn set_timeouts_before_reset(&mut self, timeouts: u8) {
+            self.timeouts_before_reset = timeouts;
         }
 
-        pub async fn init(&mut self) -> Result<EventType, Error>
+        /// Resets and initialises the controller, then restores the advertising setup
+        ///
+        /// All connections are lost, anything not yet polled is dropped.
+        pub async fn reset_controller(&mut self) -> Result<(), Error>
         where
             Self: Sized,
         {
-            let res = self.cmd_reset().await?;
-            self.cmd_set_event_mask([0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff])
-                .await?;
-            Ok(res)
+            log::warn!("Resetting the controller");
+            self.reassembler = L2capReassembler::new(MAX_SDU_SIZE);
+            self.acl_flow = AclFlowControl::new();
+            self.command_credits = 1;
+            self.pending.clear();
+            self.needs_reset = false;
+            self.timeouts = 0;
+
+            self.init().await?;
+
+            let advertising = self.advertising;
+            if let Some(address) = advertising.random_address {
+                self.cmd_set_le_random_address(address).await?;
+            }
+            match advertising.parameters {
+                Some(StoredAdvertisingParameters::Default) => {
+                    self.cmd_set_le_advertising_parameters().await?;
+                }
+                Some(StoredAdvertisingParameters::Custom(params)) => {
+                    self.cmd_set_le_advertising_parameters_custom(&params)
+                        .await?;
+                }
+                None => (),
+            }
+            if let Some(data) = advertising.data {
+                self.cmd_set_le_advertising_data(data).await?;
+            }
+            if let Some(data) = advertising.scan_response {
+                self.cmd_set_le_scan_rsp_data(data).await?;
+            }
+            if advertising.enabled {
+                self.cmd_set_le_advertise_enable(true).await?;
+            }
+
+            Ok(())
         }
 
         pub async fn cmd_reset(&mut self) -> Result<EventType, Error>
         where
             Self: Sized,
         {
-            self.write_bytes(Command::Reset.encode().as_slice()).await;
+            self.write_command(Command::Reset.encode().as_slice())
+                .await?;
             self.wait_for_command_complete(CONTROLLER_OGF, RESET_OCF)
                 .await?
                 .check_command_completed()
@@ -568,8 +843,8 @@ pub mod asynch {
         where
             Self: Sized,
         {
-            self.write_bytes(Command::SetEventMask { events }.encode().as_slice())
-                .await;
+            self.write_command(Command::SetEventMask { events }.encode().as_slice())
+                .await?;
             self.wait_for_command_complete(CONTROLLER_OGF, SET_EVENT_MASK_OCF)
                 .await?
                 .check_command_completed()
@@ -579,11 +854,14 @@ pub mod asynch {
         where
             Self: Sized,
         {
-            self.write_bytes(Command::LeSetAdvertisingParameters.encode().as_slice())
-                .await;
-            self.wait_for_command_complete(LE_OGF, SET_ADVERTISING_PARAMETERS_OCF)
+            self.write_command(Command::LeSetAdvertisingParameters.encode().as_slice())
+                .await?;
+            let res = self
+                .wait_for_command_complete(LE_OGF, SET_ADVERTISING_PARAMETERS_OCF)
                 .await?
-                .check_command_completed()
+                .check_command_completed()?;
+            self.advertising.parameters = Some(StoredAdvertisingParameters::Default);
+            Ok(res)
         }
 
         pub async fn cmd_set_le_advertising_parameters_custom(
@@ -593,26 +871,35 @@ pub mod asynch {
         where
             Self: Sized,
         {
-            self.write_bytes(
+            params
+                .validate()
+                .map_err(Error::InvalidAdvertisingParameters)?;
+            self.write_command(
                 Command::LeSetAdvertisingParametersCustom(params)
                     .encode()
                     .as_slice(),
             )
-            .await;
-            self.wait_for_command_complete(LE_OGF, SET_ADVERTISING_PARAMETERS_OCF)
+            .await?;
+            let res = self
+                .wait_for_command_complete(LE_OGF, SET_ADVERTISING_PARAMETERS_OCF)
                 .await?
-                .check_command_completed()
-        }
+                .check_command_completed()?;
+            self.advertising.parameters = Some(StoredAdvertisingParameters::Custom(*params));
+            Ok(res)
+        }
 
         pub async fn cmd_set_le_advertising_data(&mut self, data: Data) -> Result<EventType, Error>
         where
             Self: Sized,
         {
-            self.write_bytes(Command::LeSetAdvertisingData { data }.encode().as_slice())
-                .await;
-            self.wait_for_command_complete(LE_OGF, SET_ADVERTISING_DATA_OCF)
+            self.write_command(Command::LeSetAdvertisingData { data }.encode().as_slice())
+                .await?;
+            let res = self
+                .wait_for_command_complete(LE_OGF, SET_ADVERTISING_DATA_OCF)
                 .await?
-                .check_command_completed()
+                .check_command_completed()?;
+            self.advertising.data = Some(data);
+            Ok(res)
         }
 
         pub async fn cmd_set_le_advertise_enable(
@@ -622,11 +909,45 @@ pub mod asynch {
         where
             Self: Sized,
         {
-            self.write_bytes(Command::LeSetAdvertiseEnable(enable).encode().as_slice())
-                .await;
-            self.wait_for_command_complete(LE_OGF, SET_ADVERTISE_ENABLE_OCF)
+            self.write_command(Command::LeSetAdvertiseEnable(enable).encode().as_slice())
+                .await?;
+            let res = self
+                .wait_for_command_complete(LE_OGF, SET_ADVERTISE_ENABLE_OCF)
                 .await?
-                .check_command_completed()
+                .check_command_completed()?;
+            self.advertising.enabled = enable;
+            Ok(res)
+        }
+
+        pub async fn cmd_set_le_scan_rsp_data(&mut self, data: Data) -> Result<EventType, Error>
+        where
+            Self: Sized,
+        {
+            self.write_command(Command::LeSetScanRspData { data }.encode().as_slice())
+                .await?;
+            let res = self
+                .wait_for_command_complete(LE_OGF, SET_SCAN_RSP_DATA_OCF)
+                .await?
+                .check_command_completed()?;
+            self.advertising.scan_response = Some(data);
+            Ok(res)
+        }
+
+        pub async fn cmd_set_le_random_address(
+            &mut self,
+            address: [u8; 6],
+        ) -> Result<EventType, Error>
+        where
+            Self: Sized,
+        {
+            self.write_command(Command::LeSetRandomAddress { address }.encode().as_slice())
+                .await?;
+            let res = self
+                .wait_for_command_complete(LE_OGF, SET_RANDOM_ADDRESS_OCF)
+                .await?
+                .check_command_completed()?;
+            self.advertising.random_address = Some(address);
+            Ok(res)
         }
 
         pub async fn cmd_long_term_key_request_reply(
@@ -638,12 +959,12 @@ pub mod asynch {
             Self: Sized,
         {
             log::trace!("before, key = {:x}, handle = {:x}", ltk, handle);
-            self.write_bytes(
+            self.write_command(
                 Command::LeLongTermKeyRequestReply { handle, ltk }
                     .encode()
                     .as_slice(),
             )
-            .await;
+            .await?;
             log::trace!("done writing command");
             let res = self
                 .wait_for_command_complete(LE_OGF, LONG_TERM_KEY_REQUEST_REPLY_OCF)
@@ -658,8 +979,8 @@ pub mod asynch {
         where
             Self: Sized,
         {
-            self.write_bytes(Command::ReadBrAddr.encode().as_slice())
-                .await;
+            self.write_command(Command::ReadBrAddr.encode().as_slice())
+                .await?;
             let res = self
                 .wait_for_command_complete(INFORMATIONAL_OGF, READ_BD_ADDR_OCF)
                 .await?
@@ -670,8 +991,156 @@ pub mod asynch {
                     opcode: _,
                     data,
                 } => Ok(data.as_slice()[1..][..6].try_into().unwrap()),
-                _ => Err(Error::Failed(0)),
+                _ => Err(Error::Truncated),
+            }
+        }
+
+        pub async fn cmd_read_buffer_size(&mut self) -> Result<AclBufferSize, Error>
+        where
+            Self: Sized,
+        {
+            self.write_command(Command::ReadBufferSize.encode().as_slice())
+                .await?;
+            let res = self
+                .wait_for_command_complete(INFORMATIONAL_OGF, READ_BUFFER_SIZE_OCF)
+                .await?
+                .check_command_completed()?;
+            decode_read_buffer_size(res)
+        }
+
+        pub async fn cmd_le_read_buffer_size(&mut self) -> Result<AclBufferSize, Error>
+        where
+            Self: Sized,
+        {
+            self.write_command(Command::LeReadBufferSize.encode().as_slice())
+                .await?;
+            let res = self
+                .wait_for_command_complete(LE_OGF, LE_READ_BUFFER_SIZE_OCF)
+                .await?
+                .check_command_completed()?;
+            decode_le_read_buffer_size(res)
+        }
+
+        /// Sends an L2CAP PDU to the connection `handle`.
+        ///
+        /// The PDU is split into ACL packets fitting the controller's buffers. Each packet waits
+        /// until the controller has a free buffer, packets received meanwhile are returned by the
+        /// following calls to [`Ble::poll`].
+        pub async fn write_acl(&mut self, handle: u16, pdu: Data<L2CAP_PDU_LEN>) -> Result<(), Error>
+        where
+            Self: Sized,
+        {
+            let mut boundary_flag = BoundaryFlag::FirstAutoFlushable;
+            let fragment_len = self.acl_flow.fragment_len().min(ACL_DATA_LEN);
+            for fragment in pdu.as_slice().chunks(fragment_len) {
+                self.wait_for_acl_buffer(handle).await?;
+
+                let packet = AclPacket::encode(
+                    handle,
+                    boundary_flag,
+                    HostBroadcastFlag::NoBroadcast,
+                    Data::<ACL_DATA_LEN>::new(fragment),
+                );
+                log::trace!("writing {:x?}", packet.as_slice());
+                self.write_bytes(packet.as_slice()).await?;
+                boundary_flag = BoundaryFlag::Continuing;
+            }
+
+            Ok(())
+        }
+
+        async fn wait_for_acl_buffer(&mut self, handle: u16) -> Result<(), Error>
+        where
+            Self: Sized,
+        {
+            if self.acl_flow.try_acquire(handle) {
+                return Ok(());
+            }
+
+            log::debug!("Waiting for a free ACL buffer");
+            let timeout_at = self.millis() + TIMEOUT_MILLIS;
+            loop {
+                if !self.poll_pending(timeout_at).await? {
+                    return Err(Error::Timeout);
+                }
+
+                if self.acl_flow.try_acquire(handle) {
+                    return Ok(());
+                }
+            }
+        }
+
+        /// Writes an encoded command once the controller is able to accept it
+        pub(crate) async fn write_command(&mut self, bytes: &[u8]) -> Result<(), Error>
+        where
+            Self: Sized,
+        {
+            if self.command_credits == 0 {
+                log::debug!("Waiting for the controller to accept commands");
+                let timeout_at = self.millis() + TIMEOUT_MILLIS;
+                while self.command_credits == 0 {
+                    if !self.poll_pending(timeout_at).await? {
+                        return Err(self.command_timed_out());
+                    }
+                }
             }
+
+            self.command_credits -= 1;
+            self.write_bytes(bytes).await?;
+            Ok(())
+        }
+
+        /// Polls once, keeping anything received for later calls to [`Ble::poll`]
+        ///
+        /// Returns `false` once `deadline` passed without anything received.
+        async fn poll_pending(&mut self, deadline: u64) -> Result<bool, Error>
+        where
+            Self: Sized,
+        {
+            // whatever isn't read yet waits in the transport instead of getting lost
+            if self.pending.is_full() {
+                return Err(Error::PendingFull);
+            }
+
+            match self.poll_hci(Some(deadline)).await {
+                Ok(Some(res)) => {
+                    self.keep_pending(res)?;
+                    Ok(true)
+                }
+                Ok(None) => Ok(false),
+                Err(err @ (Error::UnknownPacketType(_) | Error::Truncated)) => {
+                    log::warn!("Error while waiting for the controller: {:?}", err);
+                    Ok(true)
+                }
+                Err(err) => Err(err),
+            }
+        }
+
+        /// Executes a [`bt_hci`] command answered by Command Complete and returns its return parameters
+        pub async fn exec<C: SyncCmd>(&mut self, cmd: &C) -> Result<C::Return, Error>
+        where
+            Self: Sized,
+        {
+            self.write_command(encode_hci_cmd(cmd)?.as_slice()).await?;
+            let res = self
+                .wait_for_command_event(C::OPCODE.to_raw())
+                .await?
+                .check_command_completed()?;
+            decode_return_params::<C>(res)
+        }
+
+        /// Executes a [`bt_hci`] command answered by Command Status
+        ///
+        /// The outcome is reported later by an event of its own, e.g. Disconnection Complete.
+        pub async fn exec_async_cmd<C: AsyncCmd>(&mut self, cmd: &C) -> Result<(), Error>
+        where
+            Self: Sized,
+        {
+            self.write_command(encode_hci_cmd(cmd)?.as_slice()).await?;
+            self.wait_for_command_event(C::OPCODE.to_raw())
+                .await?
+                .check_command_completed()?;
+            Ok(())
         }
 
         pub(crate) async fn wait_for_command_complete(
@@ -679,119 +1148,557 @@ pub mod asynch {
             ogf: u8,
             ocf: u16,
         ) -> Result<EventType, Error>
+        where
+            Self: Sized,
+        {
+            self.wait_for_command_event(opcode(ogf, ocf)).await
+        }
+
+        /// Waits for the Command Complete or Command Status event of `code`
+        ///
+        /// A failing command might answer with Command Status even if it usually completes with
+        /// Command Complete, so both are accepted. Everything else received meanwhile is returned by
+        /// later calls to [`Ble::poll`].
+        pub(crate) async fn wait_for_command_event(&mut self, code: u16) -> Result<EventType, Error>
         where
             Self: Sized,
         {
             let timeout_at = self.millis() + TIMEOUT_MILLIS;
             loop {
-                let res = self.poll().await;
-
-                match res {
-                    Some(PollResult::Event(event)) => match event {
-                        EventType::CommandComplete { opcode: code, .. }
-                            if code == opcode(ogf, ocf) =>
-                        {
-                            return Ok(event);
-                        }
-                        _ => (),
-                    },
-                    _ => (),
+                // anything else received in the meantime is kept for `poll`
+                if self.pending.is_full() {
+                    return Err(Error::PendingFull);
                 }
 
-                if self.millis() > timeout_at {
-                    return Err(Error::Timeout);
+                let res = match self.poll_hci(Some(timeout_at)).await {
+                    Ok(Some(res)) => res,
+                    Ok(None) => {
+                        return Err(self.command_timed_out());
+                    }
+                    Err(err @ (Error::UnknownPacketType(_) | Error::Truncated)) => {
+                        log::warn!("Error while waiting for command complete: {:?}", err);
+                        continue;
+                    }
+                    Err(err) => return Err(err),
+                };
+                log::debug!("polled while waiting {:?}", res);
+
+                match res {
+                    PollResult::Event(
+                        event @ (EventType::CommandComplete { opcode, .. }
+                        | EventType::CommandStatus { opcode, .. }),
+                    ) if opcode == code => return Ok(event),
+                    res => self.keep_pending(res)?,
                 }
             }
         }
 
-        pub async fn poll(&mut self) -> Option<PollResult>
+        /// Polls the controller for the next packet
+        ///
+        /// The sync version returns `Ok(None)` if nothing is available, the async version waits
+        /// for the next packet. Both give up with [`Error::Timeout`] if the controller stalls in
+        /// the middle of a packet.
+        ///
+        /// The async version is cancel safe as long as the transport's `read` is: a packet read
+        /// half-way when the future is dropped is completed by the next call.
+        pub async fn poll(&mut self) -> Result<Option<PollResult>, Error>
         where
             Self: Sized,
         {
-            // poll & process input
-            let packet_type = {
-                let mut buffer = [0u8];
-                self.hci.borrow_mut().read(&mut buffer).await.unwrap();
-                Some(buffer[0])
-            };
+            if let Some(res) = self.pending.pop_front() {
+                return Ok(Some(res));
+            }
 
-            match packet_type {
-                Some(packet_type) => match packet_type {
-                    PACKET_TYPE_COMMAND => {}
-                    PACKET_TYPE_ASYNC_DATA => {
-                        let mut acl_packet =
-                            AclPacket::async_read(&mut *self.hci.borrow_mut()).await;
-
-                        let wanted =
-                            u16::from_le_bytes(acl_packet.data.as_slice()[..2].try_into().unwrap())
-                                as usize;
-
-                        // somewhat dirty way to handle re-assembling fragmented packets
-                        loop {
-                            log::debug!("Wanted = {}, actual = {}", wanted, acl_packet.data.len());
-
-                            if wanted == acl_packet.data.len() - 4 {
-                                break;
-                            }
-
-                            log::debug!("Need more!");
-                            let mut buffer = [0u8; 1];
-                            (&mut *self.hci.borrow_mut())
-                                .read(&mut buffer)
-                                .await
-                                .unwrap();
-                            if buffer[0] != PACKET_TYPE_ASYNC_DATA {
-                                log::error!("Expected async data");
-                            }
-
-                            let next_acl_packet =
-                                AclPacket::async_read(&mut *self.hci.borrow_mut()).await;
-                            acl_packet.data.append(next_acl_packet.data.as_slice());
-                        }
+            self.poll_hci(None).await
+        }
 
-                        return Some(PollResult::AsyncData(acl_packet));
-                    }
-                    PACKET_TYPE_EVENT => {
-                        let event = EventType::async_read(&mut *self.hci.borrow_mut()).await;
-                        return Some(PollResult::Event(event));
-                    }
-                    _ => {
-                        // this is an serious error
-                        panic!("Unknown packet type {}", packet_type);
+        /// Keeps `res` for a later call to [`Ble::poll`]
+        ///
+        /// Number Of Completed Packets events are dropped, their credits were counted already.
+        fn keep_pending(&mut self, res: PollResult) -> Result<(), Error> {
+            if let PollResult::Event(EventType::NumberOfCompletedPackets { .. }) = res {
+                return Ok(());
+            }
+
+            self.pending.push_back(res).map_err(|_| Error::PendingFull)
+        }
+
+        fn reassemble(&mut self, acl_packet: AclPacket) -> Option<PollResult> {
+            match self.reassembler.push(acl_packet) {
+                Ok(packet) => packet.map(PollResult::AsyncData),
+                Err(err) => {
+                    log::warn!("Dropping ACL data: {:?}", err);
+                    None
+                }
+            }
+        }
+
+        /// Counts a command the controller didn't answer or accept in time
+        fn command_timed_out(&mut self) -> Error {
+            self.timeouts = self.timeouts.saturating_add(1);
+            if self.timeouts_before_reset != 0 && self.timeouts >= self.timeouts_before_reset {
+                log::warn!("The controller stopped answering commands, it needs a reset");
+                self.needs_reset = true;
+            }
+            Error::Timeout
+        }
+
+        fn track_event(&mut self, event: &EventType) {
+            match event {
+                EventType::DisconnectComplete { handle, .. } => {
+                    self.reassembler.discard(*handle);
+                    self.acl_flow.disconnected(*handle);
+                }
+                EventType::CommandComplete { num_packets, .. }
+                | EventType::CommandStatus { num_packets, .. } => {
+                    self.command_credits = *num_packets;
+                    self.timeouts = 0;
+                }
+                EventType::NumberOfCompletedPackets { completed_packets } => {
+                    for (handle, count) in completed_packets {
+                        self.acl_flow.complete(*handle, *count);
                     }
-                },
-                None => {}
+                }
+                EventType::HardwareError { code } => {
+                    log::warn!("Hardware error {:#04x}, the controller needs a reset", code);
+                    self.needs_reset = true;
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Fills `buf`, giving up when the controller stalls for longer than `READ_TIMEOUT_MILLIS`.
+pub(crate) fn read_exact(connector: &dyn HciConnection, buf: &mut [u8]) -> Result<(), Error> {
+    let mut filled = 0;
+    let mut timeout_at = None;
+    while filled < buf.len() {
+        let len = connector.read(&mut buf[filled..])?;
+        if len > 0 {
+            filled += len;
+            timeout_at = None;
+            continue;
+        }
+
+        let now = connector.millis();
+        match timeout_at {
+            None => timeout_at = Some(now + READ_TIMEOUT_MILLIS),
+            Some(timeout_at) if now > timeout_at => return Err(Error::Timeout),
+            Some(_) => (),
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) fn decode_return_params<C: SyncCmd>(event: EventType) -> Result<C::Return, Error> {
+    match event {
+        // the status was checked already
+        EventType::CommandComplete { data, .. } if data.len() >= 1 => {
+            let (params, _) = C::Return::from_hci_bytes(&data.as_slice()[1..])?;
+            Ok(params)
+        }
+        // commands without return parameters may be answered by Command Status
+        EventType::CommandStatus { .. } => {
+            let (params, _) = C::Return::from_hci_bytes(&[])?;
+            Ok(params)
+        }
+        _ => Err(Error::Truncated),
+    }
+}
+
+fn decode_read_buffer_size(event: EventType) -> Result<AclBufferSize, Error> {
+    match event {
+        EventType::CommandComplete { data, .. } if data.len() >= 8 => {
+            let data = data.as_slice();
+            let buffer_size = AclBufferSize {
+                packet_len: u16::from_le_bytes([data[1], data[2]]),
+                packet_count: u16::from_le_bytes([data[4], data[5]]),
+            };
+            if buffer_size.packet_len == 0 || buffer_size.packet_count == 0 {
+                return Err(Error::InvalidValue);
+            }
+            Ok(buffer_size)
+        }
+        _ => Err(Error::Truncated),
+    }
+}
+
+fn decode_le_read_buffer_size(event: EventType) -> Result<AclBufferSize, Error> {
+    match event {
+        EventType::CommandComplete { data, .. } if data.len() >= 4 => {
+            let data = data.as_slice();
+            let buffer_size = AclBufferSize {
+                packet_len: u16::from_le_bytes([data[1], data[2]]),
+                packet_count: data[3] as u16,
+            };
+            // a length of zero means the buffers are shared with BR/EDR
+            if buffer_size.packet_len != 0 && buffer_size.packet_count == 0 {
+                return Err(Error::InvalidValue);
+            }
+            Ok(buffer_size)
+        }
+        _ => Err(Error::Truncated),
+    }
+}
+
+impl<const N: usize> Data<N> {
+    fn read(connector: &dyn HciConnection, len: usize) -> Result<Self, Error> {
+        let mut data = Self::default();
+        read_exact(connector, data.data.get_mut(..len).ok_or(Error::Truncated)?)?;
+        data.len = len;
+        Ok(data)
+    }
+
+    /// Reads `len` bytes but only keeps as many as fit into the buffer.
+    pub(crate) fn read_truncating(
+        connector: &dyn HciConnection,
+        len: usize,
+    ) -> Result<Self, Error> {
+        let keep = len.min(N);
+        let data = Self::read(connector, keep)?;
+
+        let mut skip = [0u8; 32];
+        let mut remaining = len - keep;
+        while remaining > 0 {
+            let len = remaining.min(skip.len());
+            read_exact(connector, &mut skip[..len])?;
+            remaining -= len;
+        }
+
+        if len > keep {
+            log::warn!("Dropped {} bytes of an oversized packet", len - keep);
+        }
+
+        Ok(data)
+    }
+}
+
+/// Transport to the controller, moving bytes in slices
+pub trait HciConnection {
+    /// Reads the bytes available right now into `buf` and returns how many there were
+    fn read(&self, buf: &mut [u8]) -> Result<usize, embedded_io_blocking::ErrorKind>;
+
+    /// Writes all of `data`, it may be held back until [`HciConnection::flush`]
+    fn write(&self, data: &[u8]) -> Result<(), embedded_io_blocking::ErrorKind>;
+
+    fn flush(&self) -> Result<(), embedded_io_blocking::ErrorKind>;
+
+    fn millis(&self) -> u64;
+}
+
+/// A transport moving one byte per call
+///
+/// Every implementation is also an [`HciConnection`].
+pub trait ByteHciConnection {
+    fn read(&self) -> Option<u8>;
+
+    /// Like [`ByteHciConnection::read`] but reports errors of the transport.
+    fn try_read(&self) -> Result<Option<u8>, embedded_io_blocking::ErrorKind> {
+        Ok(self.read())
+    }
+
+    fn write(&self, data: u8);
+
+    fn millis(&self) -> u64;
+}
+
+impl<T> HciConnection for T
+where
+    T: ByteHciConnection,
+{
+    fn read(&self, buf: &mut [u8]) -> Result<usize, embedded_io_blocking::ErrorKind> {
+        for (len, byte) in buf.iter_mut().enumerate() {
+            match self.try_read()? {
+                Some(value) => *byte = value,
+                None => return Ok(len),
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn write(&self, data: &[u8]) -> Result<(), embedded_io_blocking::ErrorKind> {
+        for byte in data {
+            ByteHciConnection::write(self, *byte);
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), embedded_io_blocking::ErrorKind> {
+        Ok(())
+    }
+
+    fn millis(&self) -> u64 {
+        ByteHciConnection::millis(self)
+    }
+}
+
+/// Bytes received from the transport but not yet handed to the stack
+struct RxBuffer {
+    data: [u8; HCI_RX_BUFFER_SIZE],
+    pos: usize,
+    len: usize,
+}
+
+/// An [`HciConnection`] over an `embedded-io` transport, reading ahead into a small buffer
+pub struct HciConnector<T, K = fn() -> u64>
+where
+    T: Read + Write,
+{
+    hci: RefCell<T>,
+    clock: K,
+    rx: RefCell<RxBuffer>,
+}
+
+impl<T, K> HciConnector<T, K>
+where
+    T: Read + Write,
+    K: Clock,
+{
+    pub fn new(hci: T, clock: K) -> HciConnector<T, K> {
+        HciConnector {
+            hci: RefCell::new(hci),
+            clock,
+            rx: RefCell::new(RxBuffer {
+                data: [0u8; HCI_RX_BUFFER_SIZE],
+                pos: 0,
+                len: 0,
+            }),
+        }
+    }
+
+    fn read_transport(&self, buf: &mut [u8]) -> Result<usize, embedded_io_blocking::ErrorKind> {
+        match self.hci.borrow_mut().read(buf) {
+            Ok(len) => Ok(len),
+            // nothing to read yet, timeouts are handled by the stack
+            Err(err) if err.kind() == embedded_io_blocking::ErrorKind::TimedOut => Ok(0),
+            Err(err) => Err(err.kind()),
+        }
+    }
+}
+
+impl<T, K> HciConnection for HciConnector<T, K>
+where
+    T: Read + Write,
+    K: Clock,
+{
+    fn read(&self, buf: &mut [u8]) -> Result<usize, embedded_io_blocking::ErrorKind> {
+        let mut rx = self.rx.borrow_mut();
+        if rx.pos == rx.len {
+            if buf.len() >= HCI_RX_BUFFER_SIZE {
+                return self.read_transport(buf);
             }
 
-            None
+            rx.len = self.read_transport(&mut rx.data)?;
+            rx.pos = 0;
         }
 
-        pub(crate) async fn write_bytes(&mut self, bytes: &[u8]) {
-            self.hci.borrow_mut().write(bytes).await.unwrap();
+        let len = buf.len().min(rx.len - rx.pos);
+        buf[..len].copy_from_slice(&rx.data[rx.pos..][..len]);
+        rx.pos += len;
+        Ok(len)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<(), embedded_io_blocking::ErrorKind> {
+        self.hci
+            .borrow_mut()
+            .write_all(data)
+            .map_err(|err| err.kind())
+    }
+
+    fn flush(&self) -> Result<(), embedded_io_blocking::ErrorKind> {
+        self.hci.borrow_mut().flush().map_err(|err| err.kind())
+    }
+
+    fn millis(&self) -> u64 {
+        self.clock.now_millis()
+    }
+}
+
+#[cfg(feature = "async")]
+pub mod asynch {
+    use futures::future::{select, Either};
+    use futures::pin_mut;
+
+    use super::*;
+    use crate::clock::AsyncClock;
+    use crate::h4::{H4Reader, RawPacket};
+
+    pub struct Ble<T, K = fn() -> u64, const P: usize = PENDING_POLL_RESULTS>
+    where
+        T: embedded_io_async::Read + embedded_io_async::Write,
+    {
+        pub(crate) hci: T,
+        pub(crate) clock: K,
+        pub(crate) reader: H4Reader,
+        pub(crate) reassembler: L2capReassembler<MAX_CONNECTIONS>,
+        pub(crate) acl_flow: AclFlowControl<MAX_CONNECTIONS>,
+        pub(crate) command_credits: u8,
+        pub(crate) pending: heapless::Deque<PollResult, P>,
+        pub(crate) controller_info: Option<ControllerInfo>,
+        pub(crate) advertising: AdvertisingState,
+        pub(crate) needs_reset: bool,
+        pub(crate) timeouts: u8,
+        pub(crate) timeouts_before_reset: u8,
+    }
+
+    impl<T, K> Ble<T, K>
+    where
+        T: embedded_io_async::Read + embedded_io_async::Write,
+        K: AsyncClock,
+    {
+        pub fn new(hci: T, clock: K) -> Ble<T, K> {
+            Ble::with_pending_capacity(hci, clock)
         }
     }
 
-    impl Data {
-        pub(crate) async fn async_read<T>(mut connector: T, len: usize) -> Self
+    impl<T, K, const P: usize> Ble<T, K, P>
+    where
+        T: embedded_io_async::Read + embedded_io_async::Write,
+        K: AsyncClock,
+    {
+        /// Creates an instance keeping up to `P` received packets while waiting for the
+        /// controller
+        ///
+        /// Each of them takes `size_of::<PollResult>()` bytes, [`Ble::new`] keeps
+        /// [`PENDING_POLL_RESULTS`].
+        pub fn with_pending_capacity(hci: T, clock: K) -> Ble<T, K, P> {
+            Ble {
+                hci,
+                clock,
+                reader: H4Reader::new(),
+                reassembler: L2capReassembler::new(MAX_SDU_SIZE),
+                acl_flow: AclFlowControl::new(),
+                // the controller accepts one command until it reports otherwise
+                command_credits: 1,
+                pending: heapless::Deque::new(),
+                controller_info: None,
+                advertising: AdvertisingState::default(),
+                needs_reset: false,
+                timeouts: 0,
+                timeouts_before_reset: DEFAULT_TIMEOUTS_BEFORE_RESET,
+            }
+        }
+
+        /// Number of bytes discarded while resynchronising to the HCI byte stream
+        pub fn dropped_bytes(&self) -> usize {
+            self.reader.dropped_bytes()
+        }
+
+        pub(crate) fn millis(&self) -> u64 {
+            self.clock.now_millis()
+        }
+
+        /// Reads the next packet
+        ///
+        /// Without a `deadline` it waits for the next packet. With one it waits until there is
+        /// something to return, `Ok(None)` means the deadline passed.
+        pub(crate) async fn poll_hci(
+            &mut self,
+            deadline: Option<u64>,
+        ) -> Result<Option<PollResult>, Error>
         where
-            T: embedded_io_async::Read,
+            Self: Sized,
         {
-            let mut idx = 0;
-            let mut data = [0u8; 256];
             loop {
-                let l = connector.read(&mut data[idx..][..len]).await.unwrap();
-                idx += l;
+                let packet = match deadline {
+                    None => read_packet(&mut self.hci, &mut self.reader, &self.clock).await?,
+                    Some(deadline) => {
+                        // reading is cancel safe, a packet cut short by the deadline is completed
+                        // later
+                        let read = read_packet(&mut self.hci, &mut self.reader, &self.clock);
+                        let timeout = self.clock.wait_until(deadline);
+                        pin_mut!(read);
+                        pin_mut!(timeout);
+
+                        match select(read, timeout).await {
+                            Either::Left((res, _)) => res?,
+                            Either::Right(_) => return Ok(None),
+                        }
+                    }
+                };
 
-                if idx >= len {
-                    break;
-                }
+                let res = match packet.packet_type {
+                    PacketType::AclData => {
+                        let acl_packet = AclPacket::from_raw(&packet)?;
+                        self.reassemble(acl_packet)
+                    }
+                    PacketType::SyncData => {
+                        Some(PollResult::SyncData(ScoPacket::from_raw(&packet)))
+                    }
+                    PacketType::Event => {
+                        let event = EventType::from_raw(&packet)?;
+                        self.track_event(&event);
+                        Some(PollResult::Event(event))
+                    }
+                    PacketType::IsoData => Some(PollResult::IsoData(IsoPacket::from_raw(&packet))),
+                    PacketType::Command => None,
+                };
 
-                // TODO timeout?
+                if res.is_some() || deadline.is_none() {
+                    return Ok(res);
+                }
             }
+        }
+
+        pub(crate) async fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+            self.hci.write_all(bytes).await.map_err(|err| err.kind())?;
+            self.hci.flush().await.map_err(|err| err.kind())?;
+            Ok(())
+        }
+    }
 
-            let mut data = Self::new(&data);
-            data.len = len;
-            data
+    /// Reads from the transport until `reader` completed a packet
+    ///
+    /// Each `read` goes straight into the reader, so dropping this future loses nothing. A
+    /// packet the controller stops sending for longer than `READ_TIMEOUT_MILLIS` is discarded.
+    async fn read_packet<T, K>(
+        hci: &mut T,
+        reader: &mut H4Reader,
+        clock: &K,
+    ) -> Result<RawPacket, Error>
+    where
+        T: embedded_io_async::Read,
+        K: AsyncClock,
+    {
+        loop {
+            let in_packet = reader.in_packet();
+            let res = {
+                let read = hci.read(reader.buffer());
+                if in_packet {
+                    let timeout = clock.wait_until(clock.now_millis() + READ_TIMEOUT_MILLIS);
+                    pin_mut!(read);
+                    pin_mut!(timeout);
+
+                    match select(read, timeout).await {
+                        Either::Left((res, _)) => Some(res),
+                        Either::Right(_) => None,
+                    }
+                } else {
+                    Some(read.await)
+                }
+            };
+
+            let len = match res {
+                None => {
+                    reader.discard_packet();
+                    return Err(Error::Timeout);
+                }
+                Some(Ok(0)) => {
+                    reader.discard_packet();
+                    return Err(Error::Truncated);
+                }
+                Some(Ok(len)) => len,
+                Some(Err(err)) => {
+                    reader.discard_packet();
+                    return Err(err.kind().into());
+                }
+            };
+
+            if let Some(packet) = reader.advance(len)? {
+                return Ok(packet);
+            }
         }
     }
 }
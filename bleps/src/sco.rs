@@ -0,0 +1,60 @@
+use crate::{h4::RawPacket, read_exact, Data, Error, HciConnection};
+
+/// Synchronous (SCO) data packet sent by the controller ([Vol 4] Part E, Section 5.4.3).
+#[derive(Debug, Clone, Copy)]
+pub struct ScoPacket {
+    pub handle: u16,
+    pub packet_status: ScoPacketStatus,
+    pub data: Data,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ScoPacketStatus {
+    CorrectlyReceived,
+    PossiblyInvalid,
+    NoData,
+    PartiallyLost,
+}
+
+impl ScoPacket {
+    pub fn read(connector: &dyn HciConnection) -> Result<Self, Error> {
+        let mut header = [0u8; 3];
+        read_exact(connector, &mut header)?;
+
+        let data = Data::read(connector, header[2] as usize)?;
+        Ok(Self::from_parts(header, data))
+    }
+
+    /// Decodes a packet collected by [`crate::h4::H4Reader`].
+    pub fn from_raw(packet: &RawPacket) -> Self {
+        Self::from_parts(
+            [packet.header[0], packet.header[1], packet.header[2]],
+            packet.data,
+        )
+    }
+
+    fn from_parts(header: [u8; 3], data: Data) -> Self {
+        let (packet_status, handle) = Self::decode_raw_handle([header[0], header[1]]);
+
+        Self {
+            handle,
+            packet_status,
+            data,
+        }
+    }
+
+    fn decode_raw_handle(raw_handle_buffer: [u8; 2]) -> (ScoPacketStatus, u16) {
+        let raw_handle = u16::from_le_bytes(raw_handle_buffer);
+
+        let packet_status = match (raw_handle & 0b0011000000000000) >> 12 {
+            0b00 => ScoPacketStatus::CorrectlyReceived,
+            0b01 => ScoPacketStatus::PossiblyInvalid,
+            0b10 => ScoPacketStatus::NoData,
+            _ => ScoPacketStatus::PartiallyLost,
+        };
+
+        let handle = raw_handle & 0b111111111111;
+
+        (packet_status, handle)
+    }
+}
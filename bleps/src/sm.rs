@@ -7,8 +7,8 @@ use crate::{
     acl::{AclPacket, BoundaryFlag, HostBroadcastFlag},
     attribute_server::AttributeServerError,
     crypto::{Check, Confirm, DHKey, IoCap, MacKey, Nonce, PublicKey, SecretKey},
-    l2cap::L2capPacket,
-    Addr, Ble, Data,
+    l2cap::{L2capPacket, L2CAP_PDU_LEN, SM_PDU_LEN},
+    Addr, Ble, Data, Error,
 };
 
 #[derive(Debug, Clone, Copy)]
@@ -31,6 +31,38 @@ pub enum SecurityManagerError {
     KeyRejected,
 }
 
+impl core::fmt::Display for SecurityManagerError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let reason = match self {
+            SecurityManagerError::PasskeyEntryFailed => "passkey entry failed",
+            SecurityManagerError::OobNotAvailable => "OOB not available",
+            SecurityManagerError::AuthenticationRequirements => "authentication requirements",
+            SecurityManagerError::ConfirmValueFailed => "confirm value failed",
+            SecurityManagerError::PairingNotSupported => "pairing not supported",
+            SecurityManagerError::EncryptionKeySize => "encryption key size",
+            SecurityManagerError::CommandNotSupported => "command not supported",
+            SecurityManagerError::UnspecifiedReason => "unspecified reason",
+            SecurityManagerError::RepeatedAttempts => "repeated attempts",
+            SecurityManagerError::InvalidParameters => "invalid parameters",
+            SecurityManagerError::DHKeyCheckFailed => "DHKey check failed",
+            SecurityManagerError::NumericComparisonFailed => "numeric comparison failed",
+            SecurityManagerError::BrEdrPairingInProgress => "BR/EDR pairing in progress",
+            SecurityManagerError::GenerationNotAllowed => {
+                "cross-transport key derivation not allowed"
+            }
+            SecurityManagerError::KeyRejected => "key rejected",
+        };
+        write!(f, "{} ({:#04x})", reason, *self as u8)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for SecurityManagerError {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "{}", defmt::Debug2Format(self))
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum IoCapability {
@@ -97,11 +129,29 @@ pub struct SecurityManager<'a, B, R: CryptoRng> {
 
 pub trait BleWriter {
     fn write_bytes(&mut self, bytes: &[u8]);
+
+    /// Sends an L2CAP PDU, by default as a single ACL packet
+    fn write_acl(&mut self, handle: u16, pdu: Data<L2CAP_PDU_LEN>) -> Result<(), Error> {
+        let res = AclPacket::encode(
+            handle,
+            BoundaryFlag::FirstAutoFlushable,
+            HostBroadcastFlag::NoBroadcast,
+            pdu,
+        );
+        self.write_bytes(res.as_slice());
+        Ok(())
+    }
 }
 
-impl<'a> BleWriter for Ble<'a> {
+impl<'a, const P: usize> BleWriter for Ble<'a, P> {
     fn write_bytes(&mut self, bytes: &[u8]) {
-        self.write_bytes(bytes);
+        if let Err(err) = self.write_bytes(bytes) {
+            log::warn!("Failed to write to the controller: {:?}", err);
+        }
+    }
+
+    fn write_acl(&mut self, handle: u16, pdu: Data<L2CAP_PDU_LEN>) -> Result<(), Error> {
+        self.write_acl(handle, pdu)
     }
 }
 
@@ -156,16 +206,41 @@ pub struct AsyncSecurityManager<'a, B, R: CryptoRng> {
 
 #[cfg(feature = "async")]
 pub trait AsyncBleWriter {
-    async fn write_bytes(&mut self, bytes: &[u8]);
+    fn write_bytes(&mut self, bytes: &[u8]) -> impl core::future::Future<Output = ()>;
+
+    /// Sends an L2CAP PDU, by default as a single ACL packet
+    fn write_acl(
+        &mut self,
+        handle: u16,
+        pdu: Data<L2CAP_PDU_LEN>,
+    ) -> impl core::future::Future<Output = Result<(), Error>> {
+        async move {
+            let res = AclPacket::encode(
+                handle,
+                BoundaryFlag::FirstAutoFlushable,
+                HostBroadcastFlag::NoBroadcast,
+                pdu,
+            );
+            self.write_bytes(res.as_slice()).await;
+            Ok(())
+        }
+    }
 }
 
 #[cfg(feature = "async")]
-impl<T> AsyncBleWriter for crate::asynch::Ble<T>
+impl<T, K, const P: usize> AsyncBleWriter for crate::asynch::Ble<T, K, P>
 where
     T: embedded_io_async::Read + embedded_io_async::Write,
+    K: crate::clock::AsyncClock,
 {
     async fn write_bytes(&mut self, bytes: &[u8]) {
-        self.write_bytes(bytes).await
+        if let Err(err) = self.write_bytes(bytes).await {
+            log::warn!("Failed to write to the controller: {:?}", err);
+        }
+    }
+
+    async fn write_acl(&mut self, handle: u16, pdu: Data<L2CAP_PDU_LEN>) -> Result<(), Error> {
+        self.write_acl(handle, pdu).await
     }
 }
 
@@ -206,11 +281,23 @@ bleps_dedup::dedup! {
 impl<'a, B, R> SYNC SecurityManager<'a, B, R> where B: BleWriter, R: CryptoRng + RngCore
 impl<'a, B, R> ASYNC AsyncSecurityManager<'a, B, R> where B: AsyncBleWriter, R: CryptoRng + RngCore
  {
-    pub(crate) async fn handle(&mut self, ble: &mut B, src_handle: u16, payload: crate::Data, pin_callback: &mut Option<&mut dyn FnMut(u32)>) -> Result<(), AttributeServerError> {
-        log::debug!("SM packet {:02x?}", payload.as_slice());
-
-        let data = &payload.as_slice()[1..];
-        let command = payload.as_slice()[0];
+    pub(crate) async fn handle(&mut self, ble: &mut B, src_handle: u16, payload: &[u8], pin_callback: &mut Option<&mut dyn FnMut(u32)>) -> Result<(), AttributeServerError> {
+        log::debug!("SM packet {:02x?}", payload);
+
+        let Some((&command, data)) = payload.split_first() else {
+            return Err(self.report_error(ble, src_handle, SecurityManagerError::InvalidParameters).await);
+        };
+
+        let valid_len = match command {
+            SM_PAIRING_REQUEST => data.len() == 6,
+            SM_PAIRING_PUBLIC_KEY => data.len() == 64,
+            SM_PAIRING_RANDOM | SM_PAIRING_DHKEY_CHECK => data.len() == 16,
+            _ => true,
+        };
+        if !valid_len {
+            log::warn!("SM command {} with {} bytes of parameters", command, data.len());
+            return Err(self.report_error(ble, src_handle, SecurityManagerError::InvalidParameters).await);
+        }
 
         match command {
             SM_PAIRING_REQUEST => {
@@ -228,8 +315,7 @@ impl<'a, B, R> ASYNC AsyncSecurityManager<'a, B, R> where B: AsyncBleWriter, R:
             _ => {
                 // handle FAILURE
                 log::error!("Unknown SM command {}", command);
-                self.report_error(ble, src_handle, SecurityManagerError::CommandNotSupported).await;
-                return Err(AttributeServerError::SecurityManagerError);
+                return Err(self.report_error(ble, src_handle, SecurityManagerError::CommandNotSupported).await);
             }
         }
 
@@ -277,9 +363,13 @@ impl<'a, B, R> ASYNC AsyncSecurityManager<'a, B, R> where B: AsyncBleWriter, R:
         self.write_sm(ble, src_handle, data).await;
 
         let dh_key = match skb.dh_key(pka) {
-            Some(dh_key) => Ok(dh_key),
-            None => Err(AttributeServerError::SecurityManagerError),
-        }?;
+            Some(dh_key) => dh_key,
+            None => {
+                return Err(self
+                    .report_error(ble, src_handle, SecurityManagerError::DHKeyCheckFailed)
+                    .await)
+            }
+        };
 
         // SUBTLE: The order of these send/recv ops is important. See last
         // paragraph of Section 2.3.5.6.2.
@@ -305,28 +395,23 @@ impl<'a, B, R> ASYNC AsyncSecurityManager<'a, B, R> where B: AsyncBleWriter, R:
         log::debug!("got pairing random {:02x?}", random);
 
         if *&(self.nb).is_none() {
-            self.report_error(ble, src_handle, SecurityManagerError::UnspecifiedReason).await;
-            return Err(AttributeServerError::SecurityManagerError);
+            return Err(self.report_error(ble, src_handle, SecurityManagerError::UnspecifiedReason).await);
         }
 
         if *&(self.pka).is_none() {
-            self.report_error(ble, src_handle, SecurityManagerError::UnspecifiedReason).await;
-            return Err(AttributeServerError::SecurityManagerError);
+            return Err(self.report_error(ble, src_handle, SecurityManagerError::UnspecifiedReason).await);
         }
 
         if *&(self.pkb).is_none() {
-            self.report_error(ble, src_handle, SecurityManagerError::UnspecifiedReason).await;
-            return Err(AttributeServerError::SecurityManagerError);
+            return Err(self.report_error(ble, src_handle, SecurityManagerError::UnspecifiedReason).await);
         }
 
         if *&(self.peer_address).is_none() {
-            self.report_error(ble, src_handle, SecurityManagerError::UnspecifiedReason).await;
-            return Err(AttributeServerError::SecurityManagerError);
+            return Err(self.report_error(ble, src_handle, SecurityManagerError::UnspecifiedReason).await);
         }
 
         if *&(self.local_address).is_none() {
-            self.report_error(ble, src_handle, SecurityManagerError::UnspecifiedReason).await;
-            return Err(AttributeServerError::SecurityManagerError);
+            return Err(self.report_error(ble, src_handle, SecurityManagerError::UnspecifiedReason).await);
         }
 
         let mut data = Data::new(&[SM_PAIRING_RANDOM]);
@@ -376,28 +461,23 @@ impl<'a, B, R> ASYNC AsyncSecurityManager<'a, B, R> where B: AsyncBleWriter, R:
         log::debug!("got dhkey_check {:02x?}", ea);
 
         if *&(self.na).is_none() {
-            self.report_error(ble, src_handle, SecurityManagerError::UnspecifiedReason).await;
-            return Err(AttributeServerError::SecurityManagerError);
+            return Err(self.report_error(ble, src_handle, SecurityManagerError::UnspecifiedReason).await);
         }
 
         if *&(self.nb).is_none() {
-            self.report_error(ble, src_handle, SecurityManagerError::UnspecifiedReason).await;
-            return Err(AttributeServerError::SecurityManagerError);
+            return Err(self.report_error(ble, src_handle, SecurityManagerError::UnspecifiedReason).await);
         }
 
         if *&(self.ioa).is_none() {
-            self.report_error(ble, src_handle, SecurityManagerError::UnspecifiedReason).await;
-            return Err(AttributeServerError::SecurityManagerError);
+            return Err(self.report_error(ble, src_handle, SecurityManagerError::UnspecifiedReason).await);
         }
 
         if *&(self.peer_address).is_none() {
-            self.report_error(ble, src_handle, SecurityManagerError::UnspecifiedReason).await;
-            return Err(AttributeServerError::SecurityManagerError);
+            return Err(self.report_error(ble, src_handle, SecurityManagerError::UnspecifiedReason).await);
         }
 
         if *&(self.local_address).is_none() {
-            self.report_error(ble, src_handle, SecurityManagerError::UnspecifiedReason).await;
-            return Err(AttributeServerError::SecurityManagerError);
+            return Err(self.report_error(ble, src_handle, SecurityManagerError::UnspecifiedReason).await);
         }
 
         let expected = self
@@ -416,6 +496,7 @@ impl<'a, B, R> ASYNC AsyncSecurityManager<'a, B, R> where B: AsyncBleWriter, R:
             .to_le_bytes();
         if ea != expected {
             log::warn!("DH check failed");
+            return Err(self.report_error(ble, src_handle, SecurityManagerError::DHKeyCheckFailed).await);
         }
 
         let mut data = Data::new(&[SM_PAIRING_DHKEY_CHECK]);
@@ -425,27 +506,28 @@ impl<'a, B, R> ASYNC AsyncSecurityManager<'a, B, R> where B: AsyncBleWriter, R:
         Ok(())
     }
 
-    async fn write_sm(&self, ble: &mut B, handle: u16, data: Data) {
+    async fn write_sm(&self, ble: &mut B, handle: u16, data: Data<SM_PDU_LEN>) {
         log::debug!("data {:x?}", data.as_slice());
 
         let res = L2capPacket::encode_sm(data);
         log::trace!("encoded_l2cap {:x?}", res.as_slice());
 
-        let res = AclPacket::encode(
-            handle,
-            BoundaryFlag::FirstAutoFlushable,
-            HostBroadcastFlag::NoBroadcast,
-            res,
-        );
-
-        log::debug!("writing {:02x?}", res.as_slice());
-        ble.write_bytes(res.as_slice()).await;
+        if let Err(err) = ble.write_acl(handle, res).await {
+            log::warn!("Failed to write SM PDU: {:?}", err);
+        }
     }
 
-    async fn report_error(&self, ble: &mut B, src_handle: u16, error: SecurityManagerError) {
+    /// Tells the peer pairing failed, returns the error to bail out with
+    async fn report_error(
+        &self,
+        ble: &mut B,
+        src_handle: u16,
+        error: SecurityManagerError,
+    ) -> AttributeServerError {
         let mut data = Data::new(&[SM_PAIRING_FAILED]);
         data.append(&[error as u8]);
         self.write_sm(ble, src_handle, data).await;
+        AttributeServerError::SecurityManagerError(error)
     }
 }
 }
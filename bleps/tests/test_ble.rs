@@ -1,27 +1,57 @@
-#![feature(assert_matches)]
-
-use std::{assert_matches::assert_matches, cell::RefCell};
+use std::{
+    cell::{Cell, RefCell},
+    time::Duration,
+};
 
 extern crate std;
 
+/// Like the unstable `std::assert_matches!`, so the tests build on stable
+macro_rules! assert_matches {
+    ($expression:expr, $pattern:pat $(if $guard:expr)? $(,)?) => {
+        match $expression {
+            $pattern $(if $guard)? => {}
+            ref res => panic!(
+                "assertion failed: `{:?}` does not match `{}`",
+                res,
+                stringify!($pattern $(if $guard)?)
+            ),
+        }
+    };
+}
+
 use bleps::ad_structure::AdvertisementDataError;
 use bleps::{
-    acl::{AclPacket, BoundaryFlag, ControllerBroadcastFlag, HostBroadcastFlag},
+    acl::{AclBufferSize, AclPacket, BoundaryFlag, ControllerBroadcastFlag, HostBroadcastFlag},
     ad_structure::{
-        create_advertising_data, AdStructure, BR_EDR_NOT_SUPPORTED, LE_GENERAL_DISCOVERABLE,
+        create_advertising_data, parse_advertising_data, split_advertising_data, AdStructure,
+        LeRole, Placement, UuidList, BR_EDR_NOT_SUPPORTED, LE_GENERAL_DISCOVERABLE,
     },
-    att::{Att, AttErrorCode, Uuid, ATT_READ_BY_GROUP_TYPE_REQUEST_OPCODE},
+    att::{Att, AttDecodeError, AttErrorCode, Uuid, ATT_READ_BY_GROUP_TYPE_REQUEST_OPCODE},
     attribute::Attribute,
-    attribute_server::{AttributeServer, CHARACTERISTIC_UUID16, PRIMARY_SERVICE_UUID16},
+    attribute_server::{
+        AttributeServer, NotificationData, WorkResult, CHARACTERISTIC_UUID16,
+        PRIMARY_SERVICE_UUID16,
+    },
+    beacon::{AltBeacon, Beacon, Eddystone, EddystoneUrlError, IBeacon},
     command::{Command, CommandHeader},
-    event::{ErrorCode, EventType},
+    event::{ErrorCode, EventType, Phy, Role},
+    h4::H4Reader,
+    iso::{IsoBoundaryFlag, IsoPacket},
     l2cap::L2capPacket,
-    Ble, Data, HciConnection, PollResult,
+    sco::{ScoPacket, ScoPacketStatus},
+    AdvertisingChannelMapBits, AdvertisingParameters, AdvertisingParametersError, AdvertisingType,
+    Ble, ByteHciConnection, CapacityError, Data, Error, HciConnection, HciConnector,
+    PeerAddressType, PollResult,
+};
+use bt_hci::{
+    cmd::{info::ReadBdAddr, le::LeSetPhy},
+    param::{AllPhys, ConnHandle, CoreSpecificationVersion, PhyMask, PhyOptions},
 };
+use embedded_io_blocking::ErrorKind;
 use p256::elliptic_curve::rand_core::OsRng;
 
 struct TestConnector {
-    to_read: RefCell<[u8; 128]>,
+    to_read: RefCell<[u8; 512]>,
     to_write: RefCell<[u8; 128]>,
     read_idx: RefCell<usize>,
     read_max: RefCell<usize>,
@@ -87,19 +117,23 @@ impl TestConnector {
 }
 
 impl HciConnection for TestConnector {
-    fn read(&self) -> Option<u8> {
-        if self.read_max > self.read_idx {
-            let r = (self.to_read.borrow())[*(self.read_idx.borrow())];
-            *(self.read_idx.borrow_mut()) += 1;
-            Some(r)
-        } else {
-            None
-        }
+    fn read(&self, buf: &mut [u8]) -> Result<usize, ErrorKind> {
+        let from = *(self.read_idx.borrow());
+        let len = buf.len().min(*(self.read_max.borrow()) - from);
+        buf[..len].copy_from_slice(&(self.to_read.borrow())[from..][..len]);
+        *(self.read_idx.borrow_mut()) += len;
+        Ok(len)
     }
 
-    fn write(&self, data: u8) {
-        (self.to_write.borrow_mut())[*(self.write_idx.borrow())] = data;
-        *(self.write_idx.borrow_mut()) += 1;
+    fn write(&self, data: &[u8]) -> Result<(), ErrorKind> {
+        let from = *(self.write_idx.borrow());
+        (self.to_write.borrow_mut())[from..][..data.len()].copy_from_slice(data);
+        *(self.write_idx.borrow_mut()) += data.len();
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), ErrorKind> {
+        Ok(())
     }
 
     fn millis(&self) -> u64 {
@@ -111,7 +145,7 @@ impl HciConnection for TestConnector {
 
 fn connector() -> TestConnector {
     TestConnector {
-        to_read: RefCell::new([0u8; 128]),
+        to_read: RefCell::new([0u8; 512]),
         to_write: RefCell::new([0u8; 128]),
         read_idx: RefCell::new(0),
         read_max: RefCell::new(0),
@@ -125,19 +159,23 @@ fn connector() -> TestConnector {
 fn testing_will_work() {
     let connector = connector();
 
+    let mut buf = [0xffu8; 2];
+
     connector.set_read_max(1);
-    assert_eq!(Some(0), connector.read());
-    assert_eq!(None, connector.read());
+    assert_eq!(Ok(1), connector.read(&mut buf));
+    assert_eq!(buf, [0x00, 0xff]);
+    assert_eq!(Ok(0), connector.read(&mut buf));
 
     connector.set_read_idx(0);
 
-    assert_eq!(Some(0), connector.read());
-    assert_eq!(None, connector.read());
+    assert_eq!(Ok(1), connector.read(&mut buf));
+    assert_eq!(Ok(0), connector.read(&mut buf));
 
-    connector.write(0xff);
+    assert_eq!(Ok(()), connector.write(&[0xff, 0x01]));
 
-    assert_eq!(connector.get_write_idx(), 1);
+    assert_eq!(connector.get_write_idx(), 2);
     assert_eq!(connector.get_to_write_at(0), 0xff);
+    assert_eq!(connector.get_to_write_at(1), 0x01);
 }
 
 #[test]
@@ -147,611 +185,2866 @@ fn parse_event() {
 
     connector.provide_data_to_read(&[0x04, 0x0e, 0x04, 0x05, 0x03, 0x0c, 0x00]);
 
-    let res = ble.poll();
+    let res = ble.poll().unwrap();
 
     assert_matches!(res, Some(PollResult::Event(EventType::CommandComplete { num_packets: 5, opcode: 0x0c03, data})) if data.as_slice() == &[0] );
 
     connector.reset();
 }
 
+/// Answers the commands `Ble::init` sends before reading the buffer size
+fn provide_init_responses(connector: &TestConnector, le_features: [u8; 8], le_states: bool) {
+    connector.provide_data_to_read(&init_responses(le_features, le_states));
+}
+
+/// The events answering `init` up to LE Read Buffer Size
+fn init_responses(le_features: [u8; 8], le_states: bool) -> std::vec::Vec<u8> {
+    let mut responses = std::vec![
+        0x04, 0x0e, 0x04, 0x05, 0x03, 0x0c, 0x00, 0x04, 0x0e, 0x04, 0x05, 0x01, 0x0c, 0x00, //
+        0x04, 0x0e, 0x0c, 0x05, 0x01, 0x10, 0x00, 0x0c, 0x34, 0x12, 0x0c, 0x59, 0x00, 0x78, 0x56,
+    ];
+
+    let mut commands = [0u8; 71];
+    commands[..7].copy_from_slice(&[0x04, 0x0e, 0x44, 0x05, 0x02, 0x10, 0x00]);
+    if le_states {
+        commands[7 + 28] = 0x08;
+    }
+    responses.extend_from_slice(&commands);
+
+    responses.extend_from_slice(&[
+        0x04, 0x0e, 0x0c, 0x05, 0x03, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x60, 0x00, 0x00, 0x00,
+    ]);
+    responses.extend_from_slice(&[0x04, 0x0e, 0x0c, 0x05, 0x03, 0x20, 0x00]);
+    responses.extend_from_slice(&le_features);
+    responses.extend_from_slice(&[0x04, 0x0e, 0x04, 0x05, 0x01, 0x20, 0x00]);
+    responses
+}
+
 #[test]
 fn init_works() {
     let connector = connector();
     let mut ble = Ble::new(&connector);
 
+    provide_init_responses(&connector, [0x21, 0x01, 0, 0, 0, 0, 0, 0], true);
     connector.provide_data_to_read(&[
-        0x04, 0x0e, 0x04, 0x05, 0x03, 0x0c, 0x00, 0x04, 0x0e, 0x04, 0x05, 0x01, 0x0c, 0x00,
+        0x04, 0x0e, 0x07, 0x05, 0x02, 0x20, 0x00, 0x1b, 0x00, 0x02, //
+        0x04, 0x0e, 0x0c, 0x05, 0x1c, 0x20, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0x03, 0x00, 0x00,
     ]);
 
     let res = ble.init();
 
     assert_matches!(res, Ok(()));
+    assert_eq!(
+        ble.acl_buffer_size(),
+        Some(AclBufferSize {
+            packet_len: 27,
+            packet_count: 2
+        })
+    );
 
-    assert_eq!(connector.get_write_idx(), 16);
-    assert_eq!(connector.get_to_write_at(0), 0x01);
-    assert_eq!(connector.get_to_write_at(1), 0x03);
-    assert_eq!(connector.get_to_write_at(2), 0x0c);
-    assert_eq!(connector.get_to_write_at(3), 0x00);
+    let info = ble.controller_info().unwrap();
+    assert_eq!(
+        info.version.hci_version,
+        CoreSpecificationVersion::VERSION_5_3
+    );
+    assert_eq!({ info.version.hci_subversion }, 0x1234);
+    assert_eq!({ info.version.company_identifier }, 0x0059);
+    assert_eq!({ info.version.lmp_subversion }, 0x5678);
+    assert!(info.supported_commands.le_read_supported_states());
+    assert!(info.features.supports_le());
+    assert!(info.supports_data_length_extension());
+    assert!(info.supports_2m_phy());
+    assert!(!info.supports_coded_phy());
+    assert!(!info.supports_extended_advertising());
+    assert!(!info.supports_privacy());
+    assert_eq!(
+        info.acl_buffer_size,
+        AclBufferSize {
+            packet_len: 27,
+            packet_count: 2
+        }
+    );
+    assert_eq!(
+        info.le_states,
+        Some([0xff, 0xff, 0xff, 0xff, 0xff, 0x03, 0x00, 0x00])
+    );
 
-    assert_eq!(connector.get_to_write_at(4), 0x01);
-    assert_eq!(connector.get_to_write_at(5), 0x01);
-    assert_eq!(connector.get_to_write_at(6), 0x0c);
-    assert_eq!(connector.get_to_write_at(7), 0x08);
-    assert_eq!(connector.get_to_write_at(8), 0xff);
-    assert_eq!(connector.get_to_write_at(9), 0xff);
-    assert_eq!(connector.get_to_write_at(10), 0xff);
-    assert_eq!(connector.get_to_write_at(11), 0xff);
-    assert_eq!(connector.get_to_write_at(12), 0xff);
-    assert_eq!(connector.get_to_write_at(13), 0xff);
-    assert_eq!(connector.get_to_write_at(14), 0xff);
-    assert_eq!(connector.get_to_write_at(15), 0xff);
+    assert_eq!(
+        connector.get_written_data().as_slice(),
+        &[
+            0x01, 0x03, 0x0c, 0x00, //
+            0x01, 0x01, 0x0c, 0x08, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, //
+            0x01, 0x01, 0x10, 0x00, //
+            0x01, 0x02, 0x10, 0x00, //
+            0x01, 0x03, 0x10, 0x00, //
+            0x01, 0x03, 0x20, 0x00, //
+            0x01, 0x01, 0x20, 0x08, 0x5d, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+            0x01, 0x02, 0x20, 0x00, //
+            0x01, 0x1c, 0x20, 0x00,
+        ]
+    );
 }
 
 #[test]
-fn init_fails_timeout() {
+fn init_falls_back_to_read_buffer_size() {
     let connector = connector();
     let mut ble = Ble::new(&connector);
 
-    connector.set_current_millis_at(0, 0);
-    connector.set_current_millis_at(1, 100);
-    connector.set_current_millis_at(2, 2000);
+    provide_init_responses(&connector, [0; 8], false);
+    connector.provide_data_to_read(&[
+        0x04, 0x0e, 0x07, 0x05, 0x02, 0x20, 0x00, 0x00, 0x00, 0x00, //
+        0x04, 0x0e, 0x0b, 0x05, 0x05, 0x10, 0x00, 0x1b, 0x00, 0x40, 0x03, 0x00, 0x00, 0x00,
+    ]);
 
     let res = ble.init();
 
-    assert_matches!(res, Err(bleps::Error::Timeout));
-    assert_eq!(connector.get_current_millis_idx(), 3);
+    assert_matches!(res, Ok(()));
+    assert_eq!(
+        ble.acl_buffer_size(),
+        Some(AclBufferSize {
+            packet_len: 27,
+            packet_count: 3
+        })
+    );
+    assert_eq!(ble.controller_info().unwrap().le_states, None);
+
+    let written = connector.get_written_data();
+    assert_eq!(written.as_slice().len(), 52);
+    assert_eq!(
+        &written.as_slice()[32..],
+        &[
+            0x01, 0x01, 0x20, 0x08, 0x1d, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+            0x01, 0x02, 0x20, 0x00, //
+            0x01, 0x05, 0x10, 0x00,
+        ]
+    );
 }
 
 #[test]
-fn init_fails() {
+fn init_enables_le_events_for_supported_features() {
     let connector = connector();
     let mut ble = Ble::new(&connector);
 
-    connector.provide_data_to_read(&[0x04, 0x0e, 0x04, 0x05, 0x03, 0x0c, 0xff]);
+    // connection parameters request, privacy, extended advertising and CSA #2
+    provide_init_responses(&connector, [0x42, 0x50, 0, 0, 0, 0, 0, 0], false);
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x07, 0x05, 0x02, 0x20, 0x00, 0x1b, 0x00, 0x02]);
 
-    let res = ble.init();
+    assert_matches!(ble.init(), Ok(()));
 
-    assert_matches!(res, Err(bleps::Error::Failed(255)));
+    let info = ble.controller_info().unwrap();
+    assert!(info.supports_privacy());
+    assert!(info.supports_extended_advertising());
 
-    assert_eq!(connector.get_write_idx(), 4);
-    assert_eq!(connector.get_to_write_at(0), 0x01);
-    assert_eq!(connector.get_to_write_at(1), 0x03);
-    assert_eq!(connector.get_to_write_at(2), 0x0c);
-    assert_eq!(connector.get_to_write_at(3), 0x00);
+    assert_eq!(
+        &connector.get_written_data().as_slice()[32..44],
+        &[0x01, 0x01, 0x20, 0x08, 0x3d, 0x02, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x00]
+    );
 }
 
 #[test]
-pub fn command_header_reset_parse_works() {
-    let header = CommandHeader::from_bytes(&[0x03, 0x0c, 0x00]);
+fn reset_controller_after_hardware_error_restores_advertising() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
 
-    assert_eq!(header.ogf(), 0x03);
-    assert_eq!(header.ocf(), 0x03);
-    assert_eq!(header.len, 0x00);
-}
+    connector.provide_data_to_read(&[
+        0x04, 0x0e, 0x04, 0x05, 0x05, 0x20, 0x00, 0x04, 0x0e, 0x04, 0x05, 0x06, 0x20, 0x00, //
+        0x04, 0x0e, 0x04, 0x05, 0x08, 0x20, 0x00, 0x04, 0x0e, 0x04, 0x05, 0x0a, 0x20, 0x00,
+    ]);
+    let address = [0x01, 0x02, 0x03, 0x04, 0x05, 0xc6];
+    let adv_data = Data::new(&[0x02, 0x01, 0x06]);
+    assert_matches!(ble.cmd_set_le_random_address(address), Ok(_));
+    assert_matches!(ble.cmd_set_le_advertising_parameters(), Ok(_));
+    assert_matches!(ble.cmd_set_le_advertising_data(adv_data), Ok(_));
+    assert_matches!(ble.cmd_set_le_advertise_enable(true), Ok(_));
+    connector.reset();
 
-#[test]
-pub fn command_header_let_set_adv_param_parse_works() {
-    let header = CommandHeader::from_bytes(&[0x06, 0x20, 0x0f]);
+    connector.provide_data_to_read(&[0x04, 0x10, 0x01, 0x03]);
+    assert_matches!(
+        ble.poll(),
+        Ok(Some(PollResult::Event(EventType::HardwareError {
+            code: 0x03
+        })))
+    );
+    assert!(ble.needs_reset());
+    connector.reset();
 
-    assert_eq!(header.ogf(), 0x08);
-    assert_eq!(header.ocf(), 0x06);
-    assert_eq!(header.len, 0x0f);
-}
+    provide_init_responses(&connector, [0; 8], false);
+    connector.provide_data_to_read(&[
+        0x04, 0x0e, 0x07, 0x05, 0x02, 0x20, 0x00, 0x1b, 0x00, 0x02, //
+        0x04, 0x0e, 0x04, 0x05, 0x05, 0x20, 0x00, 0x04, 0x0e, 0x04, 0x05, 0x06, 0x20, 0x00, //
+        0x04, 0x0e, 0x04, 0x05, 0x08, 0x20, 0x00, 0x04, 0x0e, 0x04, 0x05, 0x0a, 0x20, 0x00,
+    ]);
 
-#[test]
-pub fn command_header_reset_works() {
-    let header = CommandHeader::from_ogf_ocf(0x03, 0x03, 0x00);
+    assert_matches!(ble.reset_controller(), Ok(()));
+    assert!(!ble.needs_reset());
 
-    assert_eq!(header.ogf(), 0x03);
-    assert_eq!(header.ocf(), 0x03);
-    assert_eq!(header.opcode, 0x0c03);
-    assert_eq!(header.len, 0x00);
+    let mut expected: Data = Data::new(&[0x01, 0x05, 0x20, 0x06]);
+    expected.append(&address);
+    expected.append(Command::LeSetAdvertisingParameters.encode().as_slice());
+    expected.append(
+        Command::LeSetAdvertisingData { data: adv_data }
+            .encode()
+            .as_slice(),
+    );
+    expected.append(Command::LeSetAdvertiseEnable(true).encode().as_slice());
+    let written = connector.get_written_data();
+    assert_eq!(&written.as_slice()[..4], &[0x01, 0x03, 0x0c, 0x00]);
+    assert_eq!(&written.as_slice()[48..], expected.as_slice());
 }
 
 #[test]
-pub fn command_header_set_adv_param_works() {
-    let header = CommandHeader::from_ogf_ocf(0x08, 0x06, 0x0f);
+fn write_acl_fragments_and_waits_for_buffers() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
 
-    assert_eq!(header.ogf(), 0x08);
-    assert_eq!(header.ocf(), 0x06);
-    assert_eq!(header.opcode, 0x2006);
-    assert_eq!(header.len, 0x0f);
-}
+    provide_init_responses(&connector, [0; 8], false);
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x07, 0x05, 0x02, 0x20, 0x00, 0x08, 0x00, 0x01]);
+    assert_matches!(ble.init(), Ok(()));
+    connector.reset();
 
-#[test]
-fn create_reset_command_works() {
-    let data = Command::Reset.encode();
-    assert_eq!(data.len, 4);
-    assert_eq!(data.data[0..4], [0x01, 0x03, 0x0c, 0x00]);
-}
+    // incoming data arrives before the controller frees a buffer
+    connector.provide_data_to_read(&[
+        0x02, 0x00, 0x20, 0x05, 0x00, 0x01, 0x00, 0x04, 0x00, 0x0a, //
+        0x04, 0x13, 0x05, 0x01, 0x00, 0x00, 0x01, 0x00,
+    ]);
+
+    let pdu = [
+        0x08, 0x00, 0x04, 0x00, 0x1b, 0x03, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05,
+    ];
+    assert_matches!(ble.write_acl(0, Data::new(&pdu)), Ok(()));
 
-#[test]
-fn create_le_set_advertising_parameters_works() {
-    let data = Command::LeSetAdvertisingParameters.encode();
-    assert_eq!(data.len, 19);
     assert_eq!(
-        data.data[..19],
-        [0x01, 0x06, 0x20, 0x0f, 0x00, 0x01, 0x00, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 7, 0]
+        connector.get_written_data().as_slice(),
+        &[
+            0x02, 0x00, 0x20, 0x08, 0x00, 0x08, 0x00, 0x04, 0x00, 0x1b, 0x03, 0x00, 0x01, //
+            0x02, 0x00, 0x10, 0x04, 0x00, 0x02, 0x03, 0x04, 0x05,
+        ]
+    );
+
+    let res = ble.poll().unwrap();
+    assert_matches!(res,
+        Some(PollResult::AsyncData(AclPacket { handle: 0, data, .. }))
+            if data.as_slice() == &[0x01, 0x00, 0x04, 0x00, 0x0a]
     );
+    // the freed buffer was counted while writing, the event isn't kept
+    assert_matches!(ble.poll(), Ok(None));
 }
 
 #[test]
-fn set_advertising_parameters_works() {
+fn write_acl_fails_when_pending_is_full() {
     let connector = connector();
     let mut ble = Ble::new(&connector);
 
-    connector.provide_data_to_read(&[0x04, 0x0e, 0x04, 0x05, 0x06, 0x20, 0x00]);
+    provide_init_responses(&connector, [0; 8], false);
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x07, 0x05, 0x02, 0x20, 0x00, 0x08, 0x00, 0x01]);
+    assert_matches!(ble.init(), Ok(()));
+    connector.reset();
 
-    let res = ble.cmd_set_le_advertising_parameters();
+    for _ in 0..bleps::PENDING_POLL_RESULTS {
+        connector
+            .provide_data_to_read(&[0x02, 0x00, 0x20, 0x05, 0x00, 0x01, 0x00, 0x04, 0x00, 0x0a]);
+    }
+    connector.provide_data_to_read(&[0x04, 0x13, 0x05, 0x01, 0x00, 0x00, 0x01, 0x00]);
 
-    assert_matches!(res, Ok(EventType::CommandComplete{ num_packets: 5, opcode: 0x2006, data}) if data.as_slice() == &[0]);
+    let pdu = [
+        0x08, 0x00, 0x04, 0x00, 0x1b, 0x03, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05,
+    ];
+    assert_matches!(
+        ble.write_acl(0, Data::new(&pdu)),
+        Err(bleps::Error::PendingFull)
+    );
+
+    // nothing received got lost
+    for _ in 0..bleps::PENDING_POLL_RESULTS {
+        assert_matches!(ble.poll(), Ok(Some(PollResult::AsyncData(_))));
+    }
+    assert_matches!(
+        ble.poll(),
+        Ok(Some(PollResult::Event(
+            EventType::NumberOfCompletedPackets { .. }
+        )))
+    );
 }
 
 #[test]
-fn create_le_set_advertising_data_works() {
-    let data = Command::LeSetAdvertisingData {
-        data: Data::new(&[1, 2, 3, 4, 5]),
-    }
-    .encode();
-    assert_eq!(data.len, 9);
-    assert_eq!(data.data[..9], [0x01, 0x08, 0x20, 0x05, 1, 2, 3, 4, 5]);
+fn pending_capacity_sets_memory_use() {
+    use core::mem::size_of;
+
+    // every packet kept costs a whole PollResult
+    let saved = (bleps::PENDING_POLL_RESULTS - 1) * size_of::<PollResult>();
+    assert_eq!(size_of::<Ble<'_, 1>>() + saved, size_of::<Ble<'_>>());
+    #[cfg(feature = "async")]
+    assert_eq!(
+        size_of::<bleps::asynch::Ble<PendingTransport, fn() -> u64, 1>>() + saved,
+        size_of::<bleps::asynch::Ble<PendingTransport>>()
+    );
+
+    let connector = connector();
+    let mut ble = Ble::<1>::with_pending_capacity(&connector);
+
+    provide_init_responses(&connector, [0; 8], false);
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x07, 0x05, 0x02, 0x20, 0x00, 0x08, 0x00, 0x01]);
+    assert_matches!(ble.init(), Ok(()));
+    connector.reset();
+
+    connector.provide_data_to_read(&[
+        0x02, 0x00, 0x20, 0x05, 0x00, 0x01, 0x00, 0x04, 0x00, 0x0a, //
+        0x04, 0x13, 0x05, 0x01, 0x00, 0x00, 0x01, 0x00,
+    ]);
+    let pdu = [
+        0x08, 0x00, 0x04, 0x00, 0x1b, 0x03, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05,
+    ];
+    assert_matches!(
+        ble.write_acl(0, Data::new(&pdu)),
+        Err(bleps::Error::PendingFull)
+    );
+    assert_matches!(ble.poll(), Ok(Some(PollResult::AsyncData(_))));
 }
 
 #[test]
-fn le_set_advertising_data_works() {
+fn init_rejects_zero_buffer_count() {
     let connector = connector();
     let mut ble = Ble::new(&connector);
 
-    connector.provide_data_to_read(&[0x04, 0x0e, 0x04, 0x05, 0x08, 0x20, 0x00]);
+    provide_init_responses(&connector, [0; 8], false);
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x07, 0x05, 0x02, 0x20, 0x00, 0x1b, 0x00, 0x00]);
+    assert_matches!(ble.init(), Err(bleps::Error::InvalidValue));
+}
 
-    let res = ble.cmd_set_le_advertising_data(Data::new(&[1, 2, 3, 4, 5]));
+#[test]
+fn init_rejects_zero_fallback_buffer_size() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
 
-    assert_matches!(res, Ok(EventType::CommandComplete{ num_packets: 5, opcode: 0x2008, data}) if data.as_slice() == &[0]);
+    provide_init_responses(&connector, [0; 8], false);
+    connector.provide_data_to_read(&[
+        0x04, 0x0e, 0x07, 0x05, 0x02, 0x20, 0x00, 0x00, 0x00, 0x00, //
+        0x04, 0x0e, 0x0b, 0x05, 0x05, 0x10, 0x00, 0x00, 0x00, 0x40, 0x03, 0x00, 0x00, 0x00,
+    ]);
+    assert_matches!(ble.init(), Err(bleps::Error::InvalidValue));
 }
 
 #[test]
-fn create_le_set_advertise_enable_works() {
-    let data = Command::LeSetAdvertiseEnable(true).encode();
-    assert_eq!(data.len, 5);
-    assert_eq!(data.data[..5], [0x01, 0x0a, 0x20, 0x01, 0x01]);
+fn init_fails_timeout() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.set_current_millis_at(0, 0);
+    connector.set_current_millis_at(1, 100);
+    connector.set_current_millis_at(2, 2000);
+
+    let res = ble.init();
+
+    assert_matches!(res, Err(bleps::Error::Timeout));
+    assert_eq!(connector.get_current_millis_idx(), 3);
+    // a single slow response doesn't force a reset
+    assert!(!ble.needs_reset());
 }
 
 #[test]
-fn le_set_advertise_enable_works() {
+fn init_fails() {
     let connector = connector();
     let mut ble = Ble::new(&connector);
 
-    connector.provide_data_to_read(&[0x04, 0x0e, 0x04, 0x05, 0x0a, 0x20, 0x00]);
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x04, 0x05, 0x03, 0x0c, 0xff]);
 
-    let res = ble.cmd_set_le_advertise_enable(false);
+    let res = ble.init();
 
-    assert_matches!(res, Ok(EventType::CommandComplete{ num_packets: 5, opcode: 0x200a, data}) if data.as_slice() == &[0]);
+    assert_matches!(
+        res,
+        Err(bleps::Error::Failed {
+            opcode: 0x0c03,
+            status: ErrorCode::Other(0xff)
+        })
+    );
+
+    assert_eq!(connector.get_write_idx(), 4);
+    assert_eq!(connector.get_to_write_at(0), 0x01);
+    assert_eq!(connector.get_to_write_at(1), 0x03);
+    assert_eq!(connector.get_to_write_at(2), 0x0c);
+    assert_eq!(connector.get_to_write_at(3), 0x00);
 }
 
 #[test]
-fn receiving_async_data_works() {
+fn exec_returns_command_complete_params() {
     let connector = connector();
     let mut ble = Ble::new(&connector);
 
     connector.provide_data_to_read(&[
-        0x02, 0x00, 0x20, 0x0b, 0x00, 0x07, 0x00, 0x04, 0x00, 0x10, 0x01, 0x00, 0xff, 0xff, 0x00,
-        0x28,
+        0x04, 0x0e, 0x0a, 0x01, 0x09, 0x10, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06,
     ]);
 
-    let res = ble.poll();
+    let res = ble.exec(&ReadBdAddr::new());
 
-    assert_matches!(res,
-        Some(PollResult::AsyncData(AclPacket {
-            handle: 0,
-            boundary_flag: BoundaryFlag::FirstAutoFlushable,
-            bc_flag: ControllerBroadcastFlag::PointToPoint,
-            data,
-        })) if data.as_slice() == &[0x7, 0x0, 0x4, 0x0, 0x10, 0x1, 0x0, 0xff, 0xff, 0x0, 0x28]
+    assert_matches!(res, Ok(addr) if addr.raw() == &[0x01, 0x02, 0x03, 0x04, 0x05, 0x06]);
+    assert_eq!(
+        connector.get_written_data().as_slice(),
+        &[0x01, 0x09, 0x10, 0x00]
     );
 }
 
+fn set_phy_cmd() -> LeSetPhy {
+    LeSetPhy::new(
+        ConnHandle::new(1),
+        AllPhys::new(),
+        PhyMask::new().set_le_1m_preferred(true),
+        PhyMask::new().set_le_1m_preferred(true),
+        PhyOptions::NoPreferredCoding,
+    )
+}
+
 #[test]
-fn receiving_disconnection_complete_works() {
+fn exec_async_cmd_waits_for_command_status() {
     let connector = connector();
     let mut ble = Ble::new(&connector);
 
-    connector.provide_data_to_read(&[0x04, 0x05, 0x04, 0x00, 0x00, 0x00, 0x13]);
+    connector.provide_data_to_read(&[0x04, 0x0f, 0x04, 0x00, 0x01, 0x32, 0x20]);
 
-    let res = ble.poll();
+    let res = ble.exec_async_cmd(&set_phy_cmd());
 
-    assert_matches!(
-        res,
-        Some(PollResult::Event(EventType::DisconnectComplete {
-            handle: 0,
-            status: ErrorCode::Okay,
-            reason: ErrorCode::RemoteUserTerminatedConnection
-        }))
+    assert_matches!(res, Ok(()));
+    assert_eq!(
+        connector.get_written_data().as_slice(),
+        &[0x01, 0x32, 0x20, 0x07, 0x01, 0x00, 0x00, 0x01, 0x01, 0x00, 0x00]
     );
 }
 
 #[test]
-fn receiving_number_of_completed_packets_works() {
+fn exec_async_cmd_fails() {
     let connector = connector();
     let mut ble = Ble::new(&connector);
 
-    connector.provide_data_to_read(&[0x04, 0x13, 0x05, 0x01, 0x00, 0x00, 0x01, 0x00]);
+    connector.provide_data_to_read(&[0x04, 0x0f, 0x04, 0x02, 0x01, 0x32, 0x20]);
 
-    let res = ble.poll();
+    let res = ble.exec_async_cmd(&set_phy_cmd());
 
     assert_matches!(
         res,
-        Some(PollResult::Event(EventType::NumberOfCompletedPackets {
-            number_of_connection_handles: 1,
-            connection_handles: 0,
-            completed_packets: 1,
-        }))
+        Err(bleps::Error::Failed {
+            opcode: 0x2032,
+            status: ErrorCode::UnknownConnectionIdentifier
+        })
     );
 }
 
 #[test]
-fn receiving_read_by_group_type_works() {
+fn command_waits_for_command_credits() {
     let connector = connector();
     let mut ble = Ble::new(&connector);
 
+    // the controller can't take another command after this one
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x04, 0x00, 0x03, 0x0c, 0x00]);
+    assert_matches!(ble.cmd_reset(), Ok(_));
+
+    connector.set_current_millis_at(2, 2000);
+    assert_matches!(
+        ble.cmd_set_event_mask([0xff; 8]),
+        Err(bleps::Error::Timeout)
+    );
+    assert_eq!(connector.get_write_idx(), 4);
+
+    // a NOP Command Complete hands out a new credit
     connector.provide_data_to_read(&[
-        0x02, 0x00, 0x20, 0x0b, 0x00, 0x07, 0x00, 0x04, 0x00, 0x10, 0x01, 0x00, 0xff, 0xff, 0x00,
-        0x28,
+        0x04, 0x0e, 0x03, 0x01, 0x00, 0x00, //
+        0x04, 0x0e, 0x04, 0x01, 0x01, 0x0c, 0x00,
     ]);
-
-    let res = ble.poll();
-    match res {
-        Some(res) => match res {
-            PollResult::Event(_) => assert!(true, "Expected async data"),
-            PollResult::AsyncData(res) => {
-                let res = Att::decode(L2capPacket::decode(res).unwrap().1);
-                assert_matches!(
-                    res,
-                    Ok(Att::ReadByGroupTypeReq {
-                        start: 0x0001,
-                        end: 0xffff,
-                        group_type: Uuid::Uuid16(0x2800),
-                    })
-                )
-            }
-        },
-        None => assert!(true, "Expected result"),
-    }
-}
+    assert_matches!(ble.cmd_set_event_mask([0xff; 8]), Ok(_));
+    assert_eq!(connector.get_write_idx(), 16);
+}
 
 #[test]
-fn create_read_by_group_type_resp_works() {
-    let mut res = Data::new_att_read_by_group_type_response();
-    res.append_att_read_by_group_type_response(0x0001, 0x0010, &Uuid::Uuid16(0x1801));
-    res.append_att_read_by_group_type_response(0x0020, 0x0030, &Uuid::Uuid16(0x1802));
+fn command_keeps_packets_received_before_its_response() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
 
+    connector.provide_data_to_read(&[
+        0x02, 0x00, 0x20, 0x05, 0x00, 0x01, 0x00, 0x04, 0x00, 0x0a, //
+        0x04, 0x05, 0x04, 0x00, 0x00, 0x00, 0x13, //
+        0x04, 0x0e, 0x04, 0x01, 0x03, 0x0c, 0x00,
+    ]);
+    assert_matches!(ble.cmd_reset(), Ok(_));
+
+    assert_matches!(ble.poll(),
+        Ok(Some(PollResult::AsyncData(AclPacket { handle: 0, data, .. })))
+            if data.as_slice() == &[0x01, 0x00, 0x04, 0x00, 0x0a]
+    );
     assert_matches!(
-        res.as_slice(),
-        [0x11, 0x06, 0x01, 0x00, 0x10, 0x00, 0x01, 0x18, 0x20, 0x00, 0x30, 0x00, 0x02, 0x18]
+        ble.poll(),
+        Ok(Some(PollResult::Event(EventType::DisconnectComplete {
+            handle: 0,
+            reason: ErrorCode::RemoteUserTerminatedConnection,
+            ..
+        })))
     );
+    assert_matches!(ble.poll(), Ok(None));
 }
 
 #[test]
-fn create_read_by_group_type_resp_acl_works() {
-    let mut res = Data::new_att_read_by_group_type_response();
-    res.append_att_read_by_group_type_response(0x0001, 0x0010, &Uuid::Uuid16(0x1801));
-    res.append_att_read_by_group_type_response(0x0020, 0x0030, &Uuid::Uuid16(0x1802));
-    let res = L2capPacket::encode(res);
-    let res = AclPacket::encode(
-        0x0000,
-        BoundaryFlag::FirstAutoFlushable,
-        HostBroadcastFlag::NoBroadcast,
-        res,
-    );
+fn command_complete_without_status_is_truncated() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x03, 0x01, 0x03, 0x0c]);
+
+    assert_matches!(ble.cmd_reset(), Err(bleps::Error::Truncated));
+}
+
+#[test]
+fn command_fails_with_command_status() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[0x04, 0x0f, 0x04, 0x0c, 0x01, 0x0a, 0x20]);
+
+    let res = ble.cmd_set_le_advertise_enable(true);
 
     assert_matches!(
-        res.as_slice(),
-        &[
-            0x02, 0x00, 0x20, 0x12, 0x00, 0x0e, 0x00, 0x04, 0x00, 0x11, 0x06, 0x01, 0x00, 0x10,
-            0x00, 0x01, 0x18, 0x20, 0x00, 0x30, 0x00, 0x02, 0x18,
-        ]
+        res,
+        Err(bleps::Error::Failed {
+            opcode: 0x200a,
+            status: ErrorCode::CommandDisallowed
+        })
+    );
+    assert_eq!(
+        res.unwrap_err().to_string(),
+        "command 0x200a failed: CommandDisallowed (0x0c)"
     );
 }
 
 #[test]
-fn create_error_resp_works() {
-    let res = Data::new_att_error_response(
-        ATT_READ_BY_GROUP_TYPE_REQUEST_OPCODE,
-        0x1234,
-        AttErrorCode::AttributeNotFound,
-    );
+pub fn command_header_reset_parse_works() {
+    let header = CommandHeader::from_bytes(&[0x03, 0x0c, 0x00]);
 
-    assert_matches!(res.as_slice(), &[0x01, 0x10, 0x34, 0x12, 0x0a,]);
+    assert_eq!(header.ogf(), 0x03);
+    assert_eq!(header.ocf(), 0x03);
+    assert_eq!(header.len, 0x00);
 }
 
 #[test]
-fn receiving_read_by_type_works() {
-    let connector = connector();
-    let mut ble = Ble::new(&connector);
-
-    connector.provide_data_to_read(&[
-        0x02, 0x00, 0x20, 0x0b, 0x00, 0x07, 0x00, 0x04, 0x00, 0x08, 0x01, 0x00, 0x02, 0x00, 0x02,
-        0x28,
-    ]);
+pub fn command_header_let_set_adv_param_parse_works() {
+    let header = CommandHeader::from_bytes(&[0x06, 0x20, 0x0f]);
 
-    let res = ble.poll();
-    match res {
-        Some(res) => match res {
-            PollResult::Event(_) => assert!(true, "Expected async data"),
-            PollResult::AsyncData(res) => {
-                let res = Att::decode(L2capPacket::decode(res).unwrap().1);
-                assert_matches!(
-                    res,
-                    Ok(Att::ReadByTypeReq {
-                        start: 0x0001,
-                        end: 0x0002,
-                        attribute_type: Uuid::Uuid16(0x2802),
-                    })
-                )
-            }
-        },
-        None => assert!(true, "Expected result"),
-    }
+    assert_eq!(header.ogf(), 0x08);
+    assert_eq!(header.ocf(), 0x06);
+    assert_eq!(header.len, 0x0f);
 }
 
 #[test]
-fn create_read_by_type_resp_works() {
-    let mut res = Data::new_att_read_by_type_response();
-    res.append_value(0x0002u16);
-    res.append(&[1u8, 2u8, 3u8, 4u8]);
-    res.append_att_read_by_type_response();
+pub fn command_header_reset_works() {
+    let header = CommandHeader::from_ogf_ocf(0x03, 0x03, 0x00);
 
-    assert_matches!(
-        res.as_slice(),
-        [0x09, 0x06, 0x02, 0x00, 0x01, 0x02, 0x03, 0x04]
-    );
+    assert_eq!(header.ogf(), 0x03);
+    assert_eq!(header.ocf(), 0x03);
+    assert_eq!(header.opcode, 0x0c03);
+    assert_eq!(header.len, 0x00);
 }
 
-// TODO test EXCHANGE_MTU
+#[test]
+pub fn command_header_set_adv_param_works() {
+    let header = CommandHeader::from_ogf_ocf(0x08, 0x06, 0x0f);
 
-// TODO test FIND_TYPE_VALUE
+    assert_eq!(header.ogf(), 0x08);
+    assert_eq!(header.ocf(), 0x06);
+    assert_eq!(header.opcode, 0x2006);
+    assert_eq!(header.len, 0x0f);
+}
 
 #[test]
-fn receiving_read_works() {
-    let connector = connector();
-    let mut ble = Ble::new(&connector);
+fn create_reset_command_works() {
+    let data = Command::Reset.encode();
+    assert_eq!(data.len, 4);
+    assert_eq!(data.data[0..4], [0x01, 0x03, 0x0c, 0x00]);
+}
 
-    connector.provide_data_to_read(&[
-        0x02, 0x00, 0x20, 0x07, 0x00, 0x03, 0x00, 0x04, 0x00, 0x0a, 0x03, 0x00,
-    ]);
+#[test]
+fn create_le_set_advertising_parameters_works() {
+    let data = Command::LeSetAdvertisingParameters.encode();
+    assert_eq!(data.len, 19);
+    assert_eq!(
+        data.data[..19],
+        [0x01, 0x06, 0x20, 0x0f, 0x00, 0x01, 0x00, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 7, 0]
+    );
+}
 
-    let res = ble.poll();
-    match res {
-        Some(res) => match res {
-            PollResult::Event(_) => assert!(true, "Expected async data"),
-            PollResult::AsyncData(res) => {
-                let res = Att::decode(L2capPacket::decode(res).unwrap().1);
-                assert_matches!(res, Ok(Att::ReadReq { handle: 0x03 }))
-            }
-        },
-        None => assert!(true, "Expected result"),
-    }
+#[test]
+fn create_le_set_advertising_parameters_custom_works() {
+    let params = AdvertisingParameters::builder()
+        .interval(Duration::from_millis(100), Duration::from_millis(150))
+        .advertising_type(AdvertisingType::AdvScanInd)
+        .channel_map(
+            AdvertisingChannelMapBits::Channel37 as u8 | AdvertisingChannelMapBits::Channel39 as u8,
+        )
+        .build()
+        .unwrap();
+    let data = Command::LeSetAdvertisingParametersCustom(&params).encode();
+    assert_eq!(
+        data.as_slice(),
+        &[0x01, 0x06, 0x20, 0x0f, 0xa0, 0x00, 0xf0, 0x00, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 5, 0]
+    );
 }
 
 #[test]
-fn create_read_resp_works() {
-    let mut res = Data::new_att_read_response();
-    res.append(&[0x01, 0x02, 0x03, 0x04]);
+fn advertising_parameters_builder_rejects_invalid_parameters() {
+    let interval = |min, max| {
+        AdvertisingParameters::builder()
+            .interval(Duration::from_millis(min), Duration::from_millis(max))
+            .build()
+    };
+    assert_eq!(
+        interval(19, 100).unwrap_err(),
+        AdvertisingParametersError::IntervalOutOfRange
+    );
+    assert_eq!(
+        interval(100, 10241).unwrap_err(),
+        AdvertisingParametersError::IntervalOutOfRange
+    );
+    assert_eq!(
+        interval(200, 100).unwrap_err(),
+        AdvertisingParametersError::MinIntervalAboveMax
+    );
+    assert!(interval(20, 10240).is_ok());
+
+    for channel_map in [0, 0b1000] {
+        assert_eq!(
+            AdvertisingParameters::builder()
+                .channel_map(channel_map)
+                .build()
+                .unwrap_err(),
+            AdvertisingParametersError::InvalidChannelMap
+        );
+    }
 
-    assert_matches!(res.as_slice(), &[0x0b, 0x01, 0x02, 0x03, 0x04,]);
+    assert_eq!(
+        AdvertisingParameters::builder()
+            .advertising_type(AdvertisingType::AdvDirectIndLowDuty)
+            .build()
+            .unwrap_err(),
+        AdvertisingParametersError::MissingPeerAddress
+    );
+    // undirected advertising doesn't use the peer address, it's still allowed
+    assert!(AdvertisingParameters::builder()
+        .peer_address(PeerAddressType::Random, [1, 2, 3, 4, 5, 0xc6])
+        .build()
+        .is_ok());
+
+    // high duty cycle directed advertising ignores the intervals
+    let params = AdvertisingParameters::builder()
+        .interval(Duration::ZERO, Duration::ZERO)
+        .advertising_type(AdvertisingType::AdvDirectInd)
+        .peer_address(PeerAddressType::Random, [1, 2, 3, 4, 5, 0xc6])
+        .build()
+        .unwrap();
+    assert_eq!(params.peer_address, [1, 2, 3, 4, 5, 0xc6]);
+
+    // invalid parameters never reach the controller
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+    let params = AdvertisingParameters {
+        advertising_interval_min: 0x0010,
+        ..AdvertisingParameters::default()
+    };
+    assert_matches!(
+        ble.cmd_set_le_advertising_parameters_custom(&params),
+        Err(Error::InvalidAdvertisingParameters(
+            AdvertisingParametersError::IntervalOutOfRange
+        ))
+    );
+    assert_eq!(*connector.write_idx.borrow(), 0);
 }
 
 #[test]
-fn receiving_write_works() {
+fn set_advertising_parameters_works() {
     let connector = connector();
     let mut ble = Ble::new(&connector);
 
-    connector.provide_data_to_read(&[
-        0x02, 0x00, 0x20, 0x08, 0x00, 0x04, 0x00, 0x04, 0x00, 0x12, 0x03, 0x00, 0x0ff,
-    ]);
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x04, 0x05, 0x06, 0x20, 0x00]);
 
-    let res = ble.poll();
-    match res {
-        Some(res) => match res {
-            PollResult::Event(_) => assert!(true, "Expected async data"),
-            PollResult::AsyncData(res) => {
-                let res = Att::decode(L2capPacket::decode(res).unwrap().1);
-                assert_matches!(
-                    res,
-                    Ok(Att::WriteReq {
-                        handle: 0x03,
-                        data
-                    }) if data.as_slice() == &[0xff]
-                )
-            }
-        },
-        None => assert!(true, "Expected result"),
-    }
+    let res = ble.cmd_set_le_advertising_parameters();
+
+    assert_matches!(res, Ok(EventType::CommandComplete{ num_packets: 5, opcode: 0x2006, data}) if data.as_slice() == &[0]);
 }
 
 #[test]
-fn create_write_resp_works() {
-    let res = Data::new_att_write_response();
-
-    assert_matches!(res.as_slice(), &[0x13]);
+fn create_le_set_advertising_data_works() {
+    let data = Command::LeSetAdvertisingData {
+        data: Data::new(&[1, 2, 3, 4, 5]),
+    }
+    .encode();
+    assert_eq!(data.len, 9);
+    assert_eq!(data.data[..9], [0x01, 0x08, 0x20, 0x05, 1, 2, 3, 4, 5]);
 }
 
 #[test]
-fn create_advertising_data_works() {
-    let res = create_advertising_data(&[
-        AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
-        AdStructure::ServiceUuids16(&[Uuid::Uuid16(0x1809)]),
-        AdStructure::CompleteLocalName("Ble-Example!"),
-    ])
-    .unwrap();
+fn le_set_advertising_data_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
 
-    println!("{:x?}", res);
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x04, 0x05, 0x08, 0x20, 0x00]);
 
-    assert_matches!(
-        res.as_slice(),
-        &[
-            21, 2, 1, 6, 3, 2, 9, 24, 13, 9, 66, 108, 101, 45, 69, 120, 97, 109, 112, 108, 101, 33,
-            0, 0, 0, 0, 0, 0, 0, 0, 0, 0
-        ]
-    );
+    let res = ble.cmd_set_le_advertising_data(Data::new(&[1, 2, 3, 4, 5]));
+
+    assert_matches!(res, Ok(EventType::CommandComplete{ num_packets: 5, opcode: 0x2008, data}) if data.as_slice() == &[0]);
 }
 
 #[test]
-fn create_advertising_data_fails() {
-    let res = create_advertising_data(&[
-        AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
-        AdStructure::ServiceUuids16(&[Uuid::Uuid16(0x1809)]),
-        AdStructure::CompleteLocalName(
-            "Ble-Example!Ble-Example!Ble-Example!Ble-Example!Ble-Example!Ble-Example!Ble-Example!",
-        ),
-    ]);
-
-    assert_matches!(res, Err(AdvertisementDataError::TooLong));
+fn create_le_set_advertise_enable_works() {
+    let data = Command::LeSetAdvertiseEnable(true).encode();
+    assert_eq!(data.len, 5);
+    assert_eq!(data.data[..5], [0x01, 0x0a, 0x20, 0x01, 0x01]);
 }
 
 #[test]
-fn attribute_server_discover_two_services() {
+fn le_set_advertise_enable_works() {
     let connector = connector();
     let mut ble = Ble::new(&connector);
 
-    let mut rf1 = |_offset: usize, data: &mut [u8]| -> usize {
-        data[0] = 0;
-        1
-    };
-    let mut wf1 = |_offset: usize, _data: &[u8]| {};
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x04, 0x05, 0x0a, 0x20, 0x00]);
 
-    let mut rf2 = |_offset: usize, data: &mut [u8]| -> usize {
-        data[0] = 0;
-        1
-    };
-    let mut wf2 = |_offset: usize, _data: &[u8]| {};
+    let res = ble.cmd_set_le_advertise_enable(false);
 
-    let srv_uuid: [u8; 16] = [
-        0xC9, 0x15, 0x15, 0x96, 0x54, 0x56, 0x64, 0xB3, 0x38, 0x45, 0x26, 0x5D, 0xF1, 0x62, 0x6A,
-        0xA8,
-    ];
-    let mut srv_uuid_att_data = &srv_uuid[..];
-    let primaray_srv = Attribute::new(PRIMARY_SERVICE_UUID16, &mut srv_uuid_att_data);
+    assert_matches!(res, Ok(EventType::CommandComplete{ num_packets: 5, opcode: 0x200a, data}) if data.as_slice() == &[0]);
+}
 
-    let char_data = [
-        0x02, // 1 byte properties: READ = 0x02
-        0x03, 0x00, // 2 bytes handle = 0x0007
-        0xC9, 0x15, 0x15, 0x96, 0x54, 0x56, 0x64, 0xB3, 0x38, 0x45, 0x26, 0x5D, 0xF1, 0x62, 0x6A,
-        0xA8, // 128 bit UUID like above
-    ];
-    let mut char_att_data = &char_data;
-    let char = Attribute::new(CHARACTERISTIC_UUID16, &mut char_att_data);
+#[test]
+fn receiving_async_data_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
 
-    let mut custom_char_att_data = (&mut rf1, &mut wf1, ());
-    let custom_char_att_data_attr = Attribute::new(
-        Uuid::Uuid128([
-            0xC9, 0x15, 0x15, 0x96, 0x54, 0x56, 0x64, 0xB3, 0x38, 0x45, 0x26, 0x5D, 0xF1, 0x62,
-            0x6A, 0xA8,
-        ]),
-        &mut custom_char_att_data,
+    connector.provide_data_to_read(&[
+        0x02, 0x00, 0x20, 0x0b, 0x00, 0x07, 0x00, 0x04, 0x00, 0x10, 0x01, 0x00, 0xff, 0xff, 0x00,
+        0x28,
+    ]);
+
+    let res = ble.poll().unwrap();
+
+    assert_matches!(res,
+        Some(PollResult::AsyncData(AclPacket {
+            handle: 0,
+            boundary_flag: BoundaryFlag::FirstAutoFlushable,
+            bc_flag: ControllerBroadcastFlag::PointToPoint,
+            data,
+        })) if data.as_slice() == &[0x7, 0x0, 0x4, 0x0, 0x10, 0x1, 0x0, 0xff, 0xff, 0x0, 0x28]
     );
+}
 
-    let srv_uuid2: [u8; 16] = [
-        0xC8, 0x15, 0x15, 0x96, 0x54, 0x56, 0x64, 0xB3, 0x38, 0x45, 0x26, 0x5D, 0xF1, 0x62, 0x6A,
-        0xA8,
-    ];
-    let mut srv_uuid_att_data2 = &srv_uuid2[..];
-    let primaray_srv2 = Attribute::new(PRIMARY_SERVICE_UUID16, &mut srv_uuid_att_data2);
+#[test]
+fn receiving_fragmented_async_data_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
 
-    let char_data2 = [
-        0x02 | 0x08, // 1 byte properties: READ = 0x02, WRITE WITH RESPONSE = 0x08
-        0x06,
-        0x00, // 2 bytes handle = 0x0007
-        0xC8,
-        0x15,
-        0x15,
-        0x96,
-        0x54,
-        0x56,
-        0x64,
-        0xB3,
-        0x38,
-        0x45,
-        0x26,
-        0x5D,
-        0xF1,
-        0x62,
-        0x6A,
-        0xA8, // 128 bit UUID like above
-    ];
-    let mut char_att_data2 = &char_data2;
-    let char2 = Attribute::new(CHARACTERISTIC_UUID16, &mut char_att_data2);
+    connector.provide_data_to_read(&[
+        0x02, 0x00, 0x20, 0x06, 0x00, 0x07, 0x00, 0x04, 0x00, 0x10, 0x01, //
+        0x02, 0x01, 0x20, 0x06, 0x00, 0x03, 0x00, 0x04, 0x00, 0x0a, 0x03, //
+        0x02, 0x00, 0x10, 0x05, 0x00, 0x00, 0xff, 0xff, 0x00, 0x28, //
+        0x02, 0x01, 0x10, 0x01, 0x00, 0x00,
+    ]);
 
-    let mut custom_char_att_data2 = (&mut rf2, &mut wf2, ());
-    let custom_char_att_data_attr2 = Attribute::new(
-        Uuid::Uuid128([
-            0xC9, 0x15, 0x15, 0x96, 0x54, 0x56, 0x64, 0xB3, 0x38, 0x45, 0x26, 0x5D, 0xF1, 0x62,
-            0x6A, 0xA8,
-        ]),
-        &mut custom_char_att_data2,
+    assert_matches!(ble.poll(), Ok(None));
+    assert_matches!(ble.poll(), Ok(None));
+
+    let res = ble.poll().unwrap();
+    assert_matches!(res,
+        Some(PollResult::AsyncData(AclPacket {
+            handle: 0,
+            boundary_flag: BoundaryFlag::FirstAutoFlushable,
+            data,
+            ..
+        })) if data.as_slice() == &[0x7, 0x0, 0x4, 0x0, 0x10, 0x1, 0x0, 0xff, 0xff, 0x0, 0x28]
     );
 
-    let mut val_att_data = &(32u32,);
-    let val = Attribute::new(CHARACTERISTIC_UUID16, &mut val_att_data);
+    let res = ble.poll().unwrap();
+    assert_matches!(res,
+        Some(PollResult::AsyncData(AclPacket {
+            handle: 1,
+            data,
+            ..
+        })) if data.as_slice() == &[0x3, 0x0, 0x4, 0x0, 0xa, 0x3, 0x0]
+    );
+}
 
-    let attributes = &mut [
-        primaray_srv,
-        char,
-        custom_char_att_data_attr,
-        primaray_srv2,
-        char2,
-        custom_char_att_data_attr2,
-        val,
-    ];
+#[test]
+fn new_first_fragment_drops_incomplete_async_data() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
 
-    let mut rng = OsRng::default();
-    let mut srv = AttributeServer::new(&mut ble, attributes, &mut rng);
+    connector.provide_data_to_read(&[
+        0x02, 0x00, 0x20, 0x06, 0x00, 0x07, 0x00, 0x04, 0x00, 0x10, 0x01, //
+        0x02, 0x00, 0x20, 0x07, 0x00, 0x03, 0x00, 0x04, 0x00, 0x0a, 0x03, 0x00, //
+        0x02, 0x00, 0x10, 0x01, 0x00, 0x00,
+    ]);
+
+    assert_matches!(ble.poll(), Ok(None));
+
+    let res = ble.poll().unwrap();
+    assert_matches!(res,
+        Some(PollResult::AsyncData(AclPacket { handle: 0, data, .. }))
+            if data.as_slice() == &[0x3, 0x0, 0x4, 0x0, 0xa, 0x3, 0x0]
+    );
+
+    // the continuation of the dropped SDU is discarded as well
+    assert_matches!(ble.poll(), Ok(None));
+}
+
+#[test]
+fn oversized_async_data_is_dropped() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
 
-    // ReadByGroupTypeReq { start: 1, end: ffff, group_type: Uuid16(2800) }
     connector.provide_data_to_read(&[
-        0x02, 0x00, 0x20, 0x0b, 0x00, 0x07, 0x00, 0x04, 0x00, 0x10, 0x01, 0x00, 0xff, 0xff, 0x00,
-        0x28,
+        0x02, 0x00, 0x20, 0x06, 0x00, 0x00, 0x04, 0x04, 0x00, 0x10, 0x01, //
+        0x02, 0x00, 0x10, 0x01, 0x00, 0x00,
     ]);
-    assert_matches!(srv.do_work(), Ok(_));
-    // check response (1-3, 0x2800)
-    let response_data = connector.get_written_data();
-    assert_eq!(
-        response_data.as_slice(),
-        &[
-            0x02, 0x00, 0x20, 0x1A, 0x00, 0x16, 0x00, 0x04, 0x00, 0x11, 0x14, 0x01, 0x00, 0x03,
-            0x00, 0xC9, 0x15, 0x15, 0x96, 0x54, 0x56, 0x64, 0xB3, 0x38, 0x45, 0x26, 0x5D, 0xF1,
-            0x62, 0x6A, 0xA8,
-        ]
+
+    assert_matches!(ble.poll(), Ok(None));
+    assert_matches!(ble.poll(), Ok(None));
+}
+
+#[test]
+fn receiving_disconnection_complete_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[0x04, 0x05, 0x04, 0x00, 0x00, 0x00, 0x13]);
+
+    let res = ble.poll().unwrap();
+
+    assert_matches!(
+        res,
+        Some(PollResult::Event(EventType::DisconnectComplete {
+            handle: 0,
+            status: ErrorCode::Okay,
+            reason: ErrorCode::RemoteUserTerminatedConnection
+        }))
     );
+}
+
+#[test]
+fn receiving_connection_complete_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
 
-    // ReadByGroupTypeReq { start: 4, end: ffff, group_type: Uuid16(2800) }
-    connector.reset();
     connector.provide_data_to_read(&[
-        0x02, 0x00, 0x20, 0x0b, 0x00, 0x07, 0x00, 0x04, 0x00, 0x10, 0x04, 0x00, 0xff, 0xff, 0x00,
-        0x28,
+        0x04, 0x3e, 0x13, 0x01, 0x00, 0x40, 0x00, 0x01, 0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66,
+        0x28, 0x00, 0x00, 0x00, 0xf4, 0x01, 0x05,
     ]);
-    assert_matches!(srv.do_work(), Ok(_));
-    // check response (4-6, 0x2800)
-    let response_data = connector.get_written_data();
-    assert_eq!(
-        response_data.as_slice(),
-        &[
-            0x02, 0x00, 0x20, 0x1a, 0x00, 0x16, 0x00, 0x04, 0x00, 0x11, 0x14, 0x04, 0x00, 0x07,
-            0x00, 0xC8, 0x15, 0x15, 0x96, 0x54, 0x56, 0x64, 0xB3, 0x38, 0x45, 0x26, 0x5D, 0xF1,
-            0x62, 0x6A, 0xA8,
-        ]
+
+    let res = ble.poll().unwrap();
+
+    assert_matches!(
+        res,
+        Some(PollResult::Event(EventType::ConnectionComplete {
+            status: 0,
+            handle: 0x40,
+            role: Role::Peripheral,
+            peer_address,
+            interval: 0x28,
+            latency: 0,
+            timeout: 0x1f4,
+            central_clock_accuracy: 5,
+        })) if peer_address.0 == [0x00, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11]
     );
+}
+
+#[test]
+fn receiving_failed_connection_complete_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
 
-    // ReadByGroupTypeReq { start: 7, end: ffff, group_type: Uuid16(2800) }
-    connector.reset();
     connector.provide_data_to_read(&[
-        0x02, 0x00, 0x20, 0x0b, 0x00, 0x07, 0x00, 0x04, 0x00, 0x10, 0x07, 0x00, 0xff, 0xff, 0x00,
-        0x28,
+        0x04, 0x3e, 0x13, 0x01, 0x3e, 0x00, 0x00, 0xff, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
     ]);
-    assert_matches!(srv.do_work(), Ok(_));
-    // check response (not found)
-    let response_data = connector.get_written_data();
-    assert_eq!(
-        response_data.as_slice(),
-        &[0x02, 0x00, 0x20, 0x09, 0x00, 0x05, 0x00, 0x04, 0x00, 0x01, 0x10, 0x07, 0x00, 0x0a]
+
+    let res = ble.poll().unwrap();
+
+    assert_matches!(
+        res,
+        Some(PollResult::Event(EventType::ConnectionComplete {
+            status: 0x3e,
+            role: Role::Other(0xff),
+            ..
+        }))
+    );
+}
+
+#[test]
+fn receiving_enhanced_connection_complete_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[
+        0x04, 0x3e, 0x1f, 0x0a, 0x00, 0x40, 0x00, 0x00, 0x03, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0x45, 0x18, 0x00, 0x01,
+        0x00, 0x48, 0x00, 0x01,
+    ]);
+
+    let res = ble.poll().unwrap();
+
+    assert_matches!(
+        res,
+        Some(PollResult::Event(EventType::EnhancedConnectionComplete {
+            status: 0,
+            handle: 0x40,
+            role: Role::Central,
+            peer_address,
+            local_resolvable_private_address: [0, 0, 0, 0, 0, 0],
+            peer_resolvable_private_address: [0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0x45],
+            interval: 0x18,
+            latency: 1,
+            timeout: 0x48,
+            central_clock_accuracy: 1,
+            advertising_handle: None,
+            sync_handle: None,
+        })) if peer_address.0 == [0x01, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11]
+    );
+}
+
+#[test]
+fn receiving_enhanced_connection_complete_v2_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[
+        0x04, 0x3e, 0x22, 0x29, 0x00, 0x40, 0x00, 0x01, 0x02, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66,
+        0xb1, 0xb2, 0xb3, 0xb4, 0xb5, 0x46, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x18, 0x00, 0x00,
+        0x00, 0x48, 0x00, 0x00, 0x02, 0xff, 0xff,
+    ]);
+
+    let res = ble.poll().unwrap();
+
+    assert_matches!(
+        res,
+        Some(PollResult::Event(EventType::EnhancedConnectionComplete {
+            role: Role::Peripheral,
+            peer_address,
+            local_resolvable_private_address: [0xb1, 0xb2, 0xb3, 0xb4, 0xb5, 0x46],
+            advertising_handle: Some(2),
+            sync_handle: Some(0xffff),
+            ..
+        })) if peer_address.0 == [0x00, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11]
+    );
+}
+
+#[test]
+fn receiving_connection_update_complete_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[
+        0x04, 0x3e, 0x0a, 0x03, 0x00, 0x40, 0x00, 0x06, 0x00, 0x02, 0x00, 0x90, 0x01,
+    ]);
+
+    let res = ble.poll().unwrap();
+
+    assert_matches!(
+        res,
+        Some(PollResult::Event(EventType::ConnectionUpdateComplete {
+            status: 0,
+            handle: 0x40,
+            interval: 6,
+            latency: 2,
+            timeout: 0x190,
+        }))
+    );
+}
+
+#[test]
+fn receiving_data_length_change_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[
+        0x04, 0x3e, 0x0b, 0x07, 0x40, 0x00, 0xfb, 0x00, 0x48, 0x08, 0x1b, 0x00, 0x48, 0x01,
+    ]);
+
+    let res = ble.poll().unwrap();
+
+    assert_matches!(
+        res,
+        Some(PollResult::Event(EventType::DataLengthChange {
+            handle: 0x40,
+            max_tx_octets: 251,
+            max_tx_time: 2120,
+            max_rx_octets: 27,
+            max_rx_time: 328,
+        }))
+    );
+}
+
+#[test]
+fn receiving_phy_update_complete_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[0x04, 0x3e, 0x06, 0x0c, 0x00, 0x40, 0x00, 0x02, 0x01]);
+
+    let res = ble.poll().unwrap();
+
+    assert_matches!(
+        res,
+        Some(PollResult::Event(EventType::PhyUpdateComplete {
+            status: 0,
+            handle: 0x40,
+            tx_phy: Phy::Le2M,
+            rx_phy: Phy::Le1M,
+        }))
+    );
+}
+
+#[test]
+fn receiving_failed_phy_update_complete_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[0x04, 0x3e, 0x06, 0x0c, 0x1a, 0x40, 0x00, 0x00, 0x00]);
+
+    let res = ble.poll().unwrap();
+
+    assert_matches!(
+        res,
+        Some(PollResult::Event(EventType::PhyUpdateComplete {
+            status: 0x1a,
+            handle: 0x40,
+            tx_phy: Phy::Other(0),
+            rx_phy: Phy::Other(0),
+        }))
+    );
+}
+
+#[test]
+fn receiving_encryption_change_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[
+        0x04, 0x08, 0x04, 0x00, 0x40, 0x00, 0x01, //
+        0x04, 0x59, 0x05, 0x00, 0x40, 0x00, 0x01, 0x10, //
+        0x04, 0x30, 0x03, 0x00, 0x40, 0x00,
+    ]);
+
+    assert_matches!(
+        ble.poll(),
+        Ok(Some(PollResult::Event(EventType::EncryptionChange {
+            status: 0,
+            handle: 0x40,
+            enabled: 1,
+            key_size: None,
+        })))
+    );
+    assert_matches!(
+        ble.poll(),
+        Ok(Some(PollResult::Event(EventType::EncryptionChange {
+            status: 0,
+            handle: 0x40,
+            enabled: 1,
+            key_size: Some(16),
+        })))
+    );
+    assert_matches!(
+        ble.poll(),
+        Ok(Some(PollResult::Event(
+            EventType::EncryptionKeyRefreshComplete {
+                status: 0,
+                handle: 0x40,
+            }
+        )))
+    );
+}
+
+#[test]
+fn receiving_number_of_completed_packets_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[0x04, 0x13, 0x05, 0x01, 0x00, 0x00, 0x01, 0x00]);
+
+    let res = ble.poll().unwrap();
+
+    assert_matches!(
+        res,
+        Some(PollResult::Event(EventType::NumberOfCompletedPackets {
+            completed_packets
+        })) if completed_packets == [(0, 1)]
+    );
+}
+
+#[test]
+fn receiving_number_of_completed_packets_for_multiple_handles_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[
+        0x04, 0x13, 0x09, 0x02, 0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x03, 0x00,
+    ]);
+
+    let res = ble.poll().unwrap();
+
+    assert_matches!(
+        res,
+        Some(PollResult::Event(EventType::NumberOfCompletedPackets {
+            completed_packets
+        })) if completed_packets == [(0, 1), (1, 3)]
+    );
+}
+
+#[test]
+fn receiving_short_number_of_completed_packets_is_truncated() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[0x04, 0x13, 0x05, 0x02, 0x00, 0x00, 0x01, 0x00]);
+
+    assert_matches!(ble.poll(), Err(bleps::Error::Truncated));
+}
+
+#[test]
+fn unknown_packet_type_resyncs() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[0xaa, 0xbb, 0x04, 0x0e, 0x04, 0x05, 0x03, 0x0c, 0x00]);
+
+    let res = ble.poll();
+    assert_matches!(res, Err(bleps::Error::UnknownPacketType(0xaa)));
+
+    let res = ble.poll();
+    assert_matches!(
+        res,
+        Ok(Some(PollResult::Event(EventType::CommandComplete {
+            num_packets: 5,
+            opcode: 0x0c03,
+            ..
+        })))
+    );
+    assert_eq!(ble.dropped_bytes(), 2);
+}
+
+#[test]
+fn reading_stalled_event_times_out() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x04, 0x05]);
+    connector.set_current_millis_at(0, 0);
+    connector.set_current_millis_at(1, 100);
+    connector.set_current_millis_at(2, 2000);
+
+    let res = ble.poll();
+
+    assert_matches!(res, Err(bleps::Error::Timeout));
+    assert_eq!(connector.get_current_millis_idx(), 3);
+}
+
+#[test]
+fn reading_short_event_is_truncated() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[
+        0x04, 0x0e, 0x01, 0x05, 0x04, 0x0e, 0x04, 0x05, 0x03, 0x0c, 0x00,
+    ]);
+
+    let res = ble.poll();
+    assert_matches!(res, Err(bleps::Error::Truncated));
+
+    let res = ble.poll();
+    assert_matches!(
+        res,
+        Ok(Some(PollResult::Event(EventType::CommandComplete {
+            opcode: 0x0c03,
+            ..
+        })))
+    );
+}
+
+#[test]
+fn receiving_sco_data_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[0x03, 0x01, 0x10, 0x02, 0xaa, 0xbb]);
+
+    let res = ble.poll().unwrap();
+
+    assert_matches!(
+        res,
+        Some(PollResult::SyncData(ScoPacket {
+            handle: 1,
+            packet_status: ScoPacketStatus::PossiblyInvalid,
+            data,
+        })) if data.as_slice() == &[0xaa, 0xbb]
+    );
+}
+
+#[test]
+fn receiving_iso_data_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[0x05, 0x01, 0x60, 0x03, 0x00, 0x01, 0x02, 0x03]);
+
+    let res = ble.poll().unwrap();
+
+    assert_matches!(
+        res,
+        Some(PollResult::IsoData(IsoPacket {
+            handle: 1,
+            boundary_flag: IsoBoundaryFlag::Complete,
+            timestamp_present: true,
+            data,
+        })) if data.as_slice() == &[0x01, 0x02, 0x03]
+    );
+}
+
+#[test]
+fn receiving_read_by_group_type_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[
+        0x02, 0x00, 0x20, 0x0b, 0x00, 0x07, 0x00, 0x04, 0x00, 0x10, 0x01, 0x00, 0xff, 0xff, 0x00,
+        0x28,
+    ]);
+
+    let res = ble.poll().unwrap();
+    match res {
+        Some(res) => match res {
+            PollResult::Event(_) | PollResult::SyncData(_) | PollResult::IsoData(_) => {
+                assert!(true, "Expected async data")
+            }
+            PollResult::AsyncData(res) => {
+                let res = Att::decode(L2capPacket::decode(&res).unwrap().1);
+                assert_matches!(
+                    res,
+                    Ok(Att::ReadByGroupTypeReq {
+                        start: 0x0001,
+                        end: 0xffff,
+                        group_type: Uuid::Uuid16(0x2800),
+                    })
+                )
+            }
+        },
+        None => assert!(true, "Expected result"),
+    }
+}
+
+#[test]
+fn create_read_by_group_type_resp_works() {
+    let mut res = Data::new_att_read_by_group_type_response();
+    res.append_att_read_by_group_type_response(0x0001, 0x0010, &Uuid::Uuid16(0x1801));
+    res.append_att_read_by_group_type_response(0x0020, 0x0030, &Uuid::Uuid16(0x1802));
+
+    assert_matches!(
+        res.as_slice(),
+        [0x11, 0x06, 0x01, 0x00, 0x10, 0x00, 0x01, 0x18, 0x20, 0x00, 0x30, 0x00, 0x02, 0x18]
+    );
+}
+
+#[test]
+fn create_read_by_group_type_resp_acl_works() {
+    let mut res = Data::new_att_read_by_group_type_response();
+    res.append_att_read_by_group_type_response(0x0001, 0x0010, &Uuid::Uuid16(0x1801));
+    res.append_att_read_by_group_type_response(0x0020, 0x0030, &Uuid::Uuid16(0x1802));
+    let res = L2capPacket::encode(res);
+    let res = AclPacket::encode(
+        0x0000,
+        BoundaryFlag::FirstAutoFlushable,
+        HostBroadcastFlag::NoBroadcast,
+        res,
+    );
+
+    assert_matches!(
+        res.as_slice(),
+        &[
+            0x02, 0x00, 0x20, 0x12, 0x00, 0x0e, 0x00, 0x04, 0x00, 0x11, 0x06, 0x01, 0x00, 0x10,
+            0x00, 0x01, 0x18, 0x20, 0x00, 0x30, 0x00, 0x02, 0x18,
+        ]
+    );
+}
+
+#[test]
+fn create_error_resp_works() {
+    let res = Data::new_att_error_response(
+        ATT_READ_BY_GROUP_TYPE_REQUEST_OPCODE,
+        0x1234,
+        AttErrorCode::AttributeNotFound,
+    );
+
+    assert_matches!(res.as_slice(), &[0x01, 0x10, 0x34, 0x12, 0x0a,]);
+}
+
+#[test]
+fn receiving_read_by_type_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[
+        0x02, 0x00, 0x20, 0x0b, 0x00, 0x07, 0x00, 0x04, 0x00, 0x08, 0x01, 0x00, 0x02, 0x00, 0x02,
+        0x28,
+    ]);
+
+    let res = ble.poll().unwrap();
+    match res {
+        Some(res) => match res {
+            PollResult::Event(_) | PollResult::SyncData(_) | PollResult::IsoData(_) => {
+                assert!(true, "Expected async data")
+            }
+            PollResult::AsyncData(res) => {
+                let res = Att::decode(L2capPacket::decode(&res).unwrap().1);
+                assert_matches!(
+                    res,
+                    Ok(Att::ReadByTypeReq {
+                        start: 0x0001,
+                        end: 0x0002,
+                        attribute_type: Uuid::Uuid16(0x2802),
+                    })
+                )
+            }
+        },
+        None => assert!(true, "Expected result"),
+    }
+}
+
+#[test]
+fn create_read_by_type_resp_works() {
+    let mut res = Data::new_att_read_by_type_response();
+    res.append_value(0x0002u16);
+    res.append(&[1u8, 2u8, 3u8, 4u8]);
+    res.append_att_read_by_type_response();
+
+    assert_matches!(
+        res.as_slice(),
+        [0x09, 0x06, 0x02, 0x00, 0x01, 0x02, 0x03, 0x04]
+    );
+}
+
+// TODO test EXCHANGE_MTU
+
+// TODO test FIND_TYPE_VALUE
+
+#[test]
+fn receiving_read_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[
+        0x02, 0x00, 0x20, 0x07, 0x00, 0x03, 0x00, 0x04, 0x00, 0x0a, 0x03, 0x00,
+    ]);
+
+    let res = ble.poll().unwrap();
+    match res {
+        Some(res) => match res {
+            PollResult::Event(_) | PollResult::SyncData(_) | PollResult::IsoData(_) => {
+                assert!(true, "Expected async data")
+            }
+            PollResult::AsyncData(res) => {
+                let res = Att::decode(L2capPacket::decode(&res).unwrap().1);
+                assert_matches!(res, Ok(Att::ReadReq { handle: 0x03 }))
+            }
+        },
+        None => assert!(true, "Expected result"),
+    }
+}
+
+#[test]
+fn create_read_resp_works() {
+    let mut res = Data::new_att_read_response();
+    res.append(&[0x01, 0x02, 0x03, 0x04]);
+
+    assert_matches!(res.as_slice(), &[0x0b, 0x01, 0x02, 0x03, 0x04,]);
+}
+
+#[test]
+fn receiving_write_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[
+        0x02, 0x00, 0x20, 0x08, 0x00, 0x04, 0x00, 0x04, 0x00, 0x12, 0x03, 0x00, 0x0ff,
+    ]);
+
+    let res = ble.poll().unwrap();
+    match res {
+        Some(res) => match res {
+            PollResult::Event(_) | PollResult::SyncData(_) | PollResult::IsoData(_) => {
+                assert!(true, "Expected async data")
+            }
+            PollResult::AsyncData(res) => {
+                let res = Att::decode(L2capPacket::decode(&res).unwrap().1);
+                assert_matches!(
+                    res,
+                    Ok(Att::WriteReq {
+                        handle: 0x03,
+                        data
+                    }) if data == &[0xff]
+                )
+            }
+        },
+        None => assert!(true, "Expected result"),
+    }
+}
+
+#[test]
+fn create_write_resp_works() {
+    let res = Data::new_att_write_response();
+
+    assert_matches!(res.as_slice(), &[0x13]);
+}
+
+#[test]
+fn create_advertising_data_works() {
+    let res = create_advertising_data(&[
+        AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
+        AdStructure::ServiceUuids16(UuidList::Uuids(&[Uuid::Uuid16(0x1809)])),
+        AdStructure::CompleteLocalName("Ble-Example!"),
+    ])
+    .unwrap();
+
+    println!("{:x?}", res);
+
+    assert_matches!(
+        res.as_slice(),
+        &[
+            21, 2, 1, 6, 3, 3, 9, 24, 13, 9, 66, 108, 101, 45, 69, 120, 97, 109, 112, 108, 101, 33,
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0
+        ]
+    );
+}
+
+#[test]
+fn create_advertising_data_fails() {
+    let res = create_advertising_data(&[
+        AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
+        AdStructure::ServiceUuids16(UuidList::Uuids(&[Uuid::Uuid16(0x1809)])),
+        AdStructure::CompleteLocalName(
+            "Ble-Example!Ble-Example!Ble-Example!Ble-Example!Ble-Example!Ble-Example!Ble-Example!",
+        ),
+    ]);
+
+    assert_matches!(res, Err(AdvertisementDataError::TooLong));
+}
+
+#[test]
+fn ad_structures_longer_than_their_length_byte_fail() {
+    let name = "Ble-Example!".repeat(25);
+    assert_matches!(
+        create_advertising_data(&[AdStructure::CompleteLocalName(&name)]),
+        Err(AdvertisementDataError::TooLong)
+    );
+
+    let mut data = Data::new(&[0xaa]);
+    assert_eq!(
+        data.try_append_ad_structure(&AdStructure::Unknown {
+            ty: 0x99,
+            data: &[0; 255],
+        }),
+        Err(AdvertisementDataError::TooLong)
+    );
+    assert_eq!(data.as_slice(), &[0xaa]);
+
+    let mut data = Data::default();
+    assert_eq!(
+        data.try_append_ad_structure(&AdStructure::Unknown {
+            ty: 0x99,
+            data: &[0; 254],
+        }),
+        Ok(())
+    );
+    assert_eq!(&data.as_slice()[..2], &[0xff, 0x99]);
+
+    // a name too long for any length byte is still shortened
+    let split = split_advertising_data(&[
+        AdStructure::CompleteLocalName(&name),
+        AdStructure::ServiceData16 {
+            uuid: 0x1809,
+            data: &[0; 260],
+        },
+    ]);
+    assert_eq!(
+        split.placements,
+        [
+            Placement::ShortenedInAdvertisingData { len: 29 },
+            Placement::NotPlaced
+        ]
+    );
+}
+
+#[test]
+fn split_advertising_data_works() {
+    let uuid = [0x42; 16];
+    let split = split_advertising_data(&[
+        AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
+        AdStructure::ServiceUuids128(UuidList::Uuids(&[Uuid::Uuid128(uuid)])),
+        AdStructure::CompleteLocalName("Ble-Example!"),
+        AdStructure::ManufacturerSpecificData {
+            company_identifier: 0x0059,
+            payload: &[0; 13],
+        },
+        AdStructure::Unknown {
+            ty: 0x99,
+            data: &[0; 30],
+        },
+    ]);
+
+    assert_eq!(
+        split.placements,
+        [
+            Placement::AdvertisingData,
+            Placement::AdvertisingData,
+            Placement::ScanResponseData,
+            Placement::ScanResponseData,
+            Placement::NotPlaced,
+        ]
+    );
+    assert!(!split.all_placed());
+
+    let mut ad = parse_advertising_data(&split.advertising_data.as_slice()[1..]);
+    assert_eq!(
+        ad.next(),
+        Some(Ok(AdStructure::Flags(
+            LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED
+        )))
+    );
+    assert_eq!(
+        ad.next(),
+        Some(Ok(AdStructure::ServiceUuids128(UuidList::Raw(&uuid))))
+    );
+    assert_eq!(ad.next(), None);
+    assert_eq!(split.advertising_data.as_slice()[0], 21);
+    assert_eq!(split.advertising_data.len(), 32);
+
+    let mut ad = parse_advertising_data(&split.scan_response_data.as_slice()[1..]);
+    assert_eq!(
+        ad.next(),
+        Some(Ok(AdStructure::CompleteLocalName("Ble-Example!")))
+    );
+    assert!(matches!(
+        ad.next(),
+        Some(Ok(AdStructure::ManufacturerSpecificData { .. }))
+    ));
+    assert_eq!(ad.next(), None);
+}
+
+#[test]
+fn split_advertising_data_shortens_the_name() {
+    let split = split_advertising_data(&[
+        AdStructure::Flags(LE_GENERAL_DISCOVERABLE),
+        AdStructure::Unknown {
+            ty: 0x99,
+            data: &[0; 20],
+        },
+        AdStructure::Unknown {
+            ty: 0x99,
+            data: &[0; 24],
+        },
+        AdStructure::CompleteLocalName("Ble-Example-With-A-Long-Name"),
+    ]);
+
+    // 31 - 3 - 22 bytes are left in the advertising data, 31 - 26 in the scan response data
+    assert_eq!(
+        split.placements[3],
+        Placement::ShortenedInAdvertisingData { len: 4 }
+    );
+    assert!(split.all_placed());
+
+    let mut ad = parse_advertising_data(&split.advertising_data.as_slice()[1..]);
+    assert_eq!(ad.nth(2), Some(Ok(AdStructure::ShortenedLocalName("Ble-"))));
+
+    // the name isn't cut in the middle of a character
+    let split = split_advertising_data(&[
+        AdStructure::Unknown {
+            ty: 0x99,
+            data: &[0; 25],
+        },
+        AdStructure::Unknown {
+            ty: 0x99,
+            data: &[0; 25],
+        },
+        AdStructure::CompleteLocalName("aäbc"),
+    ]);
+    assert_eq!(
+        split.placements[2],
+        Placement::ShortenedInAdvertisingData { len: 1 }
+    );
+
+    // flags only go into the advertising data
+    let split = split_advertising_data(&[
+        AdStructure::Unknown {
+            ty: 0x99,
+            data: &[0; 29],
+        },
+        AdStructure::Flags(LE_GENERAL_DISCOVERABLE),
+    ]);
+    assert_eq!(split.placements[1], Placement::NotPlaced);
+}
+
+#[test]
+fn beacons_create_advertising_data() {
+    let uuid = [
+        0xe2, 0xc5, 0x6d, 0xb5, 0xdf, 0xfb, 0x48, 0xd2, 0xb0, 0x60, 0xd0, 0xf5, 0xa7, 0x10, 0x96,
+        0xe0,
+    ];
+    let beacon = IBeacon::new(uuid, 1, 0x0203, -59);
+    let data = beacon.advertising_data();
+    assert_eq!(data.as_slice()[0], 30);
+    assert_eq!(
+        &data.as_slice()[1..8],
+        &[0x02, 0x01, 0x06, 0x1a, 0xff, 0x4c, 0x00]
+    );
+    assert_eq!(&data.as_slice()[8..10], &[0x02, 0x15]);
+    assert_eq!(&data.as_slice()[10..26], &uuid);
+    assert_eq!(&data.as_slice()[26..31], &[0x00, 0x01, 0x02, 0x03, 0xc5]);
+
+    let params = beacon.advertising_parameters();
+    assert_matches!(params.advertising_type, AdvertisingType::AdvNonConnInd);
+    assert_eq!(params.advertising_interval_min, 0x00a0);
+    assert_matches!(
+        bleps::beacon::non_connectable_advertising_parameters(Duration::from_millis(10)),
+        Err(AdvertisingParametersError::IntervalOutOfRange)
+    );
+
+    let beacon = AltBeacon::new(0x0118, [0x11; 20], -65, 0x42);
+    let data = beacon.advertising_data();
+    assert_eq!(data.as_slice()[0], 31);
+    assert_eq!(
+        &data.as_slice()[4..10],
+        &[0x1b, 0xff, 0x18, 0x01, 0xbe, 0xac]
+    );
+    assert_eq!(&data.as_slice()[10..30], &[0x11; 20]);
+    assert_eq!(&data.as_slice()[30..32], &[0xbf, 0x42]);
+
+    let beacon = Eddystone::uid(-20, [1; 10], [2; 6]);
+    let data = beacon.advertising_data();
+    assert_eq!(
+        &data.as_slice()[..12],
+        &[31, 0x02, 0x01, 0x06, 0x03, 0x03, 0xaa, 0xfe, 0x17, 0x16, 0xaa, 0xfe]
+    );
+    assert_eq!(&data.as_slice()[12..14], &[0x00, 0xec]);
+    assert_eq!(&data.as_slice()[14..24], &[1; 10]);
+    assert_eq!(&data.as_slice()[24..32], &[2, 2, 2, 2, 2, 2, 0, 0]);
+
+    let data = Eddystone::tlm(3000, 0x1880, 0x01020304, 10).advertising_data();
+    assert_eq!(
+        &data.as_slice()[8..26],
+        &[
+            0x11, 0x16, 0xaa, 0xfe, 0x20, 0x00, 0x0b, 0xb8, 0x18, 0x80, 0x01, 0x02, 0x03, 0x04,
+            0x00, 0x00, 0x00, 0x0a
+        ]
+    );
+
+    let data = Eddystone::eid(-20, [9; 8]).advertising_data();
+    assert_eq!(
+        &data.as_slice()[8..22],
+        &[0x0d, 0x16, 0xaa, 0xfe, 0x30, 0xec, 9, 9, 9, 9, 9, 9, 9, 9]
+    );
+}
+
+#[test]
+fn eddystone_url_is_compressed() {
+    let data = Eddystone::url(-20, "https://www.example.com/path")
+        .unwrap()
+        .advertising_data();
+    assert_eq!(
+        &data.as_slice()[8..28],
+        &[
+            0x12, 0x16, 0xaa, 0xfe, 0x10, 0xec, 0x01, b'e', b'x', b'a', b'm', b'p', b'l', b'e',
+            0x00, b'p', b'a', b't', b'h', 0
+        ]
+    );
+
+    let data = Eddystone::url(0, "http://goo.gl")
+        .unwrap()
+        .advertising_data();
+    assert_eq!(
+        &data.as_slice()[8..19],
+        &[0x0c, 0x16, 0xaa, 0xfe, 0x10, 0x00, 0x02, b'g', b'o', b'o', b'.']
+    );
+
+    assert_eq!(
+        Eddystone::url(0, "ftp://example.com").unwrap_err(),
+        EddystoneUrlError::UnsupportedScheme
+    );
+    assert_eq!(
+        Eddystone::url(0, "https://exa mple.com").unwrap_err(),
+        EddystoneUrlError::InvalidCharacter
+    );
+    assert_eq!(
+        Eddystone::url(0, "https://a-rather-long-name.com").unwrap_err(),
+        EddystoneUrlError::TooLong
+    );
+    // exactly 17 bytes
+    assert!(Eddystone::url(0, "https://abcdefghijklmnop.com").is_ok());
+}
+
+#[test]
+fn parse_advertising_data_works() {
+    let data = create_advertising_data(&[
+        AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
+        AdStructure::ServiceUuids16(UuidList::Uuids(&[Uuid::Uuid16(0x1809)])),
+        AdStructure::CompleteLocalName("Ble-Example!"),
+        AdStructure::ManufacturerSpecificData {
+            company_identifier: 0x0059,
+            payload: &[1, 2],
+        },
+    ])
+    .unwrap();
+
+    let mut ad = parse_advertising_data(&data.as_slice()[1..]);
+    assert_eq!(
+        ad.next(),
+        Some(Ok(AdStructure::Flags(
+            LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED
+        )))
+    );
+    assert_eq!(
+        ad.next(),
+        Some(Ok(AdStructure::ServiceUuids16(UuidList::Raw(&[
+            0x09, 0x18
+        ]))))
+    );
+    assert_eq!(
+        ad.next(),
+        Some(Ok(AdStructure::CompleteLocalName("Ble-Example!")))
+    );
+    assert_eq!(
+        ad.next(),
+        Some(Ok(AdStructure::ManufacturerSpecificData {
+            company_identifier: 0x0059,
+            payload: &[1, 2]
+        }))
+    );
+    assert_eq!(ad.next(), None);
+}
+
+#[test]
+fn ad_structures_round_trip() {
+    let structures = [
+        AdStructure::IncompleteServiceUuids16(UuidList::Uuids(&[Uuid::Uuid16(0x180d)])),
+        AdStructure::ServiceUuids32(UuidList::Uuids32(&[0x1234_5678])),
+        AdStructure::IncompleteServiceUuids32(UuidList::Uuids32(&[1, 2])),
+        AdStructure::IncompleteServiceUuids128(UuidList::Uuids(&[Uuid::Uuid128([7; 16])])),
+        AdStructure::ServiceSolicitation16(UuidList::Uuids(&[Uuid::Uuid16(0x1812)])),
+        AdStructure::ServiceSolicitation32(UuidList::Uuids32(&[0xabcd])),
+        AdStructure::ServiceSolicitation128(UuidList::Uuids(&[Uuid::Uuid128([9; 16])])),
+        AdStructure::ServiceData32 {
+            uuid: 0x1234_5678,
+            data: &[1],
+        },
+        AdStructure::ServiceData128 {
+            uuid: [3; 16],
+            data: &[],
+        },
+        AdStructure::TxPowerLevel(-4),
+        AdStructure::Appearance(0x03c1),
+        AdStructure::PeripheralConnectionIntervalRange {
+            min: 0x0006,
+            max: 0xffff,
+        },
+        AdStructure::Uri("\u{17}//example.com"),
+        AdStructure::LeRole(LeRole::PeripheralPreferred),
+        AdStructure::AdvertisingInterval(0x0800),
+        AdStructure::LeSupportedFeatures(&[0x01, 0x20]),
+    ];
+
+    for structure in structures.iter() {
+        let mut data: Data = Data::default();
+        data.append_ad_structure(structure);
+
+        let mut ad = parse_advertising_data(data.as_slice());
+        assert_eq!(ad.next(), Some(Ok(*structure)));
+        assert_eq!(ad.next(), None);
+    }
+
+    let mut data: Data = Data::default();
+    data.append_ad_structure(&AdStructure::ServiceUuids32(UuidList::Uuids32(&[
+        0x1234_5678,
+    ])));
+    assert_eq!(data.as_slice(), &[0x05, 0x05, 0x78, 0x56, 0x34, 0x12]);
+
+    // fixed size types need their exact length
+    let mut ad = parse_advertising_data(&[0x02, 0x19, 0xc1]);
+    assert_eq!(
+        ad.next(),
+        Some(Err(AdvertisementDataError::InvalidLength {
+            offset: 0,
+            ty: 0x19
+        }))
+    );
+
+    // 32-bit UUID lists need a multiple of 4 bytes
+    let mut ad = parse_advertising_data(&[0x04, 0x05, 1, 2, 3]);
+    assert_eq!(
+        ad.next(),
+        Some(Err(AdvertisementDataError::InvalidLength {
+            offset: 0,
+            ty: 0x05
+        }))
+    );
+}
+
+#[test]
+fn parse_advertising_data_fails_on_malformed_lengths() {
+    // the name claims 6 bytes but only 3 follow
+    let mut ad = parse_advertising_data(&[0x02, 0x01, 0x02, 0x06, 0x09, b'a', b'b']);
+    assert_eq!(
+        ad.next(),
+        Some(Ok(AdStructure::Flags(LE_GENERAL_DISCOVERABLE)))
+    );
+    assert_eq!(
+        ad.next(),
+        Some(Err(AdvertisementDataError::Truncated { offset: 3 }))
+    );
+    assert_eq!(ad.next(), None);
+
+    // service data without the complete UUID
+    let mut ad = parse_advertising_data(&[0x02, 0x16, 0x0d]);
+    assert_eq!(
+        ad.next(),
+        Some(Err(AdvertisementDataError::InvalidLength {
+            offset: 0,
+            ty: 0x16
+        }))
+    );
+    assert_eq!(ad.next(), None);
+}
+
+#[test]
+fn receiving_truncated_att_pdu_fails() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    // a Read Request missing the second byte of the handle
+    connector.provide_data_to_read(&[
+        0x02, 0x00, 0x20, 0x06, 0x00, 0x02, 0x00, 0x04, 0x00, 0x0a, 0x01,
+    ]);
+
+    let res = match ble.poll() {
+        Ok(Some(PollResult::AsyncData(res))) => res,
+        _ => panic!("Expected async data"),
+    };
+    let res = Att::decode(L2capPacket::decode(&res).unwrap().1);
+    assert_matches!(res, Err(AttDecodeError::Truncated { opcode: 0x0a }));
+    assert_eq!(
+        res.unwrap_err().to_string(),
+        "ATT PDU with opcode 0x0a is truncated"
+    );
+}
+
+#[test]
+fn data_reports_overflow_instead_of_panicking() {
+    let mut data = Data::<4>::new(&[1, 2]);
+
+    assert_eq!(data.try_append(&[3, 4, 5]), Err(CapacityError));
+    assert_eq!(data.as_slice(), &[1, 2]);
+    assert_eq!(data.try_append_value(0x0403u16), Ok(()));
+    assert_eq!(data.as_slice(), &[1, 2, 3, 4]);
+    assert_eq!(data.try_append_value(5u8), Err(CapacityError));
+    assert_eq!(data.try_set(3, 5), Ok(()));
+    assert_eq!(data.try_set(4, 5), Err(CapacityError));
+    assert_eq!(data.as_slice(), &[1, 2, 3, 5]);
+
+    assert_eq!(data.subdata_from(3).as_slice(), &[5]);
+    assert_eq!(data.subdata_from(5).as_slice(), &[] as &[u8]);
+}
+
+#[test]
+fn notification_data_rejects_values_longer_than_att_pdu() {
+    let value = [0xaa; bleps::att::ATT_PDU_LEN + 1];
+
+    assert!(NotificationData::new(0x0003, &value[..bleps::att::ATT_PDU_LEN]).is_ok());
+    assert_matches!(NotificationData::new(0x0003, &value), Err(CapacityError));
+}
+
+#[test]
+fn write_request_borrows_the_received_value() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    // Write Request for handle 0x0003 with the value 01 02 03
+    connector.provide_data_to_read(&[
+        0x02, 0x00, 0x20, 0x0a, 0x00, 0x06, 0x00, 0x04, 0x00, 0x12, 0x03, 0x00, 0x01, 0x02, 0x03,
+    ]);
+
+    let packet = match ble.poll() {
+        Ok(Some(PollResult::AsyncData(packet))) => packet,
+        _ => panic!("Expected async data"),
+    };
+    let (_, l2cap_packet) = L2capPacket::decode(&packet).unwrap();
+    assert!(core::ptr::eq(
+        l2cap_packet.payload,
+        &packet.data.as_slice()[4..]
+    ));
+
+    match Att::decode(l2cap_packet) {
+        Ok(Att::WriteReq {
+            handle: 0x0003,
+            data,
+        }) => {
+            assert_eq!(data, &[1, 2, 3]);
+            assert!(core::ptr::eq(data, &packet.data.as_slice()[7..]));
+        }
+        res => panic!("Unexpected {:?}", res),
+    }
+}
+
+#[cfg(feature = "mtu256")]
+#[test]
+fn attribute_server_receives_write_of_full_mtu() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    let written = Cell::new(0);
+    let mut wf = |_offset: usize, data: &[u8]| written.set(data.len());
+    let mut att_data = ((), &mut wf, ());
+    let attributes = &mut [Attribute::new(CHARACTERISTIC_UUID16, &mut att_data)];
+
+    let mut rng = OsRng::default();
+    let mut srv = AttributeServer::new(&mut ble, attributes, &mut rng);
+
+    // Write Request of 256 bytes fragmented into two ACL packets
+    let mut pdu = std::vec![0x00, 0x01, 0x04, 0x00, 0x12, 0x01, 0x00];
+    pdu.extend([0xaa; 253]);
+    let mut packets = std::vec![0x02, 0x00, 0x20, 200, 0x00];
+    packets.extend(&pdu[..200]);
+    packets.extend([0x02, 0x00, 0x10, 60, 0x00]);
+    packets.extend(&pdu[200..]);
+    connector.provide_data_to_read(&packets);
+
+    assert_matches!(srv.do_work(), Ok(WorkResult::DidWork));
+    assert_matches!(srv.do_work(), Ok(WorkResult::DidWork));
+    assert_eq!(written.get(), 253);
+    assert_eq!(
+        connector.get_written_data().as_slice(),
+        &[0x02, 0x00, 0x20, 0x05, 0x00, 0x01, 0x00, 0x04, 0x00, 0x13]
+    );
+}
+
+#[test]
+fn attribute_server_rejects_empty_cccd_write() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    let mut value = [0u8; 4];
+    let mut value = &mut value;
+    let mut cccd = [0u8; 2];
+    let mut cccd = &mut cccd;
+    let attributes = &mut [
+        Attribute::new(CHARACTERISTIC_UUID16, &mut value),
+        Attribute::new(Uuid::Uuid16(0x2902), &mut cccd),
+    ];
+
+    let mut rng = OsRng::default();
+    let mut srv = AttributeServer::new(&mut ble, attributes, &mut rng);
+
+    connector.provide_data_to_read(&[
+        0x02, 0x00, 0x20, 0x07, 0x00, 0x03, 0x00, 0x04, 0x00, 0x12, 0x02, 0x00,
+    ]);
+
+    assert_matches!(srv.do_work(), Ok(WorkResult::DidWork));
+    assert_eq!(
+        connector.get_written_data().as_slice(),
+        &[0x02, 0x00, 0x20, 0x09, 0x00, 0x05, 0x00, 0x04, 0x00, 0x01, 0x12, 0x02, 0x00, 0x0d]
+    );
+}
+
+#[test]
+fn attribute_server_discover_two_services() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    let mut rf1 = |_offset: usize, data: &mut [u8]| -> usize {
+        data[0] = 0;
+        1
+    };
+    let mut wf1 = |_offset: usize, _data: &[u8]| {};
+
+    let mut rf2 = |_offset: usize, data: &mut [u8]| -> usize {
+        data[0] = 0;
+        1
+    };
+    let mut wf2 = |_offset: usize, _data: &[u8]| {};
+
+    let srv_uuid: [u8; 16] = [
+        0xC9, 0x15, 0x15, 0x96, 0x54, 0x56, 0x64, 0xB3, 0x38, 0x45, 0x26, 0x5D, 0xF1, 0x62, 0x6A,
+        0xA8,
+    ];
+    let mut srv_uuid_att_data = &srv_uuid[..];
+    let primaray_srv = Attribute::new(PRIMARY_SERVICE_UUID16, &mut srv_uuid_att_data);
+
+    let char_data = [
+        0x02, // 1 byte properties: READ = 0x02
+        0x03, 0x00, // 2 bytes handle = 0x0007
+        0xC9, 0x15, 0x15, 0x96, 0x54, 0x56, 0x64, 0xB3, 0x38, 0x45, 0x26, 0x5D, 0xF1, 0x62, 0x6A,
+        0xA8, // 128 bit UUID like above
+    ];
+    let mut char_att_data = &char_data;
+    let char = Attribute::new(CHARACTERISTIC_UUID16, &mut char_att_data);
+
+    let mut custom_char_att_data = (&mut rf1, &mut wf1, ());
+    let custom_char_att_data_attr = Attribute::new(
+        Uuid::Uuid128([
+            0xC9, 0x15, 0x15, 0x96, 0x54, 0x56, 0x64, 0xB3, 0x38, 0x45, 0x26, 0x5D, 0xF1, 0x62,
+            0x6A, 0xA8,
+        ]),
+        &mut custom_char_att_data,
+    );
+
+    let srv_uuid2: [u8; 16] = [
+        0xC8, 0x15, 0x15, 0x96, 0x54, 0x56, 0x64, 0xB3, 0x38, 0x45, 0x26, 0x5D, 0xF1, 0x62, 0x6A,
+        0xA8,
+    ];
+    let mut srv_uuid_att_data2 = &srv_uuid2[..];
+    let primaray_srv2 = Attribute::new(PRIMARY_SERVICE_UUID16, &mut srv_uuid_att_data2);
+
+    let char_data2 = [
+        0x02 | 0x08, // 1 byte properties: READ = 0x02, WRITE WITH RESPONSE = 0x08
+        0x06,
+        0x00, // 2 bytes handle = 0x0007
+        0xC8,
+        0x15,
+        0x15,
+        0x96,
+        0x54,
+        0x56,
+        0x64,
+        0xB3,
+        0x38,
+        0x45,
+        0x26,
+        0x5D,
+        0xF1,
+        0x62,
+        0x6A,
+        0xA8, // 128 bit UUID like above
+    ];
+    let mut char_att_data2 = &char_data2;
+    let char2 = Attribute::new(CHARACTERISTIC_UUID16, &mut char_att_data2);
+
+    let mut custom_char_att_data2 = (&mut rf2, &mut wf2, ());
+    let custom_char_att_data_attr2 = Attribute::new(
+        Uuid::Uuid128([
+            0xC9, 0x15, 0x15, 0x96, 0x54, 0x56, 0x64, 0xB3, 0x38, 0x45, 0x26, 0x5D, 0xF1, 0x62,
+            0x6A, 0xA8,
+        ]),
+        &mut custom_char_att_data2,
+    );
+
+    let mut val_att_data = &(32u32,);
+    let val = Attribute::new(CHARACTERISTIC_UUID16, &mut val_att_data);
+
+    let attributes = &mut [
+        primaray_srv,
+        char,
+        custom_char_att_data_attr,
+        primaray_srv2,
+        char2,
+        custom_char_att_data_attr2,
+        val,
+    ];
+
+    let mut rng = OsRng::default();
+    let mut srv = AttributeServer::new(&mut ble, attributes, &mut rng);
+
+    // ReadByGroupTypeReq { start: 1, end: ffff, group_type: Uuid16(2800) }
+    connector.provide_data_to_read(&[
+        0x02, 0x00, 0x20, 0x0b, 0x00, 0x07, 0x00, 0x04, 0x00, 0x10, 0x01, 0x00, 0xff, 0xff, 0x00,
+        0x28,
+    ]);
+    assert_matches!(srv.do_work(), Ok(_));
+    // check response (1-3, 0x2800)
+    let response_data = connector.get_written_data();
+    assert_eq!(
+        response_data.as_slice(),
+        &[
+            0x02, 0x00, 0x20, 0x1A, 0x00, 0x16, 0x00, 0x04, 0x00, 0x11, 0x14, 0x01, 0x00, 0x03,
+            0x00, 0xC9, 0x15, 0x15, 0x96, 0x54, 0x56, 0x64, 0xB3, 0x38, 0x45, 0x26, 0x5D, 0xF1,
+            0x62, 0x6A, 0xA8,
+        ]
+    );
+
+    // ReadByGroupTypeReq { start: 4, end: ffff, group_type: Uuid16(2800) }
+    connector.reset();
+    connector.provide_data_to_read(&[
+        0x02, 0x00, 0x20, 0x0b, 0x00, 0x07, 0x00, 0x04, 0x00, 0x10, 0x04, 0x00, 0xff, 0xff, 0x00,
+        0x28,
+    ]);
+    assert_matches!(srv.do_work(), Ok(_));
+    // check response (4-6, 0x2800)
+    let response_data = connector.get_written_data();
+    assert_eq!(
+        response_data.as_slice(),
+        &[
+            0x02, 0x00, 0x20, 0x1a, 0x00, 0x16, 0x00, 0x04, 0x00, 0x11, 0x14, 0x04, 0x00, 0x07,
+            0x00, 0xC8, 0x15, 0x15, 0x96, 0x54, 0x56, 0x64, 0xB3, 0x38, 0x45, 0x26, 0x5D, 0xF1,
+            0x62, 0x6A, 0xA8,
+        ]
+    );
+
+    // ReadByGroupTypeReq { start: 7, end: ffff, group_type: Uuid16(2800) }
+    connector.reset();
+    connector.provide_data_to_read(&[
+        0x02, 0x00, 0x20, 0x0b, 0x00, 0x07, 0x00, 0x04, 0x00, 0x10, 0x07, 0x00, 0xff, 0xff, 0x00,
+        0x28,
+    ]);
+    assert_matches!(srv.do_work(), Ok(_));
+    // check response (not found)
+    let response_data = connector.get_written_data();
+    assert_eq!(
+        response_data.as_slice(),
+        &[0x02, 0x00, 0x20, 0x09, 0x00, 0x05, 0x00, 0x04, 0x00, 0x01, 0x10, 0x07, 0x00, 0x0a]
+    );
+}
+
+#[test]
+fn error_code_round_trips_raw_value() {
+    for value in 0..=u8::MAX {
+        assert_eq!(u8::from(ErrorCode::from_u8(value)), value);
+    }
+
+    assert_eq!(
+        ErrorCode::from_u8(0x3e),
+        ErrorCode::ConnectionFailedToBeEstablished
+    );
+    assert_eq!(ErrorCode::from_u8(0x2b), ErrorCode::Other(0x2b));
+}
+
+#[test]
+fn receiving_disconnection_complete_keeps_unlisted_reason() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[0x04, 0x05, 0x04, 0x00, 0x00, 0x00, 0x2b]);
+
+    let res = ble.poll().unwrap();
+
+    assert_matches!(
+        res,
+        Some(PollResult::Event(EventType::DisconnectComplete {
+            handle: 0,
+            status: ErrorCode::Okay,
+            reason: ErrorCode::Other(0x2b)
+        }))
+    );
+}
+
+#[test]
+fn attribute_server_resets_controller_after_hardware_error() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    let mut val_att_data = &(32u32,);
+    let val = Attribute::new(CHARACTERISTIC_UUID16, &mut val_att_data);
+    let attributes = &mut [val];
+
+    let mut rng = OsRng::default();
+    let mut srv = AttributeServer::new(&mut ble, attributes, &mut rng);
+
+    connector.provide_data_to_read(&[0x04, 0x10, 0x01, 0x00]);
+    provide_init_responses(&connector, [0; 8], false);
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x07, 0x05, 0x02, 0x20, 0x00, 0x1b, 0x00, 0x02]);
+
+    assert_matches!(srv.do_work(), Ok(WorkResult::GotDisconnected));
+    assert_eq!(
+        &connector.get_written_data().as_slice()[..4],
+        &[0x01, 0x03, 0x0c, 0x00]
+    );
+}
+
+/// Wraps a Security Manager PDU into an ACL packet of connection 0
+#[cfg(feature = "crypto")]
+fn sm_acl_packet(pdu: &[u8]) -> std::vec::Vec<u8> {
+    let mut packet = std::vec![0x02, 0x00, 0x20];
+    packet.extend_from_slice(&(pdu.len() as u16 + 4).to_le_bytes());
+    packet.extend_from_slice(&(pdu.len() as u16).to_le_bytes());
+    packet.extend_from_slice(&[0x06, 0x00]);
+    packet.extend_from_slice(pdu);
+    packet
+}
+
+/// A Pairing Public Key PDU carrying a valid key
+#[cfg(feature = "crypto")]
+fn sm_public_key_pdu() -> std::vec::Vec<u8> {
+    use p256::elliptic_curve::sec1::ToEncodedPoint;
+
+    let key = p256::SecretKey::random(&mut OsRng)
+        .public_key()
+        .to_encoded_point(false);
+    let mut pdu = std::vec![0x0c];
+    pdu.extend(key.x().unwrap().iter().rev());
+    pdu.extend(key.y().unwrap().iter().rev());
+    pdu
+}
+
+#[cfg(feature = "crypto")]
+#[test]
+fn attribute_server_fails_pairing_with_malformed_pdus() {
+    use bleps::{attribute_server::AttributeServerError, sm::SecurityManagerError};
+
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    let mut val_att_data = &(32u32,);
+    let val = Attribute::new(CHARACTERISTIC_UUID16, &mut val_att_data);
+    let attributes = &mut [val];
+
+    let mut rng = OsRng::default();
+    let mut srv = AttributeServer::new(&mut ble, attributes, &mut rng);
+
+    // an empty PDU and a Pairing Request missing most of its parameters
+    let mut packets = sm_acl_packet(&[]);
+    packets.extend(sm_acl_packet(&[0x01, 0x03, 0x00]));
+    connector.provide_data_to_read(&packets);
+
+    for _ in 0..2 {
+        assert_matches!(
+            srv.do_work(),
+            Err(AttributeServerError::SecurityManagerError(
+                SecurityManagerError::InvalidParameters
+            ))
+        );
+    }
+    let pairing_failed = [
+        0x02, 0x00, 0x20, 0x06, 0x00, 0x02, 0x00, 0x06, 0x00, 0x05, 0x0a,
+    ];
+    assert_eq!(
+        connector.get_written_data().as_slice(),
+        [pairing_failed, pairing_failed].concat()
+    );
+}
+
+#[cfg(feature = "crypto")]
+#[test]
+fn attribute_server_fails_pairing_with_invalid_public_key() {
+    use bleps::{attribute_server::AttributeServerError, sm::SecurityManagerError};
+
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    let mut val_att_data = &(32u32,);
+    let val = Attribute::new(CHARACTERISTIC_UUID16, &mut val_att_data);
+    let attributes = &mut [val];
+
+    let mut rng = OsRng::default();
+    let mut srv = AttributeServer::new(&mut ble, attributes, &mut rng);
+
+    let mut pdu = [0u8; 65];
+    pdu[0] = 0x0c;
+    connector.provide_data_to_read(&sm_acl_packet(&pdu));
+
+    assert_matches!(
+        srv.do_work(),
+        Err(AttributeServerError::SecurityManagerError(
+            SecurityManagerError::DHKeyCheckFailed
+        ))
+    );
+    let written = connector.get_written_data();
+    assert_eq!(
+        &written.as_slice()[written.as_slice().len() - 11..],
+        &[0x02, 0x00, 0x20, 0x06, 0x00, 0x02, 0x00, 0x06, 0x00, 0x05, 0x0b]
+    );
+}
+
+#[cfg(feature = "crypto")]
+#[test]
+fn attribute_server_fails_pairing_with_wrong_dhkey_check() {
+    use bleps::{attribute_server::AttributeServerError, sm::SecurityManagerError};
+
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    let mut val_att_data = &(32u32,);
+    let val = Attribute::new(CHARACTERISTIC_UUID16, &mut val_att_data);
+    let attributes = &mut [val];
+
+    let mut rng = OsRng::default();
+    let mut srv = AttributeServer::new(&mut ble, attributes, &mut rng);
+
+    connector.provide_data_to_read(&[
+        0x04, 0x3e, 0x13, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66,
+        0x28, 0x00, 0x00, 0x00, 0xf4, 0x01, 0x05,
+    ]);
+    assert_matches!(srv.do_work(), Ok(WorkResult::DidWork));
+
+    for pdu in [
+        std::vec![0x01, 0x03, 0x00, 0x0d, 0x10, 0x00, 0x00],
+        sm_public_key_pdu(),
+        std::vec![0x04; 17],
+    ] {
+        connector.reset();
+        connector.provide_data_to_read(&sm_acl_packet(&pdu));
+        assert_matches!(srv.do_work(), Ok(WorkResult::DidWork));
+    }
+
+    connector.reset();
+    let mut pdu = [0u8; 17];
+    pdu[0] = 0x0d;
+    connector.provide_data_to_read(&sm_acl_packet(&pdu));
+
+    assert_matches!(
+        srv.do_work(),
+        Err(AttributeServerError::SecurityManagerError(
+            SecurityManagerError::DHKeyCheckFailed
+        ))
+    );
+    // Pairing Failed instead of the local DHKey check
+    assert_eq!(
+        connector.get_written_data().as_slice(),
+        &[0x02, 0x00, 0x20, 0x06, 0x00, 0x02, 0x00, 0x06, 0x00, 0x05, 0x0b]
+    );
+}
+
+/// An HCI transport that never receives anything
+#[derive(Default)]
+struct SilentTransport {
+    written: std::vec::Vec<u8>,
+}
+
+impl embedded_io_blocking::ErrorType for SilentTransport {
+    type Error = core::convert::Infallible;
+}
+
+impl embedded_io_blocking::Read for SilentTransport {
+    fn read(&mut self, _buf: &mut [u8]) -> Result<usize, Self::Error> {
+        Ok(0)
+    }
+}
+
+impl embedded_io_blocking::Write for SilentTransport {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.written.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+#[test]
+fn hci_connector_times_out_with_closure_clock() {
+    let now = Cell::new(0u64);
+    let connector = HciConnector::new(SilentTransport::default(), || {
+        now.set(now.get() + 400);
+        now.get()
+    });
+    let mut ble = Ble::new(&connector);
+
+    assert_matches!(ble.cmd_reset(), Err(bleps::Error::Timeout));
+    assert!(!ble.needs_reset());
+    assert_eq!(now.get(), 1600);
+
+    // the controller never hands out another command credit
+    assert_matches!(ble.cmd_reset(), Err(bleps::Error::Timeout));
+    assert!(!ble.needs_reset());
+    assert_matches!(ble.cmd_reset(), Err(bleps::Error::Timeout));
+    assert!(ble.needs_reset());
+}
+
+#[test]
+fn hci_connector_timeouts_before_reset_are_configurable() {
+    let now = Cell::new(0u64);
+    let connector = HciConnector::new(SilentTransport::default(), || {
+        now.set(now.get() + 400);
+        now.get()
+    });
+    let mut ble = Ble::new(&connector);
+
+    ble.set_timeouts_before_reset(0);
+    for _ in 0..5 {
+        assert_matches!(ble.cmd_reset(), Err(bleps::Error::Timeout));
+    }
+    assert!(!ble.needs_reset());
+
+    ble.set_timeouts_before_reset(1);
+    assert_matches!(ble.cmd_reset(), Err(bleps::Error::Timeout));
+    assert!(ble.needs_reset());
+}
+
+/// A transport handing out one byte per call, like the connections written before slice reads
+struct ByteConnector {
+    to_read: RefCell<std::collections::VecDeque<u8>>,
+    written: RefCell<std::vec::Vec<u8>>,
+}
+
+impl ByteHciConnection for ByteConnector {
+    fn read(&self) -> Option<u8> {
+        self.to_read.borrow_mut().pop_front()
+    }
+
+    fn write(&self, data: u8) {
+        self.written.borrow_mut().push(data);
+    }
+
+    fn millis(&self) -> u64 {
+        0
+    }
+}
+
+#[test]
+fn byte_connection_still_works() {
+    let connector = ByteConnector {
+        to_read: RefCell::new(
+            [0x04, 0x0e, 0x04, 0x05, 0x03, 0x0c, 0x00]
+                .into_iter()
+                .collect(),
+        ),
+        written: RefCell::new(std::vec::Vec::new()),
+    };
+    let mut ble = Ble::new(&connector);
+
+    assert_matches!(ble.cmd_reset(), Ok(EventType::CommandComplete { .. }));
+    assert_eq!(*connector.written.borrow(), [0x01, 0x03, 0x0c, 0x00]);
+}
+
+/// An HCI transport handing out everything it has in one read, counting the reads
+struct CountingTransport {
+    to_read: std::vec::Vec<u8>,
+    reads: std::rc::Rc<Cell<usize>>,
+}
+
+impl embedded_io_blocking::ErrorType for CountingTransport {
+    type Error = core::convert::Infallible;
+}
+
+impl embedded_io_blocking::Read for CountingTransport {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.reads.set(self.reads.get() + 1);
+        let len = buf.len().min(self.to_read.len());
+        buf[..len].copy_from_slice(&self.to_read[..len]);
+        self.to_read.drain(..len);
+        Ok(len)
+    }
+}
+
+impl embedded_io_blocking::Write for CountingTransport {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+#[test]
+fn hci_connector_reads_ahead() {
+    let reads = std::rc::Rc::new(Cell::new(0));
+    let transport = CountingTransport {
+        to_read: std::vec![
+            0x04, 0x0e, 0x04, 0x05, 0x03, 0x0c, 0x00, 0x04, 0x0e, 0x04, 0x05, 0x03, 0x0c, 0x00,
+        ],
+        reads: reads.clone(),
+    };
+    let connector = HciConnector::new(transport, || 0);
+    let mut ble = Ble::new(&connector);
+
+    assert_matches!(
+        ble.poll(),
+        Ok(Some(PollResult::Event(EventType::CommandComplete { .. })))
+    );
+    assert_matches!(
+        ble.poll(),
+        Ok(Some(PollResult::Event(EventType::CommandComplete { .. })))
+    );
+    assert_eq!(reads.get(), 1);
+}
+
+#[test]
+fn h4_reader_collects_packets_in_pieces() {
+    let mut reader = H4Reader::new();
+    let mut stream = std::vec![0x05, 0x01, 0x00, 0x2c, 0x01];
+    stream.extend((0..300).map(|i| i as u8));
+    stream.extend([0x04, 0x0e, 0x04, 0x05, 0x03, 0x0c, 0x00]);
+
+    let mut packets = std::vec::Vec::new();
+    let mut stream = stream.as_slice();
+    while !stream.is_empty() {
+        let buffer = reader.buffer();
+        let len = buffer.len().min(stream.len()).min(7);
+        buffer[..len].copy_from_slice(&stream[..len]);
+        stream = &stream[len..];
+
+        if let Some(packet) = reader.advance(len).unwrap() {
+            packets.push(packet);
+        }
+    }
+
+    assert_eq!(packets.len(), 2);
+    let iso = IsoPacket::from_raw(&packets[0]);
+    assert_eq!(iso.handle, 0x0001);
+    assert_eq!(iso.data.len(), 256);
+    assert_eq!(iso.data.as_slice()[255], 255);
+    assert_matches!(
+        EventType::from_raw(&packets[1]),
+        Ok(EventType::CommandComplete { opcode: 0x0c03, .. })
+    );
+}
+
+/// An async HCI transport which is pending until bytes are provided
+#[cfg(feature = "async")]
+struct PendingTransport {
+    to_read: std::rc::Rc<RefCell<std::collections::VecDeque<u8>>>,
+    written: std::rc::Rc<RefCell<std::vec::Vec<u8>>>,
+}
+
+#[cfg(feature = "async")]
+impl embedded_io_async::ErrorType for PendingTransport {
+    type Error = core::convert::Infallible;
+}
+
+#[cfg(feature = "async")]
+impl embedded_io_async::Read for PendingTransport {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        core::future::poll_fn(|_| {
+            let mut to_read = self.to_read.borrow_mut();
+            if to_read.is_empty() {
+                return core::task::Poll::Pending;
+            }
+
+            let len = buf.len().min(to_read.len());
+            for (byte, value) in buf.iter_mut().zip(to_read.drain(..len)) {
+                *byte = value;
+            }
+            core::task::Poll::Ready(Ok(len))
+        })
+        .await
+    }
+}
+
+#[cfg(feature = "async")]
+impl embedded_io_async::Write for PendingTransport {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.written.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+}
+
+#[cfg(feature = "async")]
+fn poll_once<F: core::future::Future>(future: F) -> core::task::Poll<F::Output> {
+    let future = core::pin::pin!(future);
+    future.poll(&mut core::task::Context::from_waker(
+        core::task::Waker::noop(),
+    ))
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_poll_dropped_mid_packet_keeps_framing() {
+    let to_read = std::rc::Rc::new(RefCell::new(std::collections::VecDeque::new()));
+    let transport = PendingTransport {
+        to_read: to_read.clone(),
+        written: Default::default(),
+    };
+    let mut ble = bleps::asynch::Ble::new(transport, || 0);
+
+    to_read.borrow_mut().extend([0x04, 0x0e, 0x04, 0x05]);
+    assert!(poll_once(ble.poll()).is_pending());
+
+    to_read.borrow_mut().extend([0x03, 0x0c, 0x00]);
+    assert_matches!(
+        poll_once(ble.poll()),
+        core::task::Poll::Ready(Ok(Some(PollResult::Event(EventType::CommandComplete {
+            opcode: 0x0c03,
+            ..
+        }))))
+    );
+    assert_eq!(ble.dropped_bytes(), 0);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_poll_times_out_on_stalled_packet() {
+    use core::future::Future;
+
+    let to_read = std::rc::Rc::new(RefCell::new(std::collections::VecDeque::new()));
+    let transport = PendingTransport {
+        to_read: to_read.clone(),
+        written: Default::default(),
+    };
+    let now = Cell::new(0u64);
+    let mut ble = bleps::asynch::Ble::new(transport, || now.get());
+    let mut cx = core::task::Context::from_waker(core::task::Waker::noop());
+
+    // nothing arriving is no reason to give up
+    now.set(10_000);
+    assert!(poll_once(ble.poll()).is_pending());
+
+    to_read.borrow_mut().extend([0x04, 0x0e, 0x04, 0x05]);
+    {
+        let mut poll = core::pin::pin!(ble.poll());
+        assert!(poll.as_mut().poll(&mut cx).is_pending());
+
+        now.set(10_600);
+        assert_matches!(
+            poll.as_mut().poll(&mut cx),
+            core::task::Poll::Ready(Err(bleps::Error::Timeout))
+        );
+    }
+
+    // the stalled packet is dropped, the next one is read from its start
+    to_read
+        .borrow_mut()
+        .extend([0x04, 0x0e, 0x04, 0x05, 0x03, 0x0c, 0x00]);
+    assert_matches!(
+        poll_once(ble.poll()),
+        core::task::Poll::Ready(Ok(Some(PollResult::Event(EventType::CommandComplete {
+            opcode: 0x0c03,
+            ..
+        }))))
+    );
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn runner_executes_commands_of_control_handles() {
+    use bleps::host::{HostEvent, HostResources, Runner};
+    use core::future::Future;
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+    let to_read = std::rc::Rc::new(RefCell::new(std::collections::VecDeque::new()));
+    let written = std::rc::Rc::new(RefCell::new(std::vec::Vec::new()));
+    let transport = PendingTransport {
+        to_read: to_read.clone(),
+        written: written.clone(),
+    };
+    let resources = HostResources::<NoopRawMutex>::new();
+    let (mut runner, control) = Runner::new(bleps::asynch::Ble::new(transport, || 0), &resources);
+
+    let mut run = core::pin::pin!(runner.run());
+    let read_bd_addr = ReadBdAddr::new();
+    let mut exec = core::pin::pin!(control.exec(&read_bd_addr));
+    let mut cx = core::task::Context::from_waker(core::task::Waker::noop());
+
+    assert!(exec.as_mut().poll(&mut cx).is_pending());
+    assert!(run.as_mut().poll(&mut cx).is_pending());
+    assert_eq!(*written.borrow(), [0x01, 0x09, 0x10, 0x00]);
+
+    // a disconnection arriving before the answer still reaches the handles
+    to_read.borrow_mut().extend([
+        0x04, 0x05, 0x04, 0x00, 0x01, 0x00, 0x13, //
+        0x04, 0x0e, 0x0a, 0x01, 0x09, 0x10, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06,
+    ]);
+    assert!(run.as_mut().poll(&mut cx).is_pending());
+    assert_matches!(
+        exec.as_mut().poll(&mut cx),
+        core::task::Poll::Ready(Ok(addr)) if addr.raw() == [0x01, 0x02, 0x03, 0x04, 0x05, 0x06]
+    );
+    assert_matches!(
+        poll_once(control.receive()),
+        core::task::Poll::Ready(HostEvent::Packet(PollResult::Event(
+            EventType::DisconnectComplete { handle: 0x0001, .. }
+        )))
+    );
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn runner_delivers_packets_and_refuses_unchecked_notifications() {
+    use bleps::host::{HostEvent, HostResources, Runner};
+    use core::future::Future;
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+    let to_read = std::rc::Rc::new(RefCell::new(std::collections::VecDeque::new()));
+    let written = std::rc::Rc::new(RefCell::new(std::vec::Vec::new()));
+    let transport = PendingTransport {
+        to_read: to_read.clone(),
+        written: written.clone(),
+    };
+    let resources = HostResources::<NoopRawMutex>::new();
+    let (mut runner, control) = Runner::new(bleps::asynch::Ble::new(transport, || 0), &resources);
+
+    let mut run = core::pin::pin!(runner.run());
+    let mut cx = core::task::Context::from_waker(core::task::Waker::noop());
+
+    to_read
+        .borrow_mut()
+        .extend([0x04, 0x05, 0x04, 0x00, 0x01, 0x00, 0x13]);
+    assert!(run.as_mut().poll(&mut cx).is_pending());
+    assert_matches!(
+        poll_once(control.receive()),
+        core::task::Poll::Ready(HostEvent::Packet(PollResult::Event(
+            EventType::DisconnectComplete { handle: 0x0001, .. }
+        )))
+    );
+
+    // without an attribute server nobody can have enabled notifications
+    let mut notify = core::pin::pin!(control.notify(0x0001, 0x0003, &[0xaa, 0xbb]));
+    assert!(notify.as_mut().poll(&mut cx).is_pending());
+    assert!(run.as_mut().poll(&mut cx).is_pending());
+    assert_matches!(
+        notify.as_mut().poll(&mut cx),
+        core::task::Poll::Ready(Err(bleps::Error::NotificationsDisabled))
+    );
+    assert!(written.borrow().is_empty());
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn runner_serves_attribute_server_and_checks_notifications() {
+    use bleps::host::{HostEvent, HostResources, Runner};
+    use core::future::Future;
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+    let to_read = std::rc::Rc::new(RefCell::new(std::collections::VecDeque::new()));
+    let written = std::rc::Rc::new(RefCell::new(std::vec::Vec::new()));
+    let transport = PendingTransport {
+        to_read: to_read.clone(),
+        written: written.clone(),
+    };
+    let resources = HostResources::<NoopRawMutex>::new();
+    let (mut runner, control) = Runner::new(bleps::asynch::Ble::new(transport, || 0), &resources);
+
+    let mut value = [0u8; 4];
+    let mut value = &mut value;
+    let mut cccd = [0u8; 2];
+    let mut cccd = &mut cccd;
+    let attributes = &mut [
+        Attribute::new(CHARACTERISTIC_UUID16, &mut value),
+        Attribute::new(Uuid::Uuid16(0x2902), &mut cccd),
+    ];
+    let mut rng = OsRng::default();
+    let mut run = core::pin::pin!(runner.run_with_attribute_server(attributes, &mut rng));
+    let mut cx = core::task::Context::from_waker(core::task::Waker::noop());
+
+    let mut notify = core::pin::pin!(control.notify(0x0001, 0x0001, &[0xaa, 0xbb]));
+    assert!(notify.as_mut().poll(&mut cx).is_pending());
+    assert!(run.as_mut().poll(&mut cx).is_pending());
+    assert_matches!(
+        notify.as_mut().poll(&mut cx),
+        core::task::Poll::Ready(Err(bleps::Error::NotificationsDisabled))
+    );
+
+    // the client enables notifications, the server answers and the handles don't see the PDU
+    to_read.borrow_mut().extend([
+        0x02, 0x01, 0x20, 0x09, 0x00, 0x05, 0x00, 0x04, 0x00, 0x12, 0x02, 0x00, 0x01, 0x00,
+    ]);
+    assert!(run.as_mut().poll(&mut cx).is_pending());
+    assert_eq!(
+        *written.borrow(),
+        [0x02, 0x01, 0x20, 0x05, 0x00, 0x01, 0x00, 0x04, 0x00, 0x13]
+    );
+    assert!(poll_once(control.receive()).is_pending());
+    written.borrow_mut().clear();
+
+    // the MTU is still the default one
+    let mut notify = core::pin::pin!(control.notify(0x0001, 0x0001, &[0xaa; 21]));
+    assert!(notify.as_mut().poll(&mut cx).is_pending());
+    assert!(run.as_mut().poll(&mut cx).is_pending());
+    assert_matches!(
+        notify.as_mut().poll(&mut cx),
+        core::task::Poll::Ready(Err(bleps::Error::Truncated))
+    );
+
+    let mut notify = core::pin::pin!(control.notify(0x0001, 0x0001, &[0xaa, 0xbb]));
+    assert!(notify.as_mut().poll(&mut cx).is_pending());
+    assert!(run.as_mut().poll(&mut cx).is_pending());
+    assert_matches!(
+        notify.as_mut().poll(&mut cx),
+        core::task::Poll::Ready(Ok(()))
+    );
+    assert_eq!(
+        *written.borrow(),
+        [0x02, 0x01, 0x20, 0x09, 0x00, 0x05, 0x00, 0x04, 0x00, 0x1b, 0x01, 0x00, 0xaa, 0xbb]
+    );
+
+    // events still reach the handles
+    to_read
+        .borrow_mut()
+        .extend([0x04, 0x05, 0x04, 0x00, 0x01, 0x00, 0x13]);
+    assert!(run.as_mut().poll(&mut cx).is_pending());
+    assert_matches!(
+        poll_once(control.receive()),
+        core::task::Poll::Ready(HostEvent::Packet(PollResult::Event(
+            EventType::DisconnectComplete { handle: 0x0001, .. }
+        )))
+    );
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn runner_keeps_connection_events_when_nobody_receives() {
+    use bleps::host::{HostEvent, HostResources, Runner, RECEIVED_QUEUE_LEN};
+    use core::future::Future;
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+    let to_read = std::rc::Rc::new(RefCell::new(std::collections::VecDeque::new()));
+    let transport = PendingTransport {
+        to_read: to_read.clone(),
+        written: Default::default(),
+    };
+    let resources = HostResources::<NoopRawMutex>::new();
+    let (mut runner, control) = Runner::new(bleps::asynch::Ble::new(transport, || 0), &resources);
+
+    let mut run = core::pin::pin!(runner.run());
+    let mut cx = core::task::Context::from_waker(core::task::Waker::noop());
+
+    for _ in 0..RECEIVED_QUEUE_LEN + 2 {
+        to_read
+            .borrow_mut()
+            .extend([0x04, 0x0e, 0x04, 0x01, 0x03, 0x0c, 0x00]);
+    }
+    to_read
+        .borrow_mut()
+        .extend([0x04, 0x05, 0x04, 0x00, 0x01, 0x00, 0x13]);
+    assert!(run.as_mut().poll(&mut cx).is_pending());
+    assert_eq!(control.dropped_packets(), 2);
+
+    // the disconnection waits for room
+    for _ in 0..RECEIVED_QUEUE_LEN {
+        assert_matches!(
+            poll_once(control.receive()),
+            core::task::Poll::Ready(HostEvent::Packet(PollResult::Event(
+                EventType::CommandComplete { .. }
+            )))
+        );
+    }
+    assert!(poll_once(control.receive()).is_pending());
+    assert!(run.as_mut().poll(&mut cx).is_pending());
+    assert_matches!(
+        poll_once(control.receive()),
+        core::task::Poll::Ready(HostEvent::Packet(PollResult::Event(
+            EventType::DisconnectComplete { handle: 0x0001, .. }
+        )))
+    );
+    assert_eq!(control.dropped_packets(), 2);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn runner_tells_control_handles_about_controller_reset() {
+    use bleps::host::{HostEvent, HostResources, Runner};
+    use core::future::Future;
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+    let to_read = std::rc::Rc::new(RefCell::new(std::collections::VecDeque::new()));
+    let written = std::rc::Rc::new(RefCell::new(std::vec::Vec::new()));
+    let transport = PendingTransport {
+        to_read: to_read.clone(),
+        written: written.clone(),
+    };
+    let resources = HostResources::<NoopRawMutex>::new();
+    let (mut runner, control) = Runner::new(bleps::asynch::Ble::new(transport, || 0), &resources);
+
+    let mut run = core::pin::pin!(runner.run());
+    let mut cx = core::task::Context::from_waker(core::task::Waker::noop());
+
+    // Hardware Error, then the answers to the reset and init commands
+    to_read.borrow_mut().extend([0x04, 0x10, 0x01, 0x03]);
+    to_read.borrow_mut().extend(init_responses([0; 8], false));
+    to_read
+        .borrow_mut()
+        .extend([0x04, 0x0e, 0x07, 0x05, 0x02, 0x20, 0x00, 0x1b, 0x00, 0x02]);
+    assert!(run.as_mut().poll(&mut cx).is_pending());
+
+    assert_matches!(
+        poll_once(control.receive()),
+        core::task::Poll::Ready(HostEvent::Packet(PollResult::Event(
+            EventType::HardwareError { .. }
+        )))
+    );
+    assert!(run.as_mut().poll(&mut cx).is_pending());
+    assert_matches!(
+        poll_once(control.receive()),
+        core::task::Poll::Ready(HostEvent::ControllerReset)
     );
 }
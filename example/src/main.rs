@@ -8,9 +8,11 @@ use std::{
 
 use bleps::{
     ad_structure::{
-        create_advertising_data, AdStructure, BR_EDR_NOT_SUPPORTED, LE_GENERAL_DISCOVERABLE,
+        create_advertising_data, AdStructure, UuidList, BR_EDR_NOT_SUPPORTED,
+        LE_GENERAL_DISCOVERABLE,
     },
     attribute_server::{AttributeServer, NotificationData, WorkResult},
+    event::ErrorCode,
     gatt, Addr, Ble, HciConnector,
 };
 use embedded_io_adapters::std::FromStd;
@@ -71,7 +73,7 @@ fn main() {
             ble.cmd_set_le_advertising_data(
                 create_advertising_data(&[
                     AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
-                    AdStructure::ServiceUuids16(&[Uuid::Uuid16(0x1809)]),
+                    AdStructure::ServiceUuids16(UuidList::Uuids(&[Uuid::Uuid16(0x1809)])),
                     AdStructure::CompleteLocalName("BLEPS"),
                 ])
                 .unwrap()
@@ -194,15 +196,17 @@ fn main() {
                                 // if notifications enabled
                                 if cccd[0] == 1 {
                                     response[5] = b'0' + ((response[5] + 1) % 10);
-                                    notification = Some(NotificationData::new(
+                                    notification = NotificationData::new(
                                         my_characteristic_handle,
                                         &response[..],
-                                    ));
+                                    )
+                                    .ok();
                                 }
                             }
                         }
                         crossterm::event::KeyCode::Char('x') => {
-                            srv.disconnect(0x13).unwrap();
+                            srv.disconnect(ErrorCode::RemoteUserTerminatedConnection)
+                                .unwrap();
                         }
                         _ => (),
                     },